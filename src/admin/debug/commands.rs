@@ -3,7 +3,7 @@
 	fmt::Write,
 	iter::once,
 	str::FromStr,
-	time::{Instant, SystemTime},
+	time::{Duration, Instant, SystemTime},
 };
 
 use futures::{FutureExt, StreamExt, TryStreamExt};
@@ -1050,3 +1050,26 @@ pub(super) async fn resync_database(&self) -> Result {
 		.update()
 		.map_err(|e| err!("Failed to update from primary: {e:?}"))
 }
+
+#[admin_command]
+pub(super) async fn typing_state(&self) -> Result {
+	let snapshot = self
+		.services
+		.rooms
+		.typing
+		.typing_snapshot()
+		.await?;
+
+	let now = utils::millis_since_unix_epoch();
+	let mut out = format!("Typing in {} room(s):\n```\n", snapshot.len());
+	for (room_id, users) in snapshot {
+		writeln!(out, "{room_id}")?;
+		for (user_id, timeout) in users {
+			let remaining = Duration::from_millis(timeout.saturating_sub(now));
+			writeln!(out, "  {user_id} until {timeout} (in {remaining:?})")?;
+		}
+	}
+
+	out.push_str("```");
+	self.write_str(&out).await
+}
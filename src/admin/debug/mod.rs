@@ -259,6 +259,13 @@ pub(super) enum DebugCommand {
 	/// - Synchronize database with primary (secondary only)
 	ResyncDatabase,
 
+	/// - Print every room with users typing in it and when their typing times
+	///   out
+	///
+	/// Expired typing is cleared first, so what remains is current. Timeouts
+	/// far in the future point at clients requesting pathological durations.
+	TypingState,
+
 	/// - Developer test stubs
 	#[command(subcommand)]
 	#[allow(non_snake_case)]
@@ -1,3 +1,4 @@
+use ruma::OwnedRoomOrAliasId;
 use tuwunel_core::{Err, Result};
 
 use crate::{admin_command, admin_command_dispatch};
@@ -9,6 +10,12 @@ pub(crate) enum TesterCommand {
 	Failure,
 	Tester,
 	Timer,
+
+	/// - Exercise typing notifications in a room as the server user, which must
+	///   be joined to it
+	TypingSelfTest {
+		room: OwnedRoomOrAliasId,
+	},
 }
 
 #[rustfmt::skip]
@@ -50,3 +57,16 @@ async fn timer(&self) -> Result {
 fn timed(body: &[&str]) {
 
 }
+
+#[admin_command]
+async fn typing_self_test(&self, room: OwnedRoomOrAliasId) -> Result {
+	let room_id = self.services.rooms.alias.resolve(&room).await?;
+	let report = self
+		.services
+		.rooms
+		.typing
+		.typing_self_test(&room_id)
+		.await?;
+
+	self.write_str(&report.to_string()).await
+}
@@ -106,3 +106,20 @@ pub(super) async fn remote_user_in_rooms(&self, user_id: OwnedUserId) -> Result
 	self.write_str(&format!("Rooms {user_id} shares with us ({num}):\n```\n{body}\n```",))
 		.await
 }
+
+#[admin_command]
+pub(super) async fn resend_typing(
+	&self,
+	room_id: OwnedRoomId,
+	server_name: Option<OwnedServerName>,
+) -> Result {
+	let sent = self
+		.services
+		.rooms
+		.typing
+		.resend_federation(&room_id, server_name.as_deref())
+		.await?;
+
+	self.write_str(&format!("Re-sent typing for {sent} local user(s)."))
+		.await
+}
@@ -39,4 +39,14 @@ pub(super) enum FederationCommand {
 	RemoteUserInRooms {
 		user_id: OwnedUserId,
 	},
+
+	/// - Re-sends the current typing state of a room's local users
+	///
+	/// Sends to the specified server, or to every server in the room if none
+	/// is given. Useful when a remote server shows stale typing.
+	ResendTyping {
+		room_id: OwnedRoomId,
+
+		server_name: Option<OwnedServerName>,
+	},
 }
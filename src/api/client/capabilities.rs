@@ -52,5 +52,10 @@ pub(crate) async fn get_capabilities_route(
 		json!({"enabled": services.config.forget_forced_upon_leave}),
 	)?;
 
+	capabilities.set(
+		"org.matrix.tuwunel.typing",
+		serde_json::to_value(services.rooms.typing.timeout_policy())?,
+	)?;
+
 	Ok(get_capabilities::v3::Response { capabilities })
 }
@@ -61,6 +61,27 @@ pub(crate) async fn upload_keys_route(
 			.await?;
 	}
 
+	for (key_id, fallback_key) in &body.fallback_keys {
+		if fallback_key
+			.deserialize()
+			.inspect_err(|e| {
+				debug_warn!(
+					?key_id,
+					?fallback_key,
+					"Invalid fallback key JSON submitted by client, skipping: {e}"
+				);
+			})
+			.is_err()
+		{
+			continue;
+		}
+
+		services
+			.users
+			.add_fallback_key(sender_user, sender_device, key_id, fallback_key)
+			.await;
+	}
+
 	if let Some(device_keys) = &body.device_keys {
 		let deser_device_keys = device_keys.deserialize().map_err(|e| {
 			err!(Request(BadJson(debug_warn!(
@@ -638,11 +659,20 @@ pub(crate) async fn claim_keys_helper(
 
 		let mut container = BTreeMap::new();
 		for (device_id, key_algorithm) in map {
-			if let Ok(one_time_keys) = services
+			let claimed = match services
 				.users
 				.take_one_time_key(user_id, device_id, key_algorithm)
 				.await
 			{
+				| Ok(one_time_key) => Ok(one_time_key),
+				| Err(_) =>
+					services
+						.users
+						.take_fallback_key(user_id, device_id, key_algorithm)
+						.await,
+			};
+
+			if let Ok(one_time_keys) = claimed {
 				let mut c = BTreeMap::new();
 				c.insert(one_time_keys.0, one_time_keys.1);
 				container.insert(device_id.clone(), c);
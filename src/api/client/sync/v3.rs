@@ -365,6 +365,10 @@ async fn build_sync_events(
 		.users
 		.count_one_time_keys(sender_user, sender_device);
 
+	let device_unused_fallback_key_types = services
+		.users
+		.unused_fallback_key_types(sender_user, sender_device);
+
 	// Remove all to-device events the device received *last time*
 	let remove_to_device_events =
 		services
@@ -374,7 +378,7 @@ async fn build_sync_events(
 	let (
 		account_data,
 		keys_changed,
-		device_one_time_keys_count,
+		(device_one_time_keys_count, device_unused_fallback_key_types),
 		((), to_device_events, presence_updates),
 		(
 			(joined_rooms, mut device_list_updates, left_encrypted_users),
@@ -385,7 +389,7 @@ async fn build_sync_events(
 	) = join5(
 		account_data,
 		keys_changed,
-		device_one_time_keys_count,
+		join(device_one_time_keys_count, device_unused_fallback_key_types),
 		join3(remove_to_device_events, to_device_events, presence_updates),
 		join4(joined_rooms, left_rooms, invited_rooms, knocked_rooms),
 	)
@@ -423,8 +427,7 @@ async fn build_sync_events(
 			left: device_list_left.into_iter().collect(),
 		},
 		device_one_time_keys_count,
-		// Fallback keys are not yet supported
-		device_unused_fallback_key_types: None,
+		device_unused_fallback_key_types: Some(device_unused_fallback_key_types),
 		next_batch: next_batch.to_string(),
 		presence: Presence { events: presence_events },
 		rooms: Rooms {
@@ -920,13 +923,13 @@ async fn load_joined_room(
 	let typing_events = services
 		.rooms
 		.typing
-		.last_typing_update(room_id)
-		.and_then(async |count| {
-			if count <= since {
+		.typing_users_since(room_id, sender_user, since)
+		.and_then(async |user_ids| {
+			let Some(user_ids) = user_ids else {
 				return Ok(Vec::<Raw<AnySyncEphemeralRoomEvent>>::new());
-			}
+			};
 
-			let typings = typings_event_for_user(services, room_id, sender_user).await?;
+			let typings = SyncEphemeralRoomEvent { content: TypingEventContent { user_ids } };
 
 			Ok(vec![serde_json::from_str(&serde_json::to_string(&typings)?)?])
 		})
@@ -1315,19 +1318,3 @@ async fn fold_hero(
 	heroes.push(user_id.to_owned());
 	heroes
 }
-
-async fn typings_event_for_user(
-	services: &Services,
-	room_id: &RoomId,
-	sender_user: &UserId,
-) -> Result<SyncEphemeralRoomEvent<TypingEventContent>> {
-	Ok(SyncEphemeralRoomEvent {
-		content: TypingEventContent {
-			user_ids: services
-				.rooms
-				.typing
-				.typing_users_for_user(room_id, sender_user)
-				.await?,
-		},
-	})
-}
@@ -1,44 +1,67 @@
+#[cfg(test)]
+mod tests;
+mod unstable;
+
 use std::{
 	cmp::Ordering,
 	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
-	ops::Deref,
+	ops::{Deref, Range},
 	time::Duration,
 };
 
 use axum::extract::State;
 use futures::{
 	FutureExt, Stream, StreamExt, TryFutureExt,
-	future::{OptionFuture, join3, try_join5},
+	future::{self, OptionFuture, join3, try_join3},
 	pin_mut,
 };
 use ruma::{
-	DeviceId, OwnedEventId, OwnedRoomId, RoomId, UInt, UserId,
+	CanonicalJsonValue, DeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 	api::client::sync::sync_events::{
 		self, DeviceLists, StrippedState, UnreadNotificationsCount,
 		v5::request::ExtensionRoomConfig,
 	},
 	directory::RoomTypeFilter,
 	events::{
-		AnyRawAccountDataEvent, AnySyncEphemeralRoomEvent, StateEventType, TimelineEventType,
+		AnyRawAccountDataEvent, AnyStrippedStateEvent, AnySyncEphemeralRoomEvent,
+		AnyToDeviceEvent, GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType,
+		SyncEphemeralRoomEvent, TimelineEventType,
+		direct::{DirectEvent, DirectEventContent},
+		fully_read::FullyReadEvent,
+		ignored_user_list::{IgnoredUserListEvent, IgnoredUserListEventContent},
+		receipt::{Receipt, ReceiptEventContent, ReceiptThread, ReceiptType},
 		room::member::{MembershipState, RoomMemberEventContent},
+		tag::{TagEvent, TagName, Tags},
 		typing::TypingEventContent,
 	},
+	room::RoomType,
 	serde::Raw,
 	uint,
 };
+use serde::Serialize;
+use serde_json::json;
 use tuwunel_core::{
-	Err, Error, Result, at, error, extract_variant, is_equal_to,
-	matrix::{Event, TypeStateKey, pdu::PduCount},
+	Err, Error, Result, at, debug_warn, error, extract_variant, is_equal_to,
+	matrix::{
+		Event, TypeStateKey,
+		pdu::{PduCount, PduEvent},
+	},
 	trace,
 	utils::{
-		BoolExt, FutureBoolExt, IterStream, ReadyExt, TryFutureExtExt, TryReadyExt,
+		BoolExt, FutureBoolExt, IterStream, ReadyExt, TryFutureExtExt,
 		future::ReadyEqExt,
 		math::{ruma_from_usize, usize_from_ruma},
+		stream::TryIgnore,
 	},
 	warn,
 };
-use tuwunel_service::{Services, rooms::read_receipt::pack_receipts, sync::into_snake_key};
+use tuwunel_service::{
+	Services,
+	rooms::read_receipt::pack_receipts,
+	sync::{SnakeConnectionsKey, into_snake_key},
+};
 
+use self::unstable::{SyncResponse, Unstable};
 use super::share_encrypted_room;
 use crate::{
 	Ruma,
@@ -46,8 +69,12 @@
 };
 
 type SyncInfo<'a> = (&'a UserId, &'a DeviceId, u64, &'a sync_events::v5::Request);
-type TodoRooms = BTreeMap<OwnedRoomId, (BTreeSet<TypeStateKey>, usize, u64)>;
+type TodoRooms = BTreeMap<OwnedRoomId, (BTreeSet<TypeStateKey>, usize, u64, bool)>;
 type KnownRooms = BTreeMap<String, BTreeMap<OwnedRoomId, u64>>;
+type ListRooms = BTreeMap<String, BTreeSet<OwnedRoomId>>;
+type RoomTags<'a> = HashMap<&'a RoomId, Tags>;
+type BumpStamps<'a> = HashMap<&'a RoomId, UInt>;
+type DmRooms = HashSet<OwnedRoomId>;
 
 /// `POST /_matrix/client/unstable/org.matrix.simplified_msc3575/sync`
 /// ([MSC4186])
@@ -62,17 +89,31 @@
 pub(crate) async fn sync_events_v5_route(
 	State(ref services): State<crate::State>,
 	body: Ruma<sync_events::v5::Request>,
-) -> Result<sync_events::v5::Response> {
+) -> Result<SyncResponse> {
 	debug_assert!(DEFAULT_BUMP_TYPES.is_sorted(), "DEFAULT_BUMP_TYPES is not sorted");
 
 	let sender_user = body.sender_user();
 	let sender_device = body.sender_device();
-	let snake_key = into_snake_key(sender_user, sender_device, body.conn_id.clone());
-	let globalsince = body
-		.pos
-		.as_ref()
-		.and_then(|string| string.parse().ok())
-		.unwrap_or(0);
+	let conn_id = effective_conn_id(body.conn_id.as_deref());
+	let snake_key = into_snake_key(sender_user, sender_device, conn_id.clone());
+	let Some(globalsince) = parse_pos(body.pos.as_deref()) else {
+		return Err!(Request(UnknownPos("Unrecognized sync position; restarting sync stream.")));
+	};
+
+	if !txn_id_valid(body.txn_id.as_deref()) {
+		return Err!(Request(InvalidParam(
+			"txn_id must be at most {MAX_TXN_ID_LEN} bytes long."
+		)));
+	}
+
+	for extension in unknown_extensions(body.json_body.as_ref()) {
+		trace!(%extension, "ignoring unrecognized extension");
+	}
+
+	let mut partial_errors =
+		PartialErrors::new(partial_errors_requested(body.json_body.as_ref()));
+
+	let typing_only = typing_only_requested(body.json_body.as_ref());
 
 	if globalsince != 0 && !services.sync.snake_connection_cached(&snake_key) {
 		return Err!(Request(UnknownPos(
@@ -89,15 +130,34 @@ pub(crate) async fn sync_events_v5_route(
 
 	// Setup watchers, so if there's no response, we can wait for them
 	let watcher = services.sync.watch(sender_user, sender_device);
+	let wait_typing = typing_only || body.extensions.typing.enabled.unwrap_or(false);
 
 	let next_batch = services.globals.wait_pending().await?;
 
+	if pos_expired(globalsince, next_batch, services.config.sync_max_pos_age) {
+		services
+			.sync
+			.forget_snake_sync_connection(&snake_key);
+
+		return Err!(Request(UnknownPos("Sync position is too old; restarting sync stream.")));
+	}
+
 	// Get sticky parameters from cache
 	let mut cached = body.body.clone();
+	cached.conn_id = Some(conn_id);
 	let known_rooms = services
 		.sync
 		.update_snake_sync_request_with_cache(&snake_key, &mut cached);
 
+	let unsubscribed = unsubscribe_rooms(body.json_body.as_ref());
+	cached
+		.room_subscriptions
+		.retain(|room_id, _| !unsubscribed.contains(room_id));
+
+	if typing_only {
+		cached.extensions.typing.enabled = Some(true);
+	}
+
 	let all_joined_rooms = services
 		.rooms
 		.state_cache
@@ -122,6 +182,9 @@ pub(crate) async fn sync_events_v5_route(
 	let (all_joined_rooms, all_invited_rooms, all_knocked_rooms) =
 		join3(all_joined_rooms, all_invited_rooms, all_knocked_rooms).await;
 
+	let [all_joined_rooms, all_invited_rooms, all_knocked_rooms] =
+		strongest_memberships([all_joined_rooms, all_invited_rooms, all_knocked_rooms]);
+
 	let all_joined_rooms = all_joined_rooms.iter().map(AsRef::as_ref);
 	let all_invited_rooms = all_invited_rooms.iter().map(AsRef::as_ref);
 	let all_knocked_rooms = all_knocked_rooms.iter().map(AsRef::as_ref);
@@ -136,25 +199,32 @@ pub(crate) async fn sync_events_v5_route(
 
 	let sync_info: SyncInfo<'_> = (sender_user, sender_device, globalsince, &cached);
 
+	if typing_only {
+		return typing_poll(
+			services,
+			sync_info,
+			next_batch,
+			&snake_key,
+			all_joined_rooms,
+			wait_typing,
+		)
+		.await
+		.map(Into::into);
+	}
+
 	let account_data = collect_account_data(services, sync_info, next_batch).map(Ok);
 
 	let e2ee = collect_e2ee(services, sync_info, next_batch, all_joined_rooms.clone());
 
 	let to_device = collect_to_device(services, sync_info, next_batch).map(Ok);
 
-	let receipts = collect_receipts(services, sync_info, next_batch).map(Ok);
-
-	let typing = collect_typing_events(services, sync_info, next_batch, all_joined_rooms.clone());
-
-	let (account_data, e2ee, to_device, receipts, typing) =
-		try_join5(account_data, e2ee, to_device, receipts, typing).await?;
+	let (account_data, e2ee, to_device) = try_join3(account_data, e2ee, to_device).await?;
 
 	let extensions = sync_events::v5::response::Extensions {
 		account_data,
 		e2ee,
 		to_device,
-		receipts,
-		typing,
+		..Default::default()
 	};
 
 	let mut response = sync_events::v5::Response {
@@ -165,7 +235,12 @@ pub(crate) async fn sync_events_v5_route(
 		extensions,
 	};
 
-	handle_lists(
+	let mut unstable = Unstable::default();
+
+	// resolved once and shared by the list filters and each room's is_dm
+	let dm_rooms = dm_rooms(services, sender_user).await;
+
+	let list_rooms = handle_lists(
 		services,
 		sync_info,
 		all_invited_rooms.clone(),
@@ -173,23 +248,85 @@ pub(crate) async fn sync_events_v5_route(
 		all_rooms.clone(),
 		&mut todo_rooms,
 		&known_rooms,
+		&dm_rooms,
 		&mut response,
+		body.json_body.as_ref(),
 	)
 	.await;
 
-	fetch_subscriptions(services, sync_info, &known_rooms, &mut todo_rooms).await;
+	fetch_subscriptions(services, sync_info, &known_rooms, &unsubscribed, &mut todo_rooms).await;
+
+	// rooms handled below replace these with their own, fuller receipts
+	response.extensions.receipts =
+		collect_receipts(services, sync_info, next_batch, &list_rooms, all_joined_rooms.clone())
+			.await;
+
+	let typing_room_ids = extension_rooms(
+		cached.extensions.typing.rooms.as_deref(),
+		cached.extensions.typing.lists.as_deref(),
+		&list_rooms,
+		cached.room_subscriptions.keys().map(Deref::deref),
+		all_joined_rooms.clone(),
+	);
+
+	let typing_room_ids = typing_updated_rooms(services, typing_room_ids, |room_id| {
+		typing_room_since(&todo_rooms, room_id, globalsince)
+	})
+	.await;
+
+	response.extensions.typing =
+		collect_typing_events(services, sync_info, &typing_room_ids).await?;
 
 	response.rooms = process_rooms(
 		services,
 		sender_user,
 		next_batch,
 		all_invited_rooms.clone(),
+		all_knocked_rooms.clone(),
 		&todo_rooms,
+		&dm_rooms,
 		&mut response,
 		&cached,
+		&mut partial_errors,
+		&mut unstable,
 	)
 	.await?;
 
+	let counts = response.rooms.iter().map(|(room_id, room)| {
+		let room_id: &RoomId = room_id;
+		let count = |count: Option<UInt>| count.map_or(0, u64::from);
+		(room_id, (count(room.joined_count), count(room.invited_count)))
+	});
+
+	for (room_id, delta) in services
+		.sync
+		.update_snake_sync_room_counts(&snake_key, counts)
+	{
+		report_count_delta(&mut unstable, room_id, delta);
+	}
+
+	let timeline_limits = cached
+		.lists
+		.values()
+		.map(|list| list.room_details.timeline_limit)
+		.chain(
+			cached
+				.room_subscriptions
+				.values()
+				.map(|room| room.timeline_limit),
+		);
+
+	let capped = response_capped(
+		cached.room_subscriptions.len(),
+		services.config.sync_max_subscriptions,
+		timeline_limits,
+		services.config.sync_timeline_limit_max,
+	);
+
+	report_capped(&mut unstable, capped);
+
+	partial_errors.report(&mut unstable);
+
 	if response.rooms.iter().all(|(id, r)| {
 		r.timeline.is_empty()
 			&& r.required_state.is_empty()
@@ -207,15 +344,23 @@ pub(crate) async fn sync_events_v5_route(
 	{
 		// Hang a few seconds so requests are not spammed
 		// Stop hanging if new info arrives
-		let timeout_default = services.config.client_sync_timeout_default;
-		let timeout_min = services.config.client_sync_timeout_min;
-		let timeout_max = services.config.client_sync_timeout_max;
-		let duration = body
-			.timeout
-			.unwrap_or_else(|| Duration::from_millis(timeout_default))
-			.clamp(Duration::from_millis(timeout_min), Duration::from_millis(timeout_max));
-
-		_ = tokio::time::timeout(duration, watcher).await;
+		let duration = poll_timeout(services, body.timeout);
+
+		// Typing in a room tracked by this connection ends the hang immediately
+		let typing = async {
+			if wait_typing {
+				let tracked = todo_rooms.keys().map(AsRef::as_ref);
+				services
+					.rooms
+					.typing
+					.wait_for_rooms_update(tracked, next_batch)
+					.await;
+			} else {
+				future::pending().await
+			}
+		};
+
+		long_poll(duration, watcher, typing).await;
 	}
 
 	trace!(
@@ -224,17 +369,359 @@ pub(crate) async fn sync_events_v5_route(
 		receipts = ?response.extensions.receipts.rooms.len(),
 		"responding to request with"
 	);
-	Ok(response)
+	Ok(SyncResponse { response, unstable })
+}
+
+/// Connection id used for clients that send none.
+const DEFAULT_CONN_ID: &str = "default";
+
+/// Connection id of a request. Clients that send none share one default
+/// connection per device, which is still keyed by user and device like any
+/// other, so their sticky parameters and known rooms persist across requests.
+fn effective_conn_id(conn_id: Option<&str>) -> String {
+	conn_id.unwrap_or(DEFAULT_CONN_ID).to_owned()
+}
+
+/// Longest `txn_id` echoed back to the client.
+const MAX_TXN_ID_LEN: usize = 255;
+
+/// An absent `txn_id` is valid and is not echoed back.
+fn txn_id_valid(txn_id: Option<&str>) -> bool {
+	txn_id.is_none_or(|txn_id| txn_id.len() <= MAX_TXN_ID_LEN)
+}
+
+/// Number of recent events searched for a room's bump stamp when its timeline
+/// in the response has none.
+const BUMP_STAMP_LOOKBACK: usize = 100;
+
+/// Whether the server's caps truncated what the request asked for: more room
+/// subscriptions than are processed, or a timeline over the limit.
+fn response_capped<I>(
+	subscriptions: usize,
+	max_subscriptions: usize,
+	timeline_limits: I,
+	max_timeline_limit: usize,
+) -> bool
+where
+	I: IntoIterator<Item = UInt>,
+{
+	subscriptions > max_subscriptions
+		|| timeline_limits
+			.into_iter()
+			.any(|limit| usize_from_ruma(limit) > max_timeline_limit)
+}
+
+/// Unstable response field hinting that the server's caps truncated the
+/// response, so the client may page through smaller ranges.
+const RESPONSE_CAPPED_FIELD: &str = "org.matrix.tuwunel.capped";
+
+fn report_capped(unstable: &mut Unstable, capped: bool) {
+	if capped {
+		unstable.set(RESPONSE_CAPPED_FIELD, json!(true));
+	}
+}
+
+/// Timeline length loaded for a room: the requested `timeline_limit`, clamped
+/// to the configured maximum.
+fn timeline_limit(requested: UInt, max: usize) -> usize { usize_from_ruma(requested).min(max) }
+
+/// Holds a request with nothing to report until the watcher or a tracked
+/// typing update fires, or the duration passes. A watcher that fails, as when
+/// its channels close on a service restart, ends the wait at once so the
+/// current response is returned rather than held for the full duration.
+async fn long_poll<W, T>(duration: Duration, watcher: W, typing: T)
+where
+	W: Future<Output = Result> + Send,
+	T: Future<Output = ()> + Send,
+{
+	_ = tokio::time::timeout(duration, async {
+		tokio::select! {
+			result = watcher => if let Err(e) = result {
+				debug_warn!("sync watcher closed, responding early: {e}");
+			},
+			() = typing => {},
+		}
+	})
+	.await;
+}
+
+/// The client's requested long-poll timeout bounded by the server's limits.
+fn poll_timeout(services: &Services, timeout: Option<Duration>) -> Duration {
+	let timeout_default = services.config.client_sync_timeout_default;
+	let timeout_min = services.config.client_sync_timeout_min;
+	let timeout_max = services.config.client_sync_timeout_max;
+
+	timeout
+		.unwrap_or_else(|| Duration::from_millis(timeout_default))
+		.clamp(Duration::from_millis(timeout_min), Duration::from_millis(timeout_max))
+}
+
+/// Leaves each room under only its strongest membership, given the joined,
+/// invited and knocked rooms in that order, should a race or inconsistency
+/// list a room under several or twice.
+fn strongest_memberships(memberships: [Vec<OwnedRoomId>; 3]) -> [Vec<OwnedRoomId>; 3] {
+	let mut seen = HashSet::new();
+	memberships.map(|rooms| {
+		rooms
+			.into_iter()
+			.filter(|room_id| seen.insert(room_id.clone()))
+			.collect()
+	})
+}
+
+/// Serves a typing-only request: typing in the joined rooms the typing
+/// extension is scoped to, waiting for typing in one of them when there is
+/// none to report. Lists are not processed, so their windows are taken from the
+/// room order last sent on the connection.
+async fn typing_poll<'a, Rooms>(
+	services: &Services,
+	sync_info: SyncInfo<'_>,
+	next_batch: u64,
+	snake_key: &SnakeConnectionsKey,
+	rooms: Rooms,
+	wait: bool,
+) -> Result<sync_events::v5::Response>
+where
+	Rooms: Iterator<Item = &'a RoomId> + Send + 'a,
+{
+	let (_, _, globalsince, body) = sync_info;
+	let list_rooms: ListRooms = body
+		.lists
+		.iter()
+		.map(|(list_id, list)| {
+			let order = services
+				.sync
+				.snake_sync_list_order(snake_key, list_id);
+
+			let window = list_window(&order, &list.ranges)
+				.iter()
+				.cloned()
+				.collect();
+
+			(list_id.clone(), window)
+		})
+		.collect();
+
+	let room_ids = extension_rooms(
+		body.extensions.typing.rooms.as_deref(),
+		body.extensions.typing.lists.as_deref(),
+		&list_rooms,
+		body.room_subscriptions.keys().map(Deref::deref),
+		rooms,
+	);
+
+	let updated = typing_updated_rooms(services, room_ids.clone(), |_| globalsince).await;
+	let mut typing = collect_typing_events(services, sync_info, &updated).await?;
+
+	if wait && typing.is_empty() {
+		let duration = poll_timeout(services, body.timeout);
+		let update = services
+			.rooms
+			.typing
+			.wait_for_rooms_update(room_ids.iter().map(Deref::deref), next_batch);
+
+		if tokio::time::timeout(duration, update)
+			.await
+			.is_ok()
+		{
+			let updated = typing_updated_rooms(services, room_ids, |_| next_batch).await;
+			typing = collect_typing_events(services, sync_info, &updated).await?;
+		}
+	}
+
+	Ok(typing_only_response(body.txn_id.clone(), next_batch.to_string(), typing))
+}
+
+/// Response to a typing-only request, leaving out lists, rooms and every other
+/// extension.
+fn typing_only_response(
+	txn_id: Option<String>,
+	pos: String,
+	typing: sync_events::v5::response::Typing,
+) -> sync_events::v5::Response {
+	sync_events::v5::Response {
+		txn_id,
+		pos,
+		lists: BTreeMap::new(),
+		rooms: BTreeMap::new(),
+		extensions: sync_events::v5::response::Extensions { typing, ..Default::default() },
+	}
+}
+
+/// Version prefix of the current `pos` token format.
+const POS_VERSION: &str = "v1";
+
+/// Parses a `pos` token: either a legacy bare count or a versioned
+/// `v1:<count>`. No token is an initial sync at count zero; `None` means the
+/// token is malformed or of an unknown version.
+fn parse_pos(pos: Option<&str>) -> Option<u64> {
+	let Some(pos) = pos else {
+		return Some(0);
+	};
+
+	let count = match pos.split_once(':') {
+		| Some((version, count)) if version == POS_VERSION => count,
+		| Some(_) => return None,
+		| None => pos,
+	};
+
+	// u64::from_str also takes a leading '+'
+	if !count.bytes().all(|byte| byte.is_ascii_digit()) {
+		return None;
+	}
+
+	count.parse().ok()
+}
+
+/// Whether an incremental `pos` lags the current position by more than
+/// `max_age` counts; a `max_age` of zero never expires a position.
+fn pos_expired(globalsince: u64, next_batch: u64, max_age: u64) -> bool {
+	globalsince != 0 && max_age != 0 && next_batch.saturating_sub(globalsince) > max_age
+}
+
+/// Unstable room field carrying the room's unread notifications by kind.
+const NOTIFICATION_COUNTS_FIELD: &str = "org.matrix.tuwunel.unread_notifications";
+
+/// A room's unread notifications split into message notifications and invite
+/// or other notifications, which together make up `notification_count`.
+/// Highlights may be of either kind.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+struct NotificationCounts {
+	#[serde(rename = "message_count")]
+	message: u64,
+	#[serde(rename = "other_count")]
+	other: u64,
+	#[serde(rename = "highlight_count")]
+	highlight: u64,
+}
+
+impl NotificationCounts {
+	/// Splits the tracked counts; message notifications and highlights are
+	/// also counted as notifications.
+	fn new(notification_count: u64, message_count: u64, highlight_count: u64) -> Self {
+		let message = message_count.min(notification_count);
+
+		Self {
+			message,
+			other: notification_count.saturating_sub(message),
+			highlight: highlight_count.min(notification_count),
+		}
+	}
+}
+
+/// Unstable request flag opting in to reporting partially failed rooms.
+const PARTIAL_ERRORS_FLAG: &str = "org.matrix.tuwunel.partial_errors";
+
+/// Unstable response field listing what was left out due to errors.
+const PARTIAL_ERRORS_FIELD: &str = "org.matrix.tuwunel.errors";
+
+/// Rooms whose data was partially left out of the response due to errors.
+#[derive(Debug, Default)]
+struct PartialErrors {
+	enabled: bool,
+	rooms: BTreeMap<OwnedRoomId, Vec<&'static str>>,
+}
+
+impl PartialErrors {
+	fn new(enabled: bool) -> Self { Self { enabled, ..Default::default() } }
+
+	/// Records that `section` of the room failed; ignored unless opted in.
+	fn room(&mut self, room_id: &RoomId, section: &'static str) {
+		if self.enabled {
+			self.rooms
+				.entry(room_id.to_owned())
+				.or_default()
+				.push(section);
+		}
+	}
+
+	/// Lists the failed rooms in the response's unstable errors section, which
+	/// is left out when nothing failed.
+	fn report(self, unstable: &mut Unstable) {
+		if !self.rooms.is_empty() {
+			debug_warn!(rooms = ?self.rooms, "v5 sync response is incomplete");
+			unstable.set(PARTIAL_ERRORS_FIELD, json!({ "rooms": self.rooms }));
+		}
+	}
+}
+
+/// Unstable request flag asking for typing only, as a cheap long-poll.
+const TYPING_ONLY_FLAG: &str = "org.matrix.tuwunel.typing_only";
+
+fn partial_errors_requested(json_body: Option<&CanonicalJsonValue>) -> bool {
+	flag_requested(json_body, PARTIAL_ERRORS_FLAG)
+}
+
+fn typing_only_requested(json_body: Option<&CanonicalJsonValue>) -> bool {
+	flag_requested(json_body, TYPING_ONLY_FLAG)
+}
+
+/// Rooms named in the request's `unsubscribe_rooms`, which the request type
+/// no longer carries. Entries which are not room IDs are ignored.
+fn unsubscribe_rooms(json_body: Option<&CanonicalJsonValue>) -> Vec<OwnedRoomId> {
+	let Some(CanonicalJsonValue::Array(rooms)) =
+		json_body.and_then(|body| json_field(body, "unsubscribe_rooms"))
+	else {
+		return Vec::new();
+	};
+
+	rooms
+		.iter()
+		.filter_map(|room_id| match room_id {
+			| CanonicalJsonValue::String(room_id) => OwnedRoomId::try_from(room_id.as_str()).ok(),
+			| _ => None,
+		})
+		.collect()
+}
+
+fn flag_requested(json_body: Option<&CanonicalJsonValue>, flag: &str) -> bool {
+	match json_body {
+		| Some(CanonicalJsonValue::Object(body)) =>
+			body.get(flag) == Some(&CanonicalJsonValue::Bool(true)),
+		| _ => false,
+	}
+}
+
+/// Extensions understood by this server; any others a client requests are
+/// ignored.
+const KNOWN_EXTENSIONS: &[&str] = &["account_data", "e2ee", "receipts", "to_device", "typing"];
+
+/// Names of extensions in the raw request body which this server does not
+/// implement. These are tolerated for forward-compatibility.
+fn unknown_extensions(json_body: Option<&CanonicalJsonValue>) -> impl Iterator<Item = &str> {
+	json_body
+		.and_then(|body| match body {
+			| CanonicalJsonValue::Object(body) => body.get("extensions"),
+			| _ => None,
+		})
+		.and_then(|extensions| match extensions {
+			| CanonicalJsonValue::Object(extensions) => Some(extensions.keys()),
+			| _ => None,
+		})
+		.into_iter()
+		.flatten()
+		.map(String::as_str)
+		.filter(|name| !KNOWN_EXTENSIONS.contains(name))
 }
 
 async fn fetch_subscriptions(
 	services: &Services,
 	(sender_user, sender_device, globalsince, body): SyncInfo<'_>,
 	known_rooms: &KnownRooms,
+	unsubscribed: &[OwnedRoomId],
 	todo_rooms: &mut TodoRooms,
 ) {
+	let max_subscriptions = services.config.sync_max_subscriptions;
+	if body.room_subscriptions.len() > max_subscriptions {
+		debug_warn!(
+			count = body.room_subscriptions.len(),
+			max = max_subscriptions,
+			"ignoring room subscriptions over the limit"
+		);
+	}
+
 	let mut known_subscription_rooms = BTreeSet::new();
-	for (room_id, room) in &body.room_subscriptions {
+	for (room_id, room) in capped_subscriptions(&body.room_subscriptions, max_subscriptions) {
 		let not_exists = services.rooms.metadata.exists(room_id).eq(&false);
 
 		let is_disabled = services.rooms.metadata.is_disabled(room_id);
@@ -246,37 +733,35 @@ async fn fetch_subscriptions(
 			continue;
 		}
 
-		let todo_room =
-			todo_rooms
-				.entry(room_id.clone())
-				.or_insert((BTreeSet::new(), 0_usize, u64::MAX));
-
-		let limit: UInt = room.timeline_limit;
+		let limit = timeline_limit(room.timeline_limit, services.config.sync_timeline_limit_max);
 
-		todo_room.0.extend(
+		add_todo_room(
+			todo_rooms,
+			room_id,
 			room.required_state
 				.iter()
 				.map(|(ty, sk)| (ty.clone(), sk.as_str().into())),
-		);
-		todo_room.1 = todo_room.1.max(usize_from_ruma(limit));
-		// 0 means unknown because it got out of date
-		todo_room.2 = todo_room.2.min(
+			limit,
 			known_rooms
 				.get("subscriptions")
 				.and_then(|k| k.get(room_id))
 				.copied()
 				.unwrap_or(0),
+			true,
 		);
 		known_subscription_rooms.insert(room_id.clone());
 	}
-	// where this went (protomsc says it was removed)
-	//for r in body.unsubscribe_rooms {
-	//	known_subscription_rooms.remove(&r);
-	//	body.room_subscriptions.remove(&r);
-	//}
+
+	for room_id in unsubscribed {
+		known_subscription_rooms.remove(room_id);
+	}
 
 	if let Some(conn_id) = body.conn_id.clone() {
 		let snake_key = into_snake_key(sender_user, sender_device, conn_id);
+		services
+			.sync
+			.forget_snake_sync_subscriptions(&snake_key, unsubscribed);
+
 		services.sync.update_snake_sync_known_rooms(
 			&snake_key,
 			"subscriptions".to_owned(),
@@ -286,6 +771,15 @@ async fn fetch_subscriptions(
 	}
 }
 
+/// The room subscriptions processed for a request: the first `max` in room ID
+/// order.
+fn capped_subscriptions<V>(
+	subscriptions: &BTreeMap<OwnedRoomId, V>,
+	max: usize,
+) -> impl Iterator<Item = (&OwnedRoomId, &V)> {
+	subscriptions.iter().take(max)
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn handle_lists<'a, Rooms, AllRooms>(
 	services: &Services,
@@ -295,12 +789,24 @@ async fn handle_lists<'a, Rooms, AllRooms>(
 	all_rooms: AllRooms,
 	todo_rooms: &'a mut TodoRooms,
 	known_rooms: &'a KnownRooms,
+	dm_rooms: &DmRooms,
 	response: &'_ mut sync_events::v5::Response,
-) -> KnownRooms
+	json_body: Option<&CanonicalJsonValue>,
+) -> ListRooms
 where
 	Rooms: Iterator<Item = &'a RoomId> + Clone + Send + 'a,
 	AllRooms: Iterator<Item = &'a RoomId> + Clone + Send + 'a,
 {
+	// lists sort favourites pinned by tag order first, then the rest by latest
+	// bump stamp; tags and bump stamps are read once per request, and only for
+	// rooms some list filters or sorts
+	let mut room_tags = RoomTags::new();
+	let mut tags_read = HashSet::new();
+	let mut bump_stamps = BumpStamps::new();
+	let mut stamps_read = HashSet::new();
+	let invited: HashSet<&RoomId> = all_invited_rooms.clone().collect();
+
+	let mut list_rooms = ListRooms::new();
 	for (list_id, list) in &body.lists {
 		let active_rooms: Vec<_> = match list.filters.as_ref().and_then(|f| f.is_invite) {
 			| None => all_rooms.clone().collect(),
@@ -308,6 +814,21 @@ async fn handle_lists<'a, Rooms, AllRooms>(
 			| Some(false) => all_joined_rooms.clone().collect(),
 		};
 
+		// rooms of the included types, less those of any excluded type below
+		let room_types = list_room_types(json_body, list_id.as_str());
+		let active_rooms = if room_types.is_empty() {
+			active_rooms
+		} else {
+			filter_rooms(
+				services,
+				&room_types,
+				&false,
+				active_rooms.iter().stream().map(Deref::deref),
+			)
+			.collect()
+			.await
+		};
+
 		let active_rooms = match list.filters.as_ref().map(|f| &f.not_room_types) {
 			| None => active_rooms,
 			| Some(filter) if filter.is_empty() => active_rooms,
@@ -322,126 +843,517 @@ async fn handle_lists<'a, Rooms, AllRooms>(
 				.await,
 		};
 
-		let mut new_known_rooms: BTreeSet<OwnedRoomId> = BTreeSet::new();
+		let snake_key = body
+			.conn_id
+			.clone()
+			.map(|conn_id| into_snake_key(sender_user, sender_device, conn_id));
 
-		let ranges = list.ranges.clone();
+		let tag_filter = TagFilter::from_request(json_body, list_id.as_str());
+		let is_dm = list_is_dm(json_body, list_id.as_str());
+		let is_dm = match snake_key.as_ref() {
+			| Some(snake_key) =>
+				services
+					.sync
+					.update_snake_sync_list_is_dm(snake_key, list_id.as_str(), is_dm),
+			| None => is_dm,
+		};
 
-		for mut range in ranges {
-			range.0 = uint!(0);
-			range.1 = range
-				.1
-				.clamp(range.0, UInt::try_from(active_rooms.len()).unwrap_or(UInt::MAX));
+		let unread: Vec<_> = active_rooms
+			.iter()
+			.copied()
+			.filter(|room_id| tags_read.insert(*room_id))
+			.collect();
 
-			let room_ids =
-				active_rooms[usize_from_ruma(range.0)..usize_from_ruma(range.1)].to_vec();
+		room_tags.extend(read_room_tags(services, sender_user, unread).await);
 
-			let new_rooms: BTreeSet<OwnedRoomId> = room_ids
-				.clone()
-				.into_iter()
-				.map(From::from)
-				.collect();
+		let list_heroes = include_heroes(json_body, list_id.as_str());
+		let active_rooms: Vec<_> = active_rooms
+			.into_iter()
+			.filter(|room_id| tag_filter.matches(room_tags.get(room_id)))
+			.filter(|room_id| dm_filter_matches(is_dm, dm_rooms, room_id))
+			.collect();
 
-			new_known_rooms.extend(new_rooms);
-			for room_id in room_ids {
-				let todo_room = todo_rooms.entry(room_id.to_owned()).or_insert((
-					BTreeSet::new(),
-					0_usize,
-					u64::MAX,
-				));
-
-				let limit: usize = usize_from_ruma(list.room_details.timeline_limit).min(100);
-
-				todo_room.0.extend(
-					list.room_details
-						.required_state
-						.iter()
-						.map(|(ty, sk)| (ty.clone(), sk.as_str().into())),
-				);
-
-				todo_room.1 = todo_room.1.max(limit);
-				// 0 means unknown because it got out of date
-				todo_room.2 = todo_room.2.min(
-					known_rooms
-						.get(list_id.as_str())
-						.and_then(|k| k.get(room_id))
-						.copied()
-						.unwrap_or(0),
-				);
-			}
+		let unread: Vec<_> = active_rooms
+			.iter()
+			.copied()
+			.filter(|room_id| stamps_read.insert(*room_id))
+			.collect();
+
+		bump_stamps.extend(read_bump_stamps(services, sender_user, unread, &invited).await);
+
+		let previous_order = snake_key
+			.as_ref()
+			.map(|snake_key| {
+				services
+					.sync
+					.snake_sync_list_order(snake_key, list_id.as_str())
+			})
+			.unwrap_or_default();
+
+		let active_rooms = sort_rooms(active_rooms, &room_tags, &bump_stamps, &previous_order);
+
+		let window = list_window(&active_rooms, &list.ranges);
+		if window.is_empty() {
+			trace!(?list_id, "list requests no rooms; reporting its count only");
 		}
-		response
-			.lists
-			.insert(list_id.clone(), sync_events::v5::response::List {
-				count: ruma_from_usize(active_rooms.len()),
-			});
 
-		if let Some(conn_id) = body.conn_id.clone() {
-			let snake_key = into_snake_key(sender_user, sender_device, conn_id);
+		for &room_id in window {
+			let limit = timeline_limit(
+				list.room_details.timeline_limit,
+				services.config.sync_timeline_limit_max,
+			);
+
+			add_todo_room(
+				todo_rooms,
+				room_id,
+				list.room_details
+					.required_state
+					.iter()
+					.map(|(ty, sk)| (ty.clone(), sk.as_str().into())),
+				limit,
+				known_rooms
+					.get(list_id.as_str())
+					.and_then(|k| k.get(room_id))
+					.copied()
+					.unwrap_or(0),
+				list_heroes,
+			);
+		}
+
+		let new_known_rooms: BTreeSet<OwnedRoomId> = window
+			.iter()
+			.copied()
+			.map(ToOwned::to_owned)
+			.collect();
+
+		report_list(&mut response.lists, list_id, active_rooms.len());
+		list_rooms.insert(list_id.clone(), new_known_rooms.clone());
+
+		if let Some(snake_key) = snake_key {
 			services.sync.update_snake_sync_known_rooms(
 				&snake_key,
 				list_id.clone(),
 				new_known_rooms,
 				globalsince,
 			);
+
+			services.sync.update_snake_sync_list_order(
+				&snake_key,
+				list_id.clone(),
+				active_rooms
+					.iter()
+					.copied()
+					.map(ToOwned::to_owned)
+					.collect(),
+			);
 		}
 	}
 
-	BTreeMap::default()
+	list_rooms
 }
 
-async fn process_rooms<'a, Rooms>(
-	services: &Services,
-	sender_user: &UserId,
-	next_batch: u64,
-	all_invited_rooms: Rooms,
-	todo_rooms: &TodoRooms,
-	response: &mut sync_events::v5::Response,
-	body: &sync_events::v5::Request,
-) -> Result<BTreeMap<OwnedRoomId, sync_events::v5::response::Room>>
-where
-	Rooms: Iterator<Item = &'a RoomId> + Clone + Send + 'a,
+/// Adds a room wanted by a list or subscription to the rooms to process,
+/// merging it with what the others already asked of the room.
+fn add_todo_room<S>(
+	todo_rooms: &mut TodoRooms,
+	room_id: &RoomId,
+	required_state: S,
+	limit: usize,
+	roomsince: u64,
+	heroes: bool,
+) where
+	S: Iterator<Item = TypeStateKey>,
 {
-	let mut rooms = BTreeMap::new();
-	for (room_id, (required_state_request, timeline_limit, roomsince)) in todo_rooms {
-		let roomsincecount = PduCount::Normal(*roomsince);
+	let todo_room = todo_rooms.entry(room_id.to_owned()).or_insert((
+		BTreeSet::new(),
+		0_usize,
+		u64::MAX,
+		false,
+	));
+
+	todo_room.0.extend(required_state);
+	todo_room.1 = todo_room.1.max(limit);
+	// 0 means unknown because it got out of date
+	todo_room.2 = todo_room.2.min(roomsince);
+	// heroes are computed when any list or subscription wants them
+	todo_room.3 |= heroes;
+}
 
-		let mut timestamp: Option<_> = None;
-		let mut invite_state = None;
-		let (timeline_pdus, limited);
-		let new_room_id: &RoomId = (*room_id).as_ref();
-		if all_invited_rooms
-			.clone()
-			.any(is_equal_to!(new_room_id))
-		{
-			// TODO: figure out a timestamp we can use for remote invites
-			invite_state = services
-				.rooms
-				.state_cache
-				.invite_state(sender_user, room_id)
-				.await
-				.ok();
+/// Reports a list and its room count. A list whose filters exclude every room
+/// is still reported with a zero count, telling the client it exists but is
+/// empty.
+fn report_list(
+	lists: &mut BTreeMap<String, sync_events::v5::response::List>,
+	list_id: &str,
+	count: usize,
+) {
+	if count == 0 {
+		trace!(?list_id, "no rooms match the list; reporting it as empty");
+	}
 
-			(timeline_pdus, limited) = (Vec::new(), true);
-		} else {
-			(timeline_pdus, limited) = match load_timeline(
-				services,
-				sender_user,
-				room_id,
-				roomsincecount,
-				Some(PduCount::from(next_batch)),
-				*timeline_limit,
-			)
+	lists.insert(list_id.to_owned(), sync_events::v5::response::List {
+		count: ruma_from_usize(count),
+	});
+}
+
+/// Ordering key for rooms within a sliding sync list. Rooms the user tagged
+/// `m.favourite` are pinned above all others, by ascending tag order. The
+/// rest follow by recency: latest bump stamp first, rooms without one last.
+#[derive(Clone, Copy, Debug, Default)]
+struct ListSortKey {
+	pinned: Option<f64>,
+	bump_stamp: Option<UInt>,
+}
+
+impl ListSortKey {
+	fn compare(&self, other: &Self) -> Ordering {
+		match (self.pinned, other.pinned) {
+			| (Some(a), Some(b)) => a.total_cmp(&b),
+			| (Some(_), None) => Ordering::Less,
+			| (None, Some(_)) => Ordering::Greater,
+			| (None, None) => other.bump_stamp.cmp(&self.bump_stamp),
+		}
+	}
+}
+
+/// Sorts a list's rooms by their sort key. Rooms with equal keys keep the
+/// order previously sent on the connection, with rooms new to the list after
+/// them by room ID, so the order only moves when a sort key changed.
+fn sort_rooms<'a>(
+	rooms: Vec<&'a RoomId>,
+	room_tags: &RoomTags<'_>,
+	bump_stamps: &BumpStamps<'_>,
+	previous_order: &[OwnedRoomId],
+) -> Vec<&'a RoomId> {
+	let previous: HashMap<&RoomId, usize> = previous_order
+		.iter()
+		.enumerate()
+		.map(|(position, room_id)| (room_id.deref(), position))
+		.collect();
+
+	let position = |room_id: &RoomId| {
+		previous
+			.get(room_id)
+			.copied()
+			.unwrap_or(usize::MAX)
+	};
+
+	let mut keyed: Vec<_> = rooms
+		.into_iter()
+		.map(|room_id| {
+			let key = list_sort_key(room_tags.get(room_id), bump_stamps.get(room_id).copied());
+			(key, room_id)
+		})
+		.collect();
+
+	keyed.sort_by(|(a, a_id), (b, b_id)| {
+		a.compare(b)
+			.then_with(|| position(*a_id).cmp(&position(*b_id)))
+			.then_with(|| a_id.cmp(b_id))
+	});
+
+	keyed.into_iter().map(at!(1)).collect()
+}
+
+fn list_sort_key(tags: Option<&Tags>, bump_stamp: Option<UInt>) -> ListSortKey {
+	let pinned = tags
+		.and_then(|tags| tags.get(&TagName::Favorite))
+		.map(|info| info.order.unwrap_or(f64::INFINITY));
+
+	ListSortKey { pinned, bump_stamp }
+}
+
+/// Unstable `tags` and `not_tags` list filters selecting rooms by their
+/// `m.tag` account data.
+#[derive(Debug, Default)]
+struct TagFilter {
+	tags: Vec<TagName>,
+	not_tags: Vec<TagName>,
+}
+
+impl TagFilter {
+	fn from_request(json_body: Option<&CanonicalJsonValue>, list_id: &str) -> Self {
+		let filters = json_body
+			.and_then(|body| json_field(body, "lists"))
+			.and_then(|lists| json_field(lists, list_id))
+			.and_then(|list| json_field(list, "filters"));
+
+		let tag_names = |field: &str| -> Vec<TagName> {
+			filters
+				.and_then(|filters| json_field(filters, field))
+				.and_then(|tags| match tags {
+					| CanonicalJsonValue::Array(tags) => Some(tags),
+					| _ => None,
+				})
+				.into_iter()
+				.flatten()
+				.filter_map(|tag| match tag {
+					| CanonicalJsonValue::String(tag) => Some(TagName::from(tag.as_str())),
+					| _ => None,
+				})
+				.collect()
+		};
+
+		Self {
+			tags: tag_names("tags"),
+			not_tags: tag_names("not_tags"),
+		}
+	}
+
+	/// Rooms must carry one of `tags` (when any are given) and none of
+	/// `not_tags`.
+	fn matches(&self, room_tags: Option<&Tags>) -> bool {
+		let tagged = |tag: &TagName| room_tags.is_some_and(|tags| tags.contains_key(tag));
+
+		(self.tags.is_empty() || self.tags.iter().any(tagged))
+			&& !self.not_tags.iter().any(tagged)
+	}
+}
+
+/// Unstable per-list `include_heroes` flag; lists compute heroes unless they
+/// opt out.
+fn include_heroes(json_body: Option<&CanonicalJsonValue>, list_id: &str) -> bool {
+	json_body
+		.and_then(|body| json_field(body, "lists"))
+		.and_then(|lists| json_field(lists, list_id))
+		.and_then(|list| json_field(list, "include_heroes"))
+		.is_none_or(|value| !matches!(value, CanonicalJsonValue::Bool(false)))
+}
+
+/// Unstable per-list `is_dm` filter; absent or non-boolean means no filter.
+fn list_is_dm(json_body: Option<&CanonicalJsonValue>, list_id: &str) -> Option<bool> {
+	json_body
+		.and_then(|body| json_field(body, "lists"))
+		.and_then(|lists| json_field(lists, list_id))
+		.and_then(|list| json_field(list, "filters"))
+		.and_then(|filters| json_field(filters, "is_dm"))
+		.and_then(|value| match value {
+			| CanonicalJsonValue::Bool(is_dm) => Some(*is_dm),
+			| _ => None,
+		})
+}
+
+/// Unstable per-list `room_types` filter; a `null` entry stands for rooms
+/// without a type. Absent or empty means no filter.
+fn list_room_types(json_body: Option<&CanonicalJsonValue>, list_id: &str) -> Vec<RoomTypeFilter> {
+	let Some(CanonicalJsonValue::Array(room_types)) = json_body
+		.and_then(|body| json_field(body, "lists"))
+		.and_then(|lists| json_field(lists, list_id))
+		.and_then(|list| json_field(list, "filters"))
+		.and_then(|filters| json_field(filters, "room_types"))
+	else {
+		return Vec::new();
+	};
+
+	room_types
+		.iter()
+		.filter_map(|room_type| match room_type {
+			| CanonicalJsonValue::Null => Some(RoomTypeFilter::Default),
+			| CanonicalJsonValue::String(room_type) =>
+				Some(RoomTypeFilter::from(Some(RoomType::from(room_type.as_str())))),
+			| _ => None,
+		})
+		.collect()
+}
+
+/// Whether the room passes a list's `is_dm` filter.
+fn dm_filter_matches(is_dm: Option<bool>, dm_rooms: &DmRooms, room_id: &RoomId) -> bool {
+	is_dm.is_none_or(|is_dm| dm_rooms.contains(room_id) == is_dm)
+}
+
+/// Rooms the sender marked as direct chats in their `m.direct` account data.
+async fn dm_rooms(services: &Services, sender_user: &UserId) -> DmRooms {
+	services
+		.account_data
+		.get_global(sender_user, GlobalAccountDataEventType::Direct)
+		.await
+		.map(|event: DirectEvent| direct_rooms(event.content))
+		.unwrap_or_default()
+}
+
+fn direct_rooms(content: DirectEventContent) -> DmRooms {
+	content.0.into_values().flatten().collect()
+}
+
+fn json_field<'a>(value: &'a CanonicalJsonValue, field: &str) -> Option<&'a CanonicalJsonValue> {
+	match value {
+		| CanonicalJsonValue::Object(object) => object.get(field),
+		| _ => None,
+	}
+}
+
+/// Omits an initial sync's `prev_batch` when `suppress` is set, unless the
+/// timeline was limited and the client may need to page back.
+fn initial_prev_batch(
+	prev_batch: Option<String>,
+	initial: bool,
+	limited: bool,
+	suppress: bool,
+) -> Option<String> {
+	prev_batch.filter(|_| !suppress || !initial || limited)
+}
+
+/// The rooms of a sorted list the client sees: all of them up to the end of
+/// its furthest range, since every range starts at the top of the list.
+fn list_window<'a, T>(rooms: &'a [T], ranges: &[(UInt, UInt)]) -> &'a [T] {
+	let end = list_ranges(ranges, rooms.len())
+		.into_iter()
+		.map(|range| range.end)
+		.max()
+		.unwrap_or(0);
+
+	&rooms[..end]
+}
+
+/// Index ranges of a list's sorted rooms to expand. Ranges start at the first
+/// room and end at the requested index or the end of the list; those selecting
+/// no rooms are dropped.
+fn list_ranges(ranges: &[(UInt, UInt)], len: usize) -> Vec<Range<usize>> {
+	ranges
+		.iter()
+		.map(|&(_, end)| 0..usize_from_ruma(end).min(len))
+		.filter(|range| !range.is_empty())
+		.collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_rooms<'a, Rooms>(
+	services: &Services,
+	sender_user: &UserId,
+	next_batch: u64,
+	all_invited_rooms: Rooms,
+	all_knocked_rooms: Rooms,
+	todo_rooms: &TodoRooms,
+	dm_rooms: &DmRooms,
+	response: &mut sync_events::v5::Response,
+	body: &sync_events::v5::Request,
+	partial_errors: &mut PartialErrors,
+	unstable: &mut Unstable,
+) -> Result<BTreeMap<OwnedRoomId, sync_events::v5::response::Room>>
+where
+	Rooms: Iterator<Item = &'a RoomId> + Clone + Send + 'a,
+{
+	let mut rooms = BTreeMap::new();
+	let ignored = ignored_users(services, sender_user).await;
+	for (room_id, (required_state_request, timeline_limit, roomsince, include_heroes)) in
+		todo_rooms
+	{
+		let roomsincecount = PduCount::Normal(*roomsince);
+
+		let mut timestamp: Option<_> = None;
+		let mut invite_state = None;
+		let (timeline_pdus, limited);
+		let new_room_id: &RoomId = (*room_id).as_ref();
+		let in_invited_snapshot = all_invited_rooms
+			.clone()
+			.any(is_equal_to!(new_room_id));
+
+		// The invite may have been accepted since the room lists were gathered.
+		let joined_now = in_invited_snapshot
+			&& services
+				.rooms
+				.state_cache
+				.is_joined(sender_user, room_id)
+				.await;
+
+		let is_invited = invite_pending(in_invited_snapshot, joined_now);
+		if in_invited_snapshot && !is_invited {
+			trace!(%room_id, "invite accepted during sync; sending timeline");
+		}
+
+		// A knock may likewise have been accepted since.
+		let in_knocked_snapshot = !in_invited_snapshot
+			&& all_knocked_rooms
+				.clone()
+				.any(is_equal_to!(new_room_id));
+
+		let knock_joined_now = in_knocked_snapshot
+			&& services
+				.rooms
+				.state_cache
+				.is_joined(sender_user, room_id)
+				.await;
+
+		let is_knocked = invite_pending(in_knocked_snapshot, knock_joined_now);
+
+		if is_invited {
+			invite_state = services
+				.rooms
+				.state_cache
+				.invite_state(sender_user, room_id)
+				.await
+				.ok();
+
+			let invite_ts = services
+				.rooms
+				.state_cache
+				.get_invite_ts(room_id, sender_user)
+				.await
+				.ok();
+
+			timestamp = invite_bump_stamp(invite_state.as_deref(), sender_user, invite_ts);
+
+			let max_invite_state = services.config.sync_max_invite_state_events;
+			if let Some(dropped) = invite_state
+				.as_mut()
+				.and_then(|state| truncate_invite_state(state, max_invite_state))
+			{
+				debug_warn!(%room_id, dropped, "truncated oversized invite_state");
+			}
+
+			(timeline_pdus, limited) = (Vec::new(), true);
+		} else if is_knocked {
+			// A knocker can't see the room; only what was kept from the knock.
+			if services.config.sync_knocked_room_state {
+				invite_state = services
+					.rooms
+					.state_cache
+					.knock_state(sender_user, room_id)
+					.await
+					.ok();
+
+				let max_state = services.config.sync_max_invite_state_events;
+				if let Some(dropped) = invite_state
+					.as_mut()
+					.and_then(|state| truncate_invite_state(state, max_state))
+				{
+					debug_warn!(%room_id, dropped, "truncated oversized knock state");
+				}
+			}
+
+			(timeline_pdus, limited) = (Vec::new(), true);
+		} else {
+			(timeline_pdus, limited) = match load_timeline(
+				services,
+				sender_user,
+				room_id,
+				roomsincecount,
+				Some(PduCount::from(next_batch)),
+				(*timeline_limit).min(services.config.sync_timeline_limit_max),
+			)
 			.await
 			{
 				| Ok(value) => value,
 				| Err(err) => {
 					warn!("Encountered missing timeline in {}, error {}", room_id, err);
+					partial_errors.room(room_id, "timeline");
 					continue;
 				},
 			};
 		}
 
-		if body.extensions.account_data.enabled == Some(true) {
+		let has_room_data = services
+			.account_data
+			.has_room_data(room_id, sender_user);
+
+		if body.extensions.account_data.enabled == Some(true)
+			&& room_account_data_wanted(
+				is_invited,
+				services.config.sync_invited_room_account_data,
+				has_room_data,
+			)
+			.await
+		{
 			response.extensions.account_data.rooms.insert(
 				room_id.to_owned(),
 				services
@@ -473,12 +1385,8 @@ async fn process_rooms<'a, Rooms>(
 			.rooms
 			.read_receipt
 			.readreceipts_since(room_id, *roomsince, Some(next_batch))
-			.filter_map(async |(read_user, _ts, v)| {
-				services
-					.users
-					.user_is_ignored(read_user, sender_user)
-					.await
-					.or_some(v)
+			.ready_filter_map(|(read_user, _ts, v)| {
+				receipt_visible(&ignored, read_user).then_some(v)
 			})
 			.collect()
 			.await;
@@ -489,7 +1397,33 @@ async fn process_rooms<'a, Rooms>(
 
 		let receipt_size = receipts.len();
 
-		if receipt_size > 0 {
+		// Send the fully-read marker along with the room's receipts so clients get
+		// consistent read state from the one extension.
+		let fully_read_count: OptionFuture<_> = (body.extensions.receipts.enabled == Some(true))
+			.then(|| {
+				services
+					.account_data
+					.room_data_count(room_id, sender_user, RoomAccountDataEventType::FullyRead)
+					.ok()
+			})
+			.into();
+
+		if fully_read_count
+			.await
+			.flatten()
+			.is_some_and(|count| fully_read_wanted(*roomsince, next_batch, count))
+		{
+			let fully_read: Result<FullyReadEvent> = services
+				.account_data
+				.get_room(room_id, sender_user, RoomAccountDataEventType::FullyRead)
+				.await;
+
+			if let Ok(fully_read) = fully_read {
+				receipts.push(fully_read_receipt(fully_read.content.event_id, sender_user));
+			}
+		}
+
+		if !receipts.is_empty() {
 			response
 				.extensions
 				.receipts
@@ -529,64 +1463,113 @@ async fn process_rooms<'a, Rooms>(
 				}
 			});
 
-		let room_events: Vec<_> = timeline_pdus
+		let prev_batch = initial_prev_batch(
+			prev_batch,
+			*roomsince == 0,
+			limited,
+			services.config.sync_suppress_initial_prev_batch,
+		);
+
+		if let Some(bounds) = timeline_bounds(&timeline_pdus) {
+			unstable.set_room(room_id, TIMELINE_BOUNDS_FIELD, timeline_bounds_field(bounds));
+		}
+
+		let timeline: Vec<_> = timeline_pdus
 			.iter()
 			.stream()
 			.filter_map(|item| ignored_filter(services, item.clone(), sender_user))
-			.map(at!(1))
-			.map(Event::into_format)
 			.collect()
 			.await;
 
-		for (_, pdu) in timeline_pdus {
-			let ts = pdu.origin_server_ts;
-			if DEFAULT_BUMP_TYPES
-				.binary_search(&pdu.kind)
-				.is_ok() && timestamp.is_none_or(|time| time <= ts)
-			{
-				timestamp = Some(ts);
-			}
+		let live = num_live(&timeline, *roomsince);
+		let room_events: Vec<_> = timeline
+			.into_iter()
+			.map(at!(1))
+			.map(Event::into_format)
+			.collect();
+
+		timestamp = timestamp.max(bump_stamp(timeline_pdus.iter().map(at!(1))));
+
+		// Sparse rooms such as DMs often have nothing new in the timeline; look back
+		// for their last bump so they still sort by it.
+		if timestamp.is_none() && !is_invited && !is_knocked {
+			timestamp = latest_bump_stamp(services, sender_user, room_id).await;
 		}
 
-		let required_state = required_state_request
-			.iter()
-			.stream()
-			.filter_map(async |state| {
-				services
-					.rooms
-					.state_accessor
-					.room_state_get(room_id, &state.0, &state.1)
-					.await
-					.map(Event::into_format)
-					.ok()
-			})
-			.collect()
-			.await;
+		let required_state_plan = RequiredStatePlan::new(&required_state_request);
+		let required_state = if is_knocked {
+			Vec::new()
+		} else if required_state_plan.full_state() {
+			let mut keyed = 0_usize;
+			services
+				.rooms
+				.state_accessor
+				.room_state_full(room_id)
+				.ready_filter_map(|res| {
+					res.inspect_err(|e| warn!(%room_id, "required_state fetch failed: {e}"))
+						.ok()
+				})
+				.filter(|(key, _)| future::ready(required_state_plan.admit(key, &mut keyed)))
+				.map(|(_, pdu)| pdu.into_format())
+				.collect()
+				.await
+		} else {
+			required_state_plan
+				.explicit
+				.iter()
+				.stream()
+				.filter_map(async |state| {
+					services
+						.rooms
+						.state_accessor
+						.room_state_get(room_id, &state.0, &state.1)
+						.await
+						.map(Event::into_format)
+						.inspect_err(|e| {
+							let (event_type, state_key) = state;
+							if e.is_not_found() {
+								trace!(%room_id, %event_type, %state_key, "required_state not found");
+							} else {
+								warn!(%room_id, %event_type, %state_key, "required_state fetch failed: {e}");
+							}
+						})
+						.ok()
+				})
+				.collect()
+				.await
+		};
 
 		let room_name = services
 			.rooms
 			.state_accessor
 			.get_name(room_id)
 			.await
-			.ok();
+			.ok()
+			.or_else(|| {
+				invite_state
+					.as_deref()
+					.and_then(stripped_room_name)
+			});
 
 		// Heroes
-		let heroes: Vec<_> = if room_name.is_none() {
+		let heroes: Vec<_> = if room_name.is_none() && *include_heroes && !is_knocked {
 			services
 				.rooms
 				.state_cache
 				.room_members(room_id)
+				.chain(
+					services
+						.rooms
+						.state_cache
+						.room_members_invited(room_id),
+				)
 				.ready_filter(|member| *member != sender_user)
 				.filter_map(|user_id| {
 					services
 						.rooms
 						.state_accessor
 						.get_member(room_id, user_id)
-						.map_ok(|memberevent| sync_events::v5::response::Hero {
-							user_id: user_id.into(),
-							name: memberevent.displayname,
-							avatar: memberevent.avatar_url,
-						})
+						.map_ok(|memberevent| room_hero(user_id, memberevent))
 						.ok()
 				})
 				.take(5)
@@ -596,6 +1579,8 @@ async fn process_rooms<'a, Rooms>(
 			vec![]
 		};
 
+		let heroes = heroes_with_membership(unstable, room_id, heroes);
+
 		let hero_name = match heroes.len().cmp(&(1_usize)) {
 			| Ordering::Greater => {
 				let firsts = heroes[1..]
@@ -608,103 +1593,521 @@ async fn process_rooms<'a, Rooms>(
 					.collect::<Vec<_>>()
 					.join(", ");
 
-				let last = heroes[0]
-					.name
-					.clone()
-					.unwrap_or_else(|| heroes[0].user_id.to_string());
+				let last = heroes[0]
+					.name
+					.clone()
+					.unwrap_or_else(|| heroes[0].user_id.to_string());
+
+				Some(format!("{firsts} and {last}"))
+			},
+			| Ordering::Equal => Some(
+				heroes[0]
+					.name
+					.clone()
+					.unwrap_or_else(|| heroes[0].user_id.to_string()),
+			),
+			| Ordering::Less => None,
+		};
+
+		let heroes_avatar = if heroes.len() == 1 {
+			heroes[0].avatar.clone()
+		} else {
+			None
+		};
+
+		let room_avatar = match services
+			.rooms
+			.state_accessor
+			.get_avatar(room_id)
+			.await
+		{
+			| ruma::JsOption::Some(avatar) => ruma::JsOption::from_option(avatar.url),
+			| ruma::JsOption::Null => ruma::JsOption::Null,
+			| ruma::JsOption::Undefined => ruma::JsOption::Undefined,
+		};
+
+		let notification_count = services
+			.rooms
+			.user
+			.notification_count(sender_user, room_id)
+			.await;
+
+		let highlight_count = services
+			.rooms
+			.user
+			.highlight_count(sender_user, room_id)
+			.await;
+
+		let message_count = services
+			.rooms
+			.user
+			.message_notification_count(sender_user, room_id)
+			.await;
+
+		let notifications =
+			NotificationCounts::new(notification_count, message_count, highlight_count);
+
+		unstable.set_room(room_id, NOTIFICATION_COUNTS_FIELD, json!(notifications));
+
+		rooms.insert(room_id.clone(), sync_events::v5::response::Room {
+			avatar: if room_name.is_some() {
+				room_avatar
+			} else {
+				ruma::JsOption::from_option(heroes_avatar)
+			},
+			name: room_name.or(hero_name),
+			initial: Some(roomsince == &0),
+			is_dm: Some(dm_rooms.contains(room_id)),
+			invite_state: invite_state.map(|s| {
+				s.into_iter()
+					.map(Raw::cast::<StrippedState>)
+					.collect()
+			}),
+			unread_notifications: UnreadNotificationsCount {
+				highlight_count: Some(
+					highlight_count
+						.try_into()
+						.expect("notification count can't go that high"),
+				),
+				notification_count: Some(
+					notification_count
+						.try_into()
+						.expect("notification count can't go that high"),
+				),
+			},
+			timeline: room_events,
+			required_state,
+			prev_batch,
+			limited,
+			joined_count: Some(
+				services
+					.rooms
+					.state_cache
+					.room_joined_count(room_id)
+					.await
+					.unwrap_or(0)
+					.try_into()
+					.unwrap_or_else(|_| uint!(0)),
+			),
+			invited_count: Some(
+				services
+					.rooms
+					.state_cache
+					.room_invited_count(room_id)
+					.await
+					.unwrap_or(0)
+					.try_into()
+					.unwrap_or_else(|_| uint!(0)),
+			),
+			num_live: Some(ruma_from_usize(live)),
+			bump_stamp: timestamp,
+			heroes: Some(heroes),
+		});
+	}
+	Ok(rooms)
+}
+
+/// Effective fetch plan for a room's `required_state` after the list and
+/// subscription requests have been merged. Explicit keys already covered by a
+/// wildcard state key are dropped so no state event is fetched twice; those
+/// covered only by a `("*", state_key)` pattern stay explicit so the limit on
+/// that pattern does not apply to them.
+#[derive(Debug, Default)]
+struct RequiredStatePlan {
+	wildcards: BTreeSet<TypeStateKey>,
+	explicit: BTreeSet<TypeStateKey>,
+}
+
+const REQUIRED_STATE_WILDCARD: &str = "*";
+
+/// Most events returned for `("*", state_key)` patterns, which match that key
+/// across every event type.
+const MAX_KEYED_REQUIRED_STATE: usize = 100;
+
+impl RequiredStatePlan {
+	fn new(requested: &BTreeSet<TypeStateKey>) -> Self {
+		let (wildcards, explicit): (BTreeSet<_>, BTreeSet<_>) = requested
+			.iter()
+			.cloned()
+			.partition(is_required_state_wildcard);
+
+		let explicit = explicit
+			.into_iter()
+			.filter(|key| {
+				!wildcards
+					.iter()
+					.filter(|pattern| !is_keyed_wildcard(pattern))
+					.any(|pattern| required_state_matches(pattern, key))
+			})
+			.collect();
+
+		Self { wildcards, explicit }
+	}
+
+	/// Whether the room's full state has to be walked; explicit keys are then
+	/// served from that walk rather than looked up individually.
+	fn full_state(&self) -> bool { !self.wildcards.is_empty() }
+
+	/// Whether an event from the full state walk is served. Explicit keys and
+	/// patterns with a wildcard state key ask for whole classes of state and
+	/// are unbounded; events matched only by a `("*", state_key)` pattern are
+	/// held to [`MAX_KEYED_REQUIRED_STATE`], counted in `keyed`.
+	fn admit(&self, key: &TypeStateKey, keyed: &mut usize) -> bool {
+		if self.explicit.contains(key)
+			|| self
+				.wildcards
+				.iter()
+				.filter(|pattern| !is_keyed_wildcard(pattern))
+				.any(|pattern| required_state_matches(pattern, key))
+		{
+			return true;
+		}
+
+		if *keyed >= MAX_KEYED_REQUIRED_STATE
+			|| !self
+				.wildcards
+				.iter()
+				.any(|pattern| required_state_matches(pattern, key))
+		{
+			return false;
+		}
+
+		*keyed = keyed.saturating_add(1);
+		true
+	}
+}
+
+/// `("*", state_key)`: one state key across every event type.
+fn is_keyed_wildcard((event_type, state_key): &TypeStateKey) -> bool {
+	event_type.to_cow_str() == REQUIRED_STATE_WILDCARD
+		&& state_key.as_str() != REQUIRED_STATE_WILDCARD
+}
+
+fn is_required_state_wildcard((event_type, state_key): &TypeStateKey) -> bool {
+	event_type.to_cow_str() == REQUIRED_STATE_WILDCARD
+		|| state_key.as_str() == REQUIRED_STATE_WILDCARD
+}
+
+fn required_state_matches(
+	(pattern_type, pattern_key): &TypeStateKey,
+	(event_type, state_key): &TypeStateKey,
+) -> bool {
+	(pattern_type.to_cow_str() == REQUIRED_STATE_WILDCARD || pattern_type == event_type)
+		&& (pattern_key.as_str() == REQUIRED_STATE_WILDCARD || pattern_key == state_key)
+}
+
+/// Truncates stripped invite state to `max` events, returning how many were
+/// dropped when it was over the limit.
+fn truncate_invite_state<T>(state: &mut Vec<T>, max: usize) -> Option<usize> {
+	let dropped = state
+		.len()
+		.checked_sub(max)
+		.filter(|&dropped| dropped > 0)?;
+	state.truncate(max);
+
+	Some(dropped)
+}
+
+/// The room name carried in stripped state kept from an invite or a knock.
+fn stripped_room_name(state: &[Raw<AnyStrippedStateEvent>]) -> Option<String> {
+	state
+		.iter()
+		.filter(|event| event.get_field::<&str>("type").ok().flatten() == Some("m.room.name"))
+		.find_map(|event| {
+			event
+				.get_field::<serde_json::Value>("content")
+				.ok()
+				.flatten()
+		})
+		.and_then(|content| {
+			content
+				.get("name")?
+				.as_str()
+				.map(ToOwned::to_owned)
+		})
+		.filter(|name| !name.is_empty())
+}
+
+/// Whether a room from the invited (or knocked) snapshot is still pending,
+/// rather than one the user joined since the snapshot was taken.
+fn invite_pending(in_invited_snapshot: bool, joined_now: bool) -> bool {
+	in_invited_snapshot && !joined_now
+}
+
+/// Whether to query a room's account data changes. Users rarely have account
+/// data in rooms they are only invited to, so for those the cheaper
+/// `has_room_data` check is awaited first unless `always` is set.
+async fn room_account_data_wanted<F>(is_invited: bool, always: bool, has_room_data: F) -> bool
+where
+	F: Future<Output = bool> + Send,
+{
+	!is_invited || always || has_room_data.await
+}
+
+/// Unstable room fields carrying the change in the joined and invited counts
+/// since they were last sent on the connection.
+const JOINED_COUNT_DELTA_FIELD: &str = "org.matrix.tuwunel.joined_count_delta";
+const INVITED_COUNT_DELTA_FIELD: &str = "org.matrix.tuwunel.invited_count_delta";
+
+fn report_count_delta(unstable: &mut Unstable, room_id: &RoomId, (joined, invited): (i64, i64)) {
+	unstable.set_room(room_id, JOINED_COUNT_DELTA_FIELD, json!(joined));
+	unstable.set_room(room_id, INVITED_COUNT_DELTA_FIELD, json!(invited));
+}
+
+/// Stream positions of the oldest and newest events delivered in a room's
+/// timeline, or `None` when the timeline is empty.
+fn timeline_bounds<T>(timeline_pdus: &[(PduCount, T)]) -> Option<(PduCount, PduCount)> {
+	let (oldest, _) = timeline_pdus.first()?;
+	let (newest, _) = timeline_pdus.last()?;
+
+	Some((*oldest, *newest))
+}
+
+/// Unstable room field carrying the delivered timeline bounds.
+const TIMELINE_BOUNDS_FIELD: &str = "org.matrix.tuwunel.timeline_bounds";
+
+fn timeline_bounds_field((oldest, newest): (PduCount, PduCount)) -> serde_json::Value {
+	json!({
+		"oldest": oldest.to_string(),
+		"newest": newest.to_string(),
+	})
+}
+
+/// Number of timeline events which arrived after the room was last synced at
+/// `roomsince`, as opposed to historical ones. On an initial sync of the room
+/// every event is historical.
+fn num_live<T>(timeline: &[(PduCount, T)], roomsince: u64) -> usize {
+	if roomsince == 0 {
+		return 0;
+	}
+
+	timeline
+		.iter()
+		.filter(|(count, _)| matches!(count, PduCount::Normal(count) if *count > roomsince))
+		.count()
+}
+
+/// A hero for a nameless room along with the membership it was taken from.
+fn room_hero(
+	user_id: &UserId,
+	member: RoomMemberEventContent,
+) -> (sync_events::v5::response::Hero, MembershipState) {
+	let hero = sync_events::v5::response::Hero {
+		user_id: user_id.into(),
+		name: member.displayname,
+		avatar: member.avatar_url,
+	};
+
+	(hero, member.membership)
+}
+
+/// Unstable hero field carrying the membership the hero was taken from.
+const HERO_MEMBERSHIP_FIELD: &str = "org.matrix.tuwunel.membership";
+
+/// Records each hero's membership as an unstable field on the hero.
+fn heroes_with_membership(
+	unstable: &mut Unstable,
+	room_id: &RoomId,
+	heroes: Vec<(sync_events::v5::response::Hero, MembershipState)>,
+) -> Vec<sync_events::v5::response::Hero> {
+	heroes
+		.into_iter()
+		.map(|(hero, membership)| {
+			unstable.set_hero(room_id, &hero.user_id, HERO_MEMBERSHIP_FIELD, json!(membership));
+			hero
+		})
+		.collect()
+}
+
+/// Users on the sender's `m.ignored_user_list`, read once per request rather
+/// than for every receipt.
+async fn ignored_users(services: &Services, sender_user: &UserId) -> HashSet<OwnedUserId> {
+	services
+		.account_data
+		.get_global(sender_user, GlobalAccountDataEventType::IgnoredUserList)
+		.await
+		.map(|event: IgnoredUserListEvent| ignored_user_ids(event.content))
+		.unwrap_or_default()
+}
+
+fn ignored_user_ids(content: IgnoredUserListEventContent) -> HashSet<OwnedUserId> {
+	content.ignored_users.into_keys().collect()
+}
+
+/// Public receipts of ignored users are hidden from the sender, in the
+/// receipts extension as anywhere else.
+fn receipt_visible(ignored: &HashSet<OwnedUserId>, read_user: &UserId) -> bool {
+	!ignored.contains(read_user)
+}
+
+/// The fully-read marker is sent on an initial sync, or when it was last moved
+/// within the window this sync covers.
+fn fully_read_wanted(roomsince: u64, next_batch: u64, marker_count: u64) -> bool {
+	roomsince == 0 || (marker_count > roomsince && marker_count <= next_batch)
+}
+
+/// The user's `m.fully_read` marker expressed as a receipt on the marked event.
+fn fully_read_receipt(
+	event_id: OwnedEventId,
+	user_id: &UserId,
+) -> Raw<AnySyncEphemeralRoomEvent> {
+	let receipt = Receipt {
+		ts: None,
+		thread: ReceiptThread::Unthreaded,
+	};
+	let content = ReceiptEventContent(BTreeMap::from_iter([(
+		event_id,
+		BTreeMap::from_iter([(
+			ReceiptType::from(RoomAccountDataEventType::FullyRead.to_string()),
+			BTreeMap::from_iter([(user_id.to_owned(), receipt)]),
+		)]),
+	)]));
+
+	let event = serde_json::value::to_raw_value(&SyncEphemeralRoomEvent { content })
+		.expect("receipt created manually");
+
+	Raw::from_json(event)
+}
+
+/// Bump stamp of a room from its timeline: the `origin_server_ts` of the latest
+/// event of a bump type, whoever sent it. Whether the room is a DM plays no
+/// part.
+fn bump_stamp<'a, I>(pdus: I) -> Option<UInt>
+where
+	I: IntoIterator<Item = &'a PduEvent>,
+{
+	pdus.into_iter()
+		.filter(|pdu| is_bump_event(pdu))
+		.map(|pdu| pdu.origin_server_ts)
+		.max()
+}
+
+fn is_bump_event(pdu: &PduEvent) -> bool {
+	DEFAULT_BUMP_TYPES
+		.binary_search(&pdu.kind)
+		.is_ok()
+}
+
+/// Bump stamp of a room from its recent timeline, for rooms with nothing new
+/// to send: the newest bump event within the lookback, which stops the scan.
+async fn latest_bump_stamp(
+	services: &Services,
+	sender_user: &UserId,
+	room_id: &RoomId,
+) -> Option<UInt> {
+	let stamps = services
+		.rooms
+		.timeline
+		.pdus_rev(Some(sender_user), room_id, None)
+		.ignore_err()
+		.take(BUMP_STAMP_LOOKBACK)
+		.ready_filter_map(|(_, pdu)| is_bump_event(&pdu).then_some(pdu.origin_server_ts));
+
+	pin_mut!(stamps);
+	stamps.next().await
+}
+
+/// Tags of those `rooms` which have any, for filtering and sorting lists.
+async fn read_room_tags<'a>(
+	services: &Services,
+	sender_user: &UserId,
+	rooms: Vec<&'a RoomId>,
+) -> RoomTags<'a> {
+	rooms
+		.into_iter()
+		.stream()
+		.filter_map(async |room_id| {
+			services
+				.account_data
+				.get_room::<TagEvent>(room_id, sender_user, RoomAccountDataEventType::Tag)
+				.await
+				.ok()
+				.map(|event| (room_id, event.content.tags))
+		})
+		.collect()
+		.await
+}
+
+/// Bump stamps of those `rooms` which have one, for sorting lists.
+async fn read_bump_stamps<'a>(
+	services: &Services,
+	sender_user: &UserId,
+	rooms: Vec<&'a RoomId>,
+	invited: &HashSet<&RoomId>,
+) -> BumpStamps<'a> {
+	rooms
+		.into_iter()
+		.stream()
+		.filter_map(async |room_id| {
+			list_bump_stamp(services, sender_user, room_id, invited.contains(room_id))
+				.await
+				.map(|bump_stamp| (room_id, bump_stamp))
+		})
+		.collect()
+		.await
+}
 
-				Some(format!("{firsts} and {last}"))
-			},
-			| Ordering::Equal => Some(
-				heroes[0]
-					.name
-					.clone()
-					.unwrap_or_else(|| heroes[0].user_id.to_string()),
-			),
-			| Ordering::Less => None,
-		};
+/// Bump stamp a list sorts a room by, as `process_rooms` reports it: the
+/// invite's for an invited room, otherwise the latest in its timeline.
+async fn list_bump_stamp(
+	services: &Services,
+	sender_user: &UserId,
+	room_id: &RoomId,
+	invited: bool,
+) -> Option<UInt> {
+	if !invited {
+		return latest_bump_stamp(services, sender_user, room_id).await;
+	}
 
-		let heroes_avatar = if heroes.len() == 1 {
-			heroes[0].avatar.clone()
-		} else {
-			None
-		};
+	let invite_state = services
+		.rooms
+		.state_cache
+		.invite_state(sender_user, room_id)
+		.await
+		.ok();
 
-		let room_avatar = match services
-			.rooms
-			.state_accessor
-			.get_avatar(room_id)
-			.await
-		{
-			| ruma::JsOption::Some(avatar) => ruma::JsOption::from_option(avatar.url),
-			| ruma::JsOption::Null => ruma::JsOption::Null,
-			| ruma::JsOption::Undefined => ruma::JsOption::Undefined,
-		};
+	let invite_ts = services
+		.rooms
+		.state_cache
+		.get_invite_ts(room_id, sender_user)
+		.await
+		.ok();
 
-		rooms.insert(room_id.clone(), sync_events::v5::response::Room {
-			avatar: if room_name.is_some() {
-				room_avatar
-			} else {
-				ruma::JsOption::from_option(heroes_avatar)
-			},
-			name: room_name.or(hero_name),
-			initial: Some(roomsince == &0),
-			is_dm: None,
-			invite_state: invite_state.map(|s| {
-				s.into_iter()
-					.map(Raw::cast::<StrippedState>)
-					.collect()
-			}),
-			unread_notifications: UnreadNotificationsCount {
-				highlight_count: Some(
-					services
-						.rooms
-						.user
-						.highlight_count(sender_user, room_id)
-						.await
-						.try_into()
-						.expect("notification count can't go that high"),
-				),
-				notification_count: Some(
-					services
-						.rooms
-						.user
-						.notification_count(sender_user, room_id)
-						.await
-						.try_into()
-						.expect("notification count can't go that high"),
-				),
-			},
-			timeline: room_events,
-			required_state,
-			prev_batch,
-			limited,
-			joined_count: Some(
-				services
-					.rooms
-					.state_cache
-					.room_joined_count(room_id)
-					.await
-					.unwrap_or(0)
-					.try_into()
-					.unwrap_or_else(|_| uint!(0)),
-			),
-			invited_count: Some(
-				services
-					.rooms
-					.state_cache
-					.room_invited_count(room_id)
-					.await
-					.unwrap_or(0)
-					.try_into()
-					.unwrap_or_else(|_| uint!(0)),
-			),
-			num_live: None, // Count events in timeline greater than global sync counter
-			bump_stamp: timestamp,
-			heroes: Some(heroes),
-		});
-	}
-	Ok(rooms)
+	invite_bump_stamp(invite_state.as_deref(), sender_user, invite_ts)
+}
+
+/// Bump stamp for an invited room: the `origin_server_ts` of our member event
+/// in the stripped invite state when the inviting server provided one,
+/// otherwise the local time at which the invite was received.
+fn invite_bump_stamp(
+	invite_state: Option<&[Raw<AnyStrippedStateEvent>]>,
+	sender_user: &UserId,
+	invite_ts: Option<u64>,
+) -> Option<UInt> {
+	invite_state
+		.into_iter()
+		.flatten()
+		.filter(|event| {
+			event
+				.get_field::<StateEventType>("type")
+				.ok()
+				.flatten()
+				.is_some_and(is_equal_to!(StateEventType::RoomMember))
+		})
+		.filter(|event| {
+			event
+				.get_field::<&str>("state_key")
+				.ok()
+				.flatten()
+				.is_some_and(is_equal_to!(sender_user.as_str()))
+		})
+		.find_map(|event| {
+			event
+				.get_field::<UInt>("origin_server_ts")
+				.ok()
+				.flatten()
+		})
+		.or_else(|| invite_ts.and_then(UInt::new))
 }
 
 async fn collect_account_data(
@@ -730,7 +2133,8 @@ async fn collect_account_data(
 		.account_data
 		.changes_since(None, sender_user, globalsince, Some(next_batch))
 		.ready_filter_map(|e| extract_variant!(e, AnyRawAccountDataEvent::Global))
-		.collect()
+		.collect::<Vec<_>>()
+		.map(latest_by_type)
 		.await;
 
 	if let Some(rooms) = &body.extensions.account_data.rooms {
@@ -744,7 +2148,8 @@ async fn collect_account_data(
 					.account_data
 					.changes_since(Some(room), sender_user, globalsince, Some(next_batch))
 					.ready_filter_map(|e| extract_variant!(e, AnyRawAccountDataEvent::Room))
-					.collect()
+					.collect::<Vec<_>>()
+					.map(latest_by_type)
 					.await,
 			);
 		}
@@ -753,6 +2158,27 @@ async fn collect_account_data(
 	account_data
 }
 
+/// Keeps only the latest change of each account data type. Changes are
+/// streamed oldest first and an update normally replaces the type's previous
+/// entry, but one racing the read may still be listed twice.
+fn latest_by_type<T>(events: Vec<Raw<T>>) -> Vec<Raw<T>> {
+	let mut seen = HashSet::new();
+	let mut latest: Vec<_> = events
+		.into_iter()
+		.rev()
+		.filter(|event| {
+			event
+				.get_field::<String>("type")
+				.ok()
+				.flatten()
+				.is_none_or(|event_type| seen.insert(event_type))
+		})
+		.collect();
+
+	latest.reverse();
+	latest
+}
+
 async fn collect_e2ee<'a, Rooms>(
 	services: &Services,
 	(sender_user, sender_device, globalsince, body): (
@@ -942,21 +2368,44 @@ async fn collect_e2ee<'a, Rooms>(
 		}
 	}
 
+	let fallback_key_types = services
+		.users
+		.unused_fallback_key_types(sender_user, sender_device)
+		.await;
+
+	let conn_id = effective_conn_id(body.conn_id.as_deref());
+	let snake_key = into_snake_key(sender_user, sender_device, conn_id);
 	Ok(sync_events::v5::response::E2EE {
-		device_unused_fallback_key_types: None,
+		device_unused_fallback_key_types: services
+			.sync
+			.update_snake_sync_fallback_key_types(&snake_key, fallback_key_types),
 
 		device_one_time_keys_count: services
 			.users
 			.count_one_time_keys(sender_user, sender_device)
 			.await,
 
-		device_lists: DeviceLists {
-			changed: device_list_changes.into_iter().collect(),
-			left: device_list_left.into_iter().collect(),
-		},
+		device_lists: device_lists(sender_user, device_list_changes, device_list_left),
 	})
 }
 
+/// The sender's own device changes come from `keys_changed` and belong in
+/// `changed`; changes by others are only collected per room, where the sender
+/// is skipped. The sender never shares "no room" with themselves, so they are
+/// never `left`.
+fn device_lists(
+	sender_user: &UserId,
+	changed: HashSet<OwnedUserId>,
+	mut left: HashSet<OwnedUserId>,
+) -> DeviceLists {
+	left.remove(sender_user);
+
+	DeviceLists {
+		changed: changed.into_iter().collect(),
+		left: left.into_iter().collect(),
+	}
+}
+
 async fn collect_to_device(
 	services: &Services,
 	(sender_user, sender_device, globalsince, body): SyncInfo<'_>,
@@ -966,69 +2415,309 @@ async fn collect_to_device(
 		return None;
 	}
 
-	services
-		.users
-		.remove_to_device_events(sender_user, sender_device, globalsince)
-		.await;
+	let conn_id = effective_conn_id(body.conn_id.as_deref());
+	let snake_key = into_snake_key(sender_user, sender_device, conn_id);
+	let delivered = services
+		.sync
+		.update_snake_sync_to_device_delivered(&snake_key, next_batch);
+
+	if let Some(acked) = to_device_acked(delivered, globalsince) {
+		services
+			.users
+			.remove_to_device_events(sender_user, sender_device, acked)
+			.await;
+	}
 
 	Some(sync_events::v5::response::ToDevice {
 		next_batch: next_batch.to_string(),
-		events: services
-			.users
-			.get_to_device_events(sender_user, sender_device, None, Some(next_batch))
-			.collect()
-			.await,
+		events: deliverable_to_device(services.users.get_to_device_events(
+			sender_user,
+			sender_device,
+			None,
+			Some(next_batch),
+		))
+		.collect()
+		.await,
 	})
 }
 
-async fn collect_typing_events<'a, Rooms>(
+/// Highest to-device count the client has acknowledged: what an earlier
+/// response on this connection delivered, as far as the client has since
+/// advanced `pos`. Events delivered by the current response are never acked.
+fn to_device_acked(delivered: Option<u64>, globalsince: u64) -> Option<u64> {
+	delivered
+		.map(|delivered| delivered.min(globalsince))
+		.filter(|&acked| acked > 0)
+}
+
+/// The to-device events sent to the client out of those queued for it.
+fn deliverable_to_device<'a, S>(
+	events: S,
+) -> impl Stream<Item = Raw<AnyToDeviceEvent>> + Send + 'a
+where
+	S: Stream<Item = Raw<AnyToDeviceEvent>> + Send + 'a,
+{
+	events.ready_filter(|event| !is_typing_event(event))
+}
+
+/// Typing is only ever delivered through the typing extension; an `m.typing`
+/// event found on the to-device path is dropped rather than leaked there.
+fn is_typing_event<T>(event: &Raw<T>) -> bool {
+	let is_typing = event
+		.get_field::<&str>("type")
+		.ok()
+		.flatten()
+		.is_some_and(is_equal_to!("m.typing"));
+
+	if is_typing {
+		debug_warn!("dropping m.typing event from to-device events");
+	}
+
+	is_typing
+}
+
+/// The `room_ids` whose typing changed after the position the client last saw
+/// each of them at, as given by `since`.
+async fn typing_updated_rooms<F>(
 	services: &Services,
-	(sender_user, _, _, body): SyncInfo<'_>,
-	_next_batch: u64,
-	rooms: Rooms,
-) -> Result<sync_events::v5::response::Typing>
+	room_ids: Vec<OwnedRoomId>,
+	since: F,
+) -> Vec<OwnedRoomId>
 where
-	Rooms: Iterator<Item = &'a RoomId> + Send + 'a,
+	F: Fn(&RoomId) -> u64,
 {
+	room_ids
+		.into_iter()
+		.stream()
+		.filter_map(async |room_id| {
+			let last_update = services
+				.rooms
+				.typing
+				.last_typing_update(&room_id)
+				.await
+				.unwrap_or(u64::MAX);
+
+			(last_update > since(&room_id)).then_some(room_id)
+		})
+		.collect()
+		.await
+}
+
+/// Typing in the selected `room_ids`, which are the rooms whose typing changed.
+/// A room whose typers all stopped is reported with no users so clients clear
+/// it; when no room changed the result is empty and the `typing` extension is
+/// omitted from the response.
+async fn collect_typing_events(
+	services: &Services,
+	(sender_user, _, _, body): SyncInfo<'_>,
+	room_ids: &[OwnedRoomId],
+) -> Result<sync_events::v5::response::Typing> {
 	use sync_events::v5::response::Typing;
 
 	if !body.extensions.typing.enabled.unwrap_or(false) {
 		return Ok(Typing::default());
 	}
 
-	rooms
+	let typing = services
+		.rooms
+		.typing
+		.typing_users_for_rooms(room_ids, sender_user)
+		.await
+		.inspect_err(|e| warn!("Failed to get typing events: {e}"))
+		.unwrap_or_default();
+
+	// typing recorded before the room was encrypted or typing there was disabled
+	let typing: BTreeMap<_, _> = typing
+		.into_iter()
 		.stream()
-		.filter_map(async |room_id| {
+		.filter_map(async |(room_id, users)| {
 			services
 				.rooms
 				.typing
-				.typing_users_for_user(room_id, sender_user)
-				.inspect_err(|e| warn!(%room_id, "Failed to get typing events for room: {e}"))
+				.typing_suppressed(&room_id)
 				.await
-				.ok()
-				.filter(|users| !users.is_empty())
-				.map(|users| Ok((room_id, users)))
+				.eq(&false)
+				.then_some((room_id, users))
+		})
+		.collect()
+		.await;
+
+	// typers who left stay in the typing map until their timeout is reaped
+	let departed: Vec<_> = typing
+		.iter()
+		.flat_map(|(room_id, users)| {
+			users
+				.iter()
+				.map(move |user_id| (room_id, user_id))
 		})
-		.ready_try_fold_default(|mut response: Typing, (room_id, users)| {
+		.stream()
+		.filter_map(async |(room_id, user_id)| {
+			services
+				.rooms
+				.state_cache
+				.is_joined(user_id, room_id)
+				.await
+				.eq(&false)
+				.then(|| (room_id.clone(), user_id.clone()))
+		})
+		.collect()
+		.await;
+
+	let typing = typing_response(without_departed(typing, &departed))?;
+
+	// one line per sync; this runs on every poll of every client
+	trace!(rooms = typing.rooms.len(), "rooms with typing changes");
+
+	Ok(typing)
+}
+
+/// The `typing` extension for each room's current typers, including rooms
+/// where nobody is typing anymore.
+fn typing_response(
+	typing: BTreeMap<OwnedRoomId, Vec<OwnedUserId>>,
+) -> Result<sync_events::v5::response::Typing> {
+	typing.into_iter().try_fold(
+		sync_events::v5::response::Typing::default(),
+		|mut response, (room_id, users)| {
 			response.rooms.insert(
-				room_id.to_owned(),
+				room_id,
 				Raw::new(&ruma::events::typing::SyncTypingEvent {
 					content: TypingEventContent::new(users),
 				})?,
 			);
 
 			Ok(response)
+		},
+	)
+}
+
+/// The position the client last saw `room_id` at: the room's own since when
+/// it is being sent, otherwise the connection's. Typing no newer than this
+/// was already sent and is left out rather than repeated on every poll.
+fn typing_room_since(todo_rooms: &TodoRooms, room_id: &RoomId, globalsince: u64) -> u64 {
+	todo_rooms
+		.get(room_id)
+		.map_or(globalsince, |(_, _, roomsince, _)| *roomsince)
+}
+
+/// Drops typing users who are no longer joined to the room they were typing
+/// in.
+fn without_departed(
+	typing: BTreeMap<OwnedRoomId, Vec<OwnedUserId>>,
+	departed: &[(OwnedRoomId, OwnedUserId)],
+) -> BTreeMap<OwnedRoomId, Vec<OwnedUserId>> {
+	typing
+		.into_iter()
+		.map(|(room_id, mut users)| {
+			users.retain(|user_id| {
+				!departed
+					.iter()
+					.any(|(departed_room, departed_user)| {
+						*departed_room == room_id && departed_user == user_id
+					})
+			});
+			(room_id, users)
+		})
+		.collect()
+}
+
+/// Public receipts since the connection's position for the rooms the receipts
+/// extension asks for. Receipts of ignored users are hidden as they are in
+/// `process_rooms`.
+async fn collect_receipts<'a, Rooms>(
+	services: &Services,
+	(sender_user, _, globalsince, body): SyncInfo<'_>,
+	next_batch: u64,
+	list_rooms: &ListRooms,
+	rooms: Rooms,
+) -> sync_events::v5::response::Receipts
+where
+	Rooms: Iterator<Item = &'a RoomId> + Send + 'a,
+{
+	use sync_events::v5::response::Receipts;
+
+	if body.extensions.receipts.enabled != Some(true) {
+		return Receipts::default();
+	}
+
+	let room_ids = extension_rooms(
+		body.extensions.receipts.rooms.as_deref(),
+		body.extensions.receipts.lists.as_deref(),
+		list_rooms,
+		body.room_subscriptions.keys().map(Deref::deref),
+		rooms,
+	);
+
+	let ignored = ignored_users(services, sender_user).await;
+
+	room_ids
+		.into_iter()
+		.stream()
+		.filter_map(async |room_id| {
+			let receipts: Vec<Raw<AnySyncEphemeralRoomEvent>> = services
+				.rooms
+				.read_receipt
+				.readreceipts_since(&room_id, globalsince, Some(next_batch))
+				.ready_filter_map(|(read_user, _ts, v)| {
+					receipt_visible(&ignored, read_user).then_some(v)
+				})
+				.collect()
+				.await;
+
+			(!receipts.is_empty())
+				.then(|| (room_id, pack_receipts(Box::new(receipts.into_iter()))))
 		})
+		.collect::<BTreeMap<_, _>>()
+		.map(|rooms| Receipts { rooms })
 		.await
 }
 
-async fn collect_receipts(
-	_services: &Services,
-	(_sender_user, _, _globalsince, _body): SyncInfo<'_>,
-	_next_batch: u64,
-) -> sync_events::v5::response::Receipts {
-	sync_events::v5::response::Receipts { rooms: BTreeMap::new() }
-	// TODO: get explicitly requested read receipts
+/// Rooms an extension reports on out of the user's joined `rooms`: those named
+/// in the extension's `rooms`, every subscribed room when it asks for all of
+/// them, and the rooms in the windows `handle_lists` resolved for the `lists`
+/// it names. When it names neither rooms nor lists, every subscribed room and
+/// the rooms of every list are used.
+fn extension_rooms<'a, 'b, Rooms, Subscribed>(
+	requested_rooms: Option<&'b [ExtensionRoomConfig]>,
+	requested_lists: Option<&'b [String]>,
+	list_rooms: &'b ListRooms,
+	subscribed: Subscribed,
+	rooms: Rooms,
+) -> Vec<OwnedRoomId>
+where
+	Rooms: Iterator<Item = &'a RoomId>,
+	Subscribed: Iterator<Item = &'b RoomId>,
+{
+	let unscoped = requested_rooms.is_none() && requested_lists.is_none();
+	let all_subscribed = unscoped
+		|| requested_rooms
+			.into_iter()
+			.flatten()
+			.any(|erc| matches!(erc, ExtensionRoomConfig::AllSubscribed));
+
+	let in_requested_list = |list_id: &String| {
+		unscoped
+			|| requested_lists.is_some_and(|requested_lists| requested_lists.contains(list_id))
+	};
+
+	let selected: HashSet<&RoomId> = requested_rooms
+		.into_iter()
+		.flatten()
+		.filter_map(|erc| extract_variant!(erc, ExtensionRoomConfig::Room))
+		.map(Deref::deref)
+		.chain(subscribed.filter(|_| all_subscribed))
+		.chain(
+			list_rooms
+				.iter()
+				.filter(|(list_id, _)| in_requested_list(list_id))
+				.flat_map(|(_, list)| list.iter().map(Deref::deref)),
+		)
+		.collect();
+
+	rooms
+		.filter(|room_id| selected.contains(room_id))
+		.map(ToOwned::to_owned)
+		.collect()
 }
 
 fn filter_rooms<'a, Rooms>(
@@ -1047,21 +2736,49 @@ fn filter_rooms<'a, Rooms>(
 			.get_room_type(room_id)
 			.await;
 
-		if room_type
-			.as_ref()
-			.is_err_and(|e| !e.is_not_found())
-		{
-			return None;
-		}
-
-		let room_type_filter = RoomTypeFilter::from(room_type.ok());
+		let exclude_on_error = services
+			.config
+			.sync_exclude_rooms_on_room_type_error;
 
-		let include = if *negate {
-			!filter.contains(&room_type_filter)
-		} else {
-			filter.is_empty() || filter.contains(&room_type_filter)
-		};
+		let room_type = resolve_room_type(room_id, room_type, exclude_on_error)?;
 
-		include.then_some(room_id)
+		room_type_matches(filter, *negate, &RoomTypeFilter::from(room_type)).then_some(room_id)
 	})
 }
+
+/// Whether a room of `room_type` passes a list's `room_types` filter, or its
+/// `not_room_types` filter when `negate` is set. An empty `room_types` filter
+/// passes every room.
+fn room_type_matches(
+	filter: &[RoomTypeFilter],
+	negate: bool,
+	room_type: &RoomTypeFilter,
+) -> bool {
+	if negate {
+		!filter.contains(room_type)
+	} else {
+		filter.is_empty() || filter.contains(room_type)
+	}
+}
+
+/// Resolves the room type used for list filtering. A room without a type is an
+/// ordinary room; any other error excludes the room (`None`) only when
+/// `exclude_on_error` is set, otherwise the room is kept as an ordinary room.
+fn resolve_room_type(
+	room_id: &RoomId,
+	room_type: Result<RoomType>,
+	exclude_on_error: bool,
+) -> Option<Option<RoomType>> {
+	match room_type {
+		| Ok(room_type) => Some(Some(room_type)),
+		| Err(e) if e.is_not_found() => Some(None),
+		| Err(e) if exclude_on_error => {
+			warn!(%room_id, "excluding room with unreadable room type: {e}");
+			None
+		},
+		| Err(e) => {
+			warn!(%room_id, "keeping room with unreadable room type: {e}");
+			Some(None)
+		},
+	}
+}
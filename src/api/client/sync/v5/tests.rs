@@ -0,0 +1,1623 @@
+use std::{
+	cmp::Ordering,
+	collections::{BTreeMap, BTreeSet, HashSet},
+	sync::atomic::{AtomicBool, Ordering::Relaxed},
+	time::{Duration, Instant},
+};
+
+use futures::{StreamExt, future};
+use ruma::{
+	CanonicalJsonValue, OwnedRoomId, OwnedUserId, RoomId, UInt,
+	api::{
+		OutgoingResponse,
+		client::sync::sync_events::v5::{
+			request::ExtensionRoomConfig,
+			response::{Extensions, Room, Typing},
+		},
+	},
+	device_id,
+	directory::RoomTypeFilter,
+	event_id,
+	events::{
+		AnyGlobalAccountDataEvent, AnyStrippedStateEvent, AnySyncEphemeralRoomEvent,
+		AnySyncStateEvent, AnyToDeviceEvent, StateEventType,
+		direct::DirectEventContent,
+		ignored_user_list::IgnoredUserListEventContent,
+		room::member::{MembershipState, RoomMemberEventContent},
+		tag::{TagInfo, TagName, Tags},
+		typing::{SyncTypingEvent, TypingEventContent},
+	},
+	room::RoomType,
+	room_id,
+	serde::Raw,
+	uint, user_id,
+};
+use serde_json::json;
+use tuwunel_core::{
+	Err, err,
+	matrix::{Event, PduEvent, TypeStateKey, pdu::PduCount},
+	utils::IterStream,
+};
+use tuwunel_service::rooms::read_receipt::pack_receipts;
+
+use super::{
+	BumpStamps, DEFAULT_CONN_ID, HERO_MEMBERSHIP_FIELD, INVITED_COUNT_DELTA_FIELD,
+	JOINED_COUNT_DELTA_FIELD, ListRooms, ListSortKey, MAX_KEYED_REQUIRED_STATE, MAX_TXN_ID_LEN,
+	NOTIFICATION_COUNTS_FIELD, NotificationCounts, PARTIAL_ERRORS_FIELD, PartialErrors,
+	RESPONSE_CAPPED_FIELD, RequiredStatePlan, RoomTags, SyncResponse, TIMELINE_BOUNDS_FIELD,
+	TagFilter, TodoRooms, Unstable, add_todo_room, bump_stamp, capped_subscriptions,
+	deliverable_to_device, device_lists, direct_rooms, dm_filter_matches, effective_conn_id,
+	extension_rooms, fully_read_receipt, fully_read_wanted, heroes_with_membership,
+	ignored_user_ids, include_heroes, initial_prev_batch, into_snake_key, invite_bump_stamp,
+	invite_pending, latest_by_type, list_is_dm, list_ranges, list_room_types, list_window,
+	long_poll, num_live, parse_pos, partial_errors_requested, pos_expired, receipt_visible,
+	report_capped, report_count_delta, report_list, resolve_room_type, response_capped,
+	room_account_data_wanted, room_hero, room_type_matches, sort_rooms, stripped_room_name,
+	strongest_memberships, timeline_bounds, timeline_bounds_field, timeline_limit,
+	to_device_acked, truncate_invite_state, txn_id_valid, typing_only_requested,
+	typing_only_response, typing_response, typing_room_since, unknown_extensions,
+	unsubscribe_rooms, without_departed,
+};
+
+fn sorted(mut keys: Vec<(ListSortKey, &str)>) -> Vec<&str> {
+	keys.sort_by(|(a, _), (b, _)| a.compare(b));
+	keys.into_iter().map(|(_, id)| id).collect()
+}
+
+#[test]
+fn pinned_room_sorts_above_unpinned() {
+	let stale_pinned = ListSortKey { pinned: Some(0.5), ..Default::default() };
+	let active_unpinned = ListSortKey::default();
+
+	assert_eq!(stale_pinned.compare(&active_unpinned), Ordering::Less);
+	assert_eq!(sorted(vec![(active_unpinned, "!active"), (stale_pinned, "!pinned")]), [
+		"!pinned", "!active"
+	]);
+}
+
+#[test]
+fn pinned_rooms_follow_tag_order() {
+	let keys = vec![
+		(ListSortKey::default(), "!plain"),
+		(
+			ListSortKey {
+				pinned: Some(f64::INFINITY),
+				..Default::default()
+			},
+			"!unordered",
+		),
+		(ListSortKey { pinned: Some(0.9), ..Default::default() }, "!second"),
+		(ListSortKey { pinned: Some(0.1), ..Default::default() }, "!first"),
+	];
+
+	assert_eq!(sorted(keys), ["!first", "!second", "!unordered", "!plain"]);
+}
+
+#[test]
+fn recent_rooms_sort_first() {
+	let key = |bump_stamp: Option<u64>| ListSortKey {
+		bump_stamp: bump_stamp.map(|ts| UInt::new(ts).unwrap()),
+		..Default::default()
+	};
+
+	let keys =
+		vec![(key(None), "!quiet"), (key(Some(100)), "!older"), (key(Some(200)), "!newer")];
+
+	assert_eq!(sorted(keys), ["!newer", "!older", "!quiet"]);
+}
+
+#[test]
+fn pinned_room_sorts_above_recent_rooms() {
+	let pinned = ListSortKey { pinned: Some(0.5), ..Default::default() };
+	let recent = ListSortKey {
+		bump_stamp: Some(uint!(200)),
+		..Default::default()
+	};
+
+	assert_eq!(sorted(vec![(recent, "!recent"), (pinned, "!pinned")]), ["!pinned", "!recent"]);
+}
+
+#[test]
+fn list_sorted_by_precomputed_bump_stamps() {
+	let a = room_id!("!a:example.org");
+	let b = room_id!("!b:example.org");
+	let c = room_id!("!c:example.org");
+	let bump_stamps: BumpStamps<'_> = [(a, uint!(100)), (c, uint!(300))].into();
+
+	// previously sent order gives way to recency
+	let previous = [a.to_owned(), b.to_owned(), c.to_owned()];
+	assert_eq!(sort_rooms(vec![a, b, c], &RoomTags::new(), &bump_stamps, &previous), [c, a, b]);
+}
+
+#[test]
+fn empty_typing_extension_is_omitted() {
+	let extensions = Extensions::default();
+	let json = serde_json::to_value(&extensions).expect("serialized extensions");
+
+	assert!(json.get("typing").is_none(), "empty typing extension serialized: {json}");
+}
+
+fn stripped_member(state_key: &str, origin_server_ts: Option<u64>) -> Raw<AnyStrippedStateEvent> {
+	let mut event = json!({
+		"type": "m.room.member",
+		"state_key": state_key,
+		"sender": "@inviter:remote.example",
+		"content": { "membership": "invite" },
+	});
+
+	if let Some(ts) = origin_server_ts {
+		event["origin_server_ts"] = ts.into();
+	}
+
+	serde_json::from_value(event).expect("valid stripped state event")
+}
+
+#[test]
+fn invite_bump_stamp_prefers_origin_server_ts() {
+	let user = user_id!("@alice:example.org");
+	let state = [stripped_member(user.as_str(), Some(1_000))];
+
+	assert_eq!(invite_bump_stamp(Some(state.as_slice()), user, Some(5_000)), Some(uint!(1_000)));
+}
+
+#[test]
+fn invite_bump_stamp_falls_back_to_receive_time() {
+	let user = user_id!("@alice:example.org");
+	let state = [
+		stripped_member(user.as_str(), None),
+		stripped_member("@bob:example.org", Some(1_000)),
+	];
+
+	let remote = invite_bump_stamp(Some(state.as_slice()), user, Some(5_000));
+	assert_eq!(remote, Some(uint!(5_000)));
+
+	let older: Option<UInt> = invite_bump_stamp(None, user, Some(2_000));
+	assert!(remote > older, "more recently received invite sorts first");
+	assert_eq!(invite_bump_stamp(None, user, None), None);
+}
+
+#[test]
+fn unknown_extensions_are_reported() {
+	let body: CanonicalJsonValue = serde_json::from_value(json!({
+		"extensions": {
+			"typing": { "enabled": true },
+			"org.example.future": { "enabled": true },
+		},
+	}))
+	.expect("canonical json body");
+
+	let unknown: Vec<_> = unknown_extensions(Some(&body)).collect();
+	assert_eq!(unknown, ["org.example.future"]);
+	assert_eq!(unknown_extensions(None).count(), 0);
+}
+
+#[test]
+fn timeline_bounds_match_first_and_last_delivered() {
+	let timeline = [
+		(PduCount::Normal(41), "a"),
+		(PduCount::Normal(45), "b"),
+		(PduCount::Normal(52), "c"),
+	];
+
+	assert_eq!(timeline_bounds(&timeline), Some((PduCount::Normal(41), PduCount::Normal(52))));
+	assert_eq!(timeline_bounds::<()>(&[]), None);
+}
+
+/// Serializes a response with `rooms` and the given unstable fields, as it is
+/// sent to the client.
+fn response_body(unstable: Unstable, rooms: Vec<(&RoomId, Room)>) -> serde_json::Value {
+	let mut response = typing_only_response(None, "1".to_owned(), Default::default());
+	response.rooms = rooms
+		.into_iter()
+		.map(|(room_id, room)| (room_id.to_owned(), room))
+		.collect();
+
+	let body = SyncResponse { response, unstable }
+		.try_into_http_response::<Vec<u8>>()
+		.expect("response serializes")
+		.into_body();
+
+	serde_json::from_slice(&body).expect("response body is json")
+}
+
+#[test]
+fn timeline_bounds_sent_on_the_room() {
+	let (a, b) = (room_id!("!a:example.org"), room_id!("!b:example.org"));
+	let timeline = [(PduCount::Normal(41), "a"), (PduCount::Normal(52), "b")];
+	let bounds = timeline_bounds(&timeline).expect("timeline is not empty");
+
+	let mut unstable = Unstable::default();
+	unstable.set_room(a, TIMELINE_BOUNDS_FIELD, timeline_bounds_field(bounds));
+	let body = response_body(unstable, vec![(a, Room::default()), (b, Room::default())]);
+
+	assert_eq!(
+		body["rooms"][a.as_str()][TIMELINE_BOUNDS_FIELD],
+		json!({ "oldest": "41", "newest": "52" })
+	);
+	assert!(
+		body["rooms"][b.as_str()]
+			.get(TIMELINE_BOUNDS_FIELD)
+			.is_none()
+	);
+}
+
+#[test]
+fn num_live_counts_events_after_roomsince() {
+	let timeline = [
+		(PduCount::Backfilled(-3), "backfilled"),
+		(PduCount::Normal(40), "a"),
+		(PduCount::Normal(45), "b"),
+		(PduCount::Normal(46), "c"),
+		(PduCount::Normal(52), "d"),
+	];
+
+	assert_eq!(num_live(&timeline, 45), 2);
+	assert_eq!(num_live(&timeline, 52), 0);
+	assert_eq!(num_live(&timeline, 10), 4);
+}
+
+#[test]
+fn num_live_zero_on_initial_sync() {
+	let timeline = [(PduCount::Normal(40), "a"), (PduCount::Normal(52), "b")];
+	assert_eq!(num_live(&timeline, 0), 0);
+	assert_eq!(num_live::<()>(&[], 45), 0);
+}
+
+#[test]
+fn room_type_transient_error_keeps_room_when_failing_open() {
+	let room_id = room_id!("!room:example.org");
+
+	let resolved = resolve_room_type(room_id, Err(err!(Database("transient"))), false);
+	assert_eq!(resolved, Some(None), "room should be kept as an ordinary room");
+
+	let resolved = resolve_room_type(room_id, Err(err!(Database("transient"))), true);
+	assert_eq!(resolved, None, "room should be excluded");
+}
+
+#[test]
+fn room_type_not_found_is_ordinary_room() {
+	let room_id = room_id!("!room:example.org");
+	let not_found = || Err(err!(Request(NotFound("no room type"))));
+
+	assert_eq!(resolve_room_type(room_id, not_found(), true), Some(None));
+	assert_eq!(
+		resolve_room_type(room_id, Ok(RoomType::Space), true),
+		Some(Some(RoomType::Space))
+	);
+}
+
+#[test]
+fn fully_read_marker_sent_when_it_moved() {
+	// initial sync
+	assert!(fully_read_wanted(0, 10, 3));
+
+	// moved within the window, regardless of other receipts
+	assert!(fully_read_wanted(5, 10, 7));
+	assert!(fully_read_wanted(5, 10, 10));
+
+	// unchanged since the last sync, or moved after this one
+	assert!(!fully_read_wanted(5, 10, 5));
+	assert!(!fully_read_wanted(5, 10, 3));
+	assert!(!fully_read_wanted(5, 10, 11));
+}
+
+#[test]
+fn fully_read_marker_included_with_receipts() {
+	let user_id = user_id!("@alice:example.org");
+	let event_id = event_id!("$marked:example.org");
+
+	let read: Raw<_> = serde_json::from_value(json!({
+		"type": "m.receipt",
+		"content": {
+			event_id.as_str(): { "m.read": { "@bob:example.org": { "ts": 1 } } },
+		},
+	}))
+	.expect("valid receipt event");
+
+	let packed =
+		pack_receipts([read, fully_read_receipt(event_id.to_owned(), user_id)].into_iter());
+	let packed: serde_json::Value =
+		serde_json::from_str(packed.json().get()).expect("packed receipts");
+	let receipts = &packed["content"][event_id.as_str()];
+
+	assert!(receipts["m.fully_read"][user_id.as_str()].is_object());
+	assert!(receipts["m.read"]["@bob:example.org"].is_object());
+}
+
+#[test]
+fn threaded_receipt_keeps_thread_id() {
+	let event_id = event_id!("$in_thread:example.org");
+	let threaded: Raw<_> = serde_json::from_value(json!({
+		"type": "m.receipt",
+		"content": {
+			event_id.as_str(): {
+				"m.read": {
+					"@alice:example.org": { "ts": 1, "thread_id": "$root:example.org" },
+				},
+			},
+		},
+	}))
+	.expect("valid receipt event");
+
+	let packed = pack_receipts([threaded].into_iter());
+	let packed: serde_json::Value =
+		serde_json::from_str(packed.json().get()).expect("packed receipts");
+
+	assert_eq!(
+		packed["content"][event_id.as_str()]["m.read"]["@alice:example.org"]["thread_id"],
+		"$root:example.org"
+	);
+}
+
+#[test]
+fn ancient_pos_forces_reinit() {
+	assert!(pos_expired(10, 1_000_000, 100_000));
+	assert!(!pos_expired(950_000, 1_000_000, 100_000));
+}
+
+#[test]
+fn pos_age_unlimited_or_initial_never_expires() {
+	assert!(!pos_expired(10, 1_000_000, 0));
+	assert!(!pos_expired(0, 1_000_000, 100_000));
+}
+
+#[test]
+fn heroes_carry_their_membership() {
+	let joined = user_id!("@joined:example.org");
+	let invited = user_id!("@invited:example.org");
+
+	let mut member = RoomMemberEventContent::new(MembershipState::Join);
+	member.displayname = Some("Joined".to_owned());
+	let (hero, membership) = room_hero(joined, member);
+	assert_eq!(hero.user_id, joined);
+	assert_eq!(hero.name.as_deref(), Some("Joined"));
+	assert_eq!(membership, MembershipState::Join);
+
+	let (hero, membership) =
+		room_hero(invited, RoomMemberEventContent::new(MembershipState::Invite));
+	assert_eq!(hero.user_id, invited);
+	assert_eq!(membership, MembershipState::Invite);
+}
+
+#[test]
+fn member_count_deltas_sent_beside_the_counts() {
+	let room_id = room_id!("!big:example.org");
+	let room = Room {
+		joined_count: Some(uint!(13)),
+		invited_count: Some(uint!(1)),
+		..Default::default()
+	};
+
+	let mut unstable = Unstable::default();
+	report_count_delta(&mut unstable, room_id, (3, -1));
+	let body = response_body(unstable, vec![(room_id, room)]);
+	let room = &body["rooms"][room_id.as_str()];
+
+	assert_eq!(room["joined_count"], 13);
+	assert_eq!(room[JOINED_COUNT_DELTA_FIELD], 3);
+	assert_eq!(room["invited_count"], 1);
+	assert_eq!(room[INVITED_COUNT_DELTA_FIELD], -1);
+}
+
+#[test]
+fn heroes_sent_with_their_membership() {
+	let room_id = room_id!("!nameless:example.org");
+	let joined = user_id!("@joined:example.org");
+	let invited = user_id!("@invited:example.org");
+
+	let mut unstable = Unstable::default();
+	let heroes = heroes_with_membership(&mut unstable, room_id, vec![
+		room_hero(joined, RoomMemberEventContent::new(MembershipState::Join)),
+		room_hero(invited, RoomMemberEventContent::new(MembershipState::Invite)),
+	]);
+
+	let room = Room {
+		heroes: Some(heroes),
+		..Default::default()
+	};
+	let body = response_body(unstable, vec![(room_id, room)]);
+	let heroes = &body["rooms"][room_id.as_str()]["heroes"];
+
+	assert_eq!(heroes[0]["user_id"], joined.as_str());
+	assert_eq!(heroes[0][HERO_MEMBERSHIP_FIELD], "join");
+	assert_eq!(heroes[1]["user_id"], invited.as_str());
+	assert_eq!(heroes[1][HERO_MEMBERSHIP_FIELD], "invite");
+}
+
+#[test]
+fn failed_timeline_reported_when_opted_in() {
+	let body: CanonicalJsonValue =
+		serde_json::from_value(json!({ "org.matrix.tuwunel.partial_errors": true }))
+			.expect("canonical json body");
+
+	let room_id = room_id!("!broken:example.org");
+	let mut errors = PartialErrors::new(partial_errors_requested(Some(&body)));
+	errors.room(room_id, "timeline");
+
+	let mut unstable = Unstable::default();
+	errors.report(&mut unstable);
+	let body = response_body(unstable, Vec::new());
+
+	assert_eq!(body[PARTIAL_ERRORS_FIELD]["rooms"][room_id.as_str()], json!(["timeline"]));
+}
+
+#[test]
+fn failed_timeline_not_reported_without_opt_in() {
+	let room_id = room_id!("!broken:example.org");
+	let mut errors = PartialErrors::new(partial_errors_requested(None));
+	errors.room(room_id, "timeline");
+
+	let mut unstable = Unstable::default();
+	errors.report(&mut unstable);
+	let body = response_body(unstable, Vec::new());
+
+	assert!(body.get(PARTIAL_ERRORS_FIELD).is_none());
+}
+
+#[tokio::test]
+async fn to_device_never_carries_typing() {
+	let event = |kind: &str| -> Raw<AnyToDeviceEvent> {
+		serde_json::from_value(json!({
+			"type": kind,
+			"sender": "@alice:example.org",
+			"content": { "user_ids": ["@alice:example.org"] },
+		}))
+		.expect("valid to-device event")
+	};
+
+	let events = [event("m.room_key_request"), event("m.typing"), event("m.dummy")];
+	let delivered: Vec<_> = deliverable_to_device(events.into_iter().stream())
+		.map(|event| {
+			event
+				.get_field::<String>("type")
+				.unwrap()
+				.unwrap()
+		})
+		.collect()
+		.await;
+
+	assert_eq!(delivered, ["m.room_key_request", "m.dummy"]);
+}
+
+fn tags(names: &[&str]) -> Tags {
+	names
+		.iter()
+		.map(|name| (TagName::from(*name), TagInfo::new()))
+		.collect()
+}
+
+fn tag_filter(filters: serde_json::Value) -> TagFilter {
+	let body: CanonicalJsonValue =
+		serde_json::from_value(json!({ "lists": { "main": { "filters": filters } } }))
+			.expect("canonical json body");
+
+	TagFilter::from_request(Some(&body), "main")
+}
+
+#[test]
+fn tag_filter_include_only() {
+	let filter = tag_filter(json!({ "tags": ["u.work", "m.favourite"] }));
+
+	assert!(filter.matches(Some(&tags(&["u.work"]))));
+	assert!(filter.matches(Some(&tags(&["m.favourite", "u.other"]))));
+	assert!(!filter.matches(Some(&tags(&["u.other"]))));
+	assert!(!filter.matches(None));
+}
+
+#[test]
+fn tag_filter_exclude_only() {
+	let filter = tag_filter(json!({ "not_tags": ["m.lowpriority"] }));
+
+	assert!(filter.matches(None));
+	assert!(filter.matches(Some(&tags(&["u.work"]))));
+	assert!(!filter.matches(Some(&tags(&["m.lowpriority", "u.work"]))));
+}
+
+#[test]
+fn tag_filter_combined() {
+	let filter = tag_filter(json!({ "tags": ["u.work"], "not_tags": ["m.lowpriority"] }));
+
+	assert!(filter.matches(Some(&tags(&["u.work"]))));
+	assert!(!filter.matches(Some(&tags(&["u.work", "m.lowpriority"]))));
+	assert!(!filter.matches(Some(&tags(&["u.home"]))));
+}
+
+#[test]
+fn tag_filter_absent_matches_everything() {
+	let filter = TagFilter::from_request(None, "main");
+
+	assert!(filter.matches(None));
+	assert!(filter.matches(Some(&tags(&["m.lowpriority"]))));
+}
+
+#[test]
+fn list_excluding_all_rooms_reported_empty() {
+	let filter = tag_filter(json!({ "tags": ["u.work"] }));
+	let rooms = [room_id!("!a:example.org"), room_id!("!b:example.org")];
+	let room_tags: RoomTags<'_> = [(rooms[0], tags(&["u.home"]))].into();
+
+	let active_rooms: Vec<_> = rooms
+		.iter()
+		.filter(|room_id| filter.matches(room_tags.get(*room_id)))
+		.collect();
+
+	let mut response = typing_only_response(None, "1".to_owned(), Default::default());
+	report_list(&mut response.lists, "main", active_rooms.len());
+
+	let list = response
+		.lists
+		.get("main")
+		.expect("empty list still reported");
+
+	assert_eq!(list.count, uint!(0));
+}
+
+#[tokio::test]
+async fn invited_room_without_account_data_is_not_queried() {
+	let checked = AtomicBool::new(false);
+	let has_room_data = async {
+		checked.store(true, Relaxed);
+		false
+	};
+
+	assert!(!room_account_data_wanted(true, false, has_room_data).await);
+	assert!(checked.load(Relaxed), "existence check should be consulted");
+}
+
+#[tokio::test]
+async fn invited_room_account_data_queried_when_present_or_forced() {
+	assert!(room_account_data_wanted(true, false, async { true }).await);
+	assert!(room_account_data_wanted(true, true, async { false }).await);
+}
+
+#[tokio::test]
+async fn joined_room_account_data_skips_existence_check() {
+	let checked = AtomicBool::new(false);
+	let has_room_data = async {
+		checked.store(true, Relaxed);
+		false
+	};
+
+	assert!(room_account_data_wanted(false, false, has_room_data).await);
+	assert!(!checked.load(Relaxed));
+}
+
+fn state_key(event_type: &str, state_key: &str) -> TypeStateKey {
+	(StateEventType::from(event_type), state_key.into())
+}
+
+/// Mirrors the fetch in process_rooms: a full-state walk filtered by the plan
+/// when any wildcard is present, otherwise one lookup per explicit key.
+fn planned_fetch(plan: &RequiredStatePlan, room_state: &[TypeStateKey]) -> Vec<TypeStateKey> {
+	if plan.full_state() {
+		let mut keyed = 0;
+		room_state
+			.iter()
+			.filter(|key| plan.admit(key, &mut keyed))
+			.cloned()
+			.collect()
+	} else {
+		plan.explicit
+			.iter()
+			.filter(|key| room_state.contains(key))
+			.cloned()
+			.collect()
+	}
+}
+
+#[test]
+fn state_key_across_types_fetched() {
+	let alice = "@alice:example.org";
+	let room_state = [
+		state_key("m.room.create", ""),
+		state_key("m.room.member", alice),
+		state_key("m.room.member", "@bob:example.org"),
+		state_key("org.example.status", alice),
+		state_key("org.example.profile", alice),
+	];
+
+	let requested = [state_key("*", alice)].into();
+	let plan = RequiredStatePlan::new(&requested);
+	assert!(plan.full_state());
+
+	let fetched = planned_fetch(&plan, &room_state);
+	assert_eq!(fetched, [room_state[1].clone(), room_state[3].clone(), room_state[4].clone()]);
+}
+
+#[test]
+fn state_key_across_types_bounded() {
+	let alice = "@alice:example.org";
+	let room_state: Vec<_> = (0..MAX_KEYED_REQUIRED_STATE.saturating_add(50))
+		.map(|i| state_key(&format!("org.example.type{i}"), alice))
+		.collect();
+
+	let requested = [state_key("*", alice)].into();
+	let plan = RequiredStatePlan::new(&requested);
+	assert_eq!(planned_fetch(&plan, &room_state).len(), MAX_KEYED_REQUIRED_STATE);
+}
+
+#[test]
+fn state_key_across_types_bound_spares_other_requests() {
+	let alice = "@alice:example.org";
+	let members: Vec<_> = (0..MAX_KEYED_REQUIRED_STATE)
+		.map(|i| state_key("m.room.member", &format!("@user{i}:example.org")))
+		.collect();
+
+	let last_type = format!("org.example.type{}", MAX_KEYED_REQUIRED_STATE.saturating_add(49));
+	let room_state: Vec<_> = (0..MAX_KEYED_REQUIRED_STATE.saturating_add(50))
+		.map(|i| state_key(&format!("org.example.type{i}"), alice))
+		.chain(members.iter().cloned())
+		.chain([state_key("m.room.name", "")])
+		.collect();
+
+	let requested = [
+		state_key("*", alice),
+		state_key("m.room.member", "*"),
+		state_key("m.room.name", ""),
+		state_key(&last_type, alice),
+	]
+	.into();
+
+	let plan = RequiredStatePlan::new(&requested);
+	let fetched = planned_fetch(&plan, &room_state);
+
+	assert!(fetched.contains(&state_key("m.room.name", "")));
+	assert!(fetched.contains(&state_key(&last_type, alice)), "explicit key past the bound");
+	assert!(
+		members
+			.iter()
+			.all(|member| fetched.contains(member))
+	);
+
+	let keyed = fetched
+		.iter()
+		.filter(|(event_type, _)| event_type.to_string().starts_with("org.example."))
+		.count();
+	assert_eq!(keyed, MAX_KEYED_REQUIRED_STATE.saturating_add(1));
+}
+
+#[test]
+fn overlapping_wildcard_and_explicit_state_fetched_once() {
+	let room_state = [
+		state_key("m.room.create", ""),
+		state_key("m.room.name", ""),
+		state_key("m.room.topic", ""),
+		state_key("m.room.member", "@alice:example.org"),
+	];
+
+	let requested = [state_key("*", "*"), state_key("m.room.name", "")].into();
+	let plan = RequiredStatePlan::new(&requested);
+	assert!(plan.full_state());
+	assert!(plan.explicit.is_empty(), "explicit key is covered by the wildcard");
+
+	let fetched = planned_fetch(&plan, &room_state);
+	assert_eq!(fetched, room_state);
+}
+
+#[test]
+fn type_wildcard_keeps_uncovered_explicit_keys() {
+	let room_state = [
+		state_key("m.room.name", ""),
+		state_key("m.room.member", "@alice:example.org"),
+		state_key("m.room.member", "@bob:example.org"),
+		state_key("m.room.topic", ""),
+	];
+
+	let requested = [
+		state_key("m.room.member", "*"),
+		state_key("m.room.member", "@alice:example.org"),
+		state_key("m.room.name", ""),
+	]
+	.into();
+
+	let plan = RequiredStatePlan::new(&requested);
+	assert_eq!(plan.explicit, [state_key("m.room.name", "")].into());
+
+	let fetched = planned_fetch(&plan, &room_state);
+	assert_eq!(fetched, room_state[..3]);
+}
+
+#[test]
+fn explicit_only_state_is_looked_up_individually() {
+	let requested = [state_key("m.room.name", ""), state_key("m.room.topic", "")].into();
+	let plan = RequiredStatePlan::new(&requested);
+
+	assert!(!plan.full_state());
+	assert_eq!(plan.explicit, requested);
+}
+
+#[test]
+fn incremental_sync_keeps_initial_list_order() {
+	let a = room_id!("!a:example.org");
+	let b = room_id!("!b:example.org");
+	let c = room_id!("!c:example.org");
+	let room_tags = RoomTags::new();
+
+	let initial = sort_rooms(vec![c, a, b], &room_tags, &BumpStamps::new(), &[]);
+	let sent: Vec<OwnedRoomId> = initial
+		.iter()
+		.copied()
+		.map(ToOwned::to_owned)
+		.collect();
+
+	// rooms come back from the database in a different order next time
+	let incremental = sort_rooms(vec![b, c, a], &room_tags, &BumpStamps::new(), &sent);
+	assert_eq!(incremental, initial);
+}
+
+#[test]
+fn list_order_moves_when_sort_key_changes() {
+	let a = room_id!("!a:example.org");
+	let b = room_id!("!b:example.org");
+	let previous = [a.to_owned(), b.to_owned()];
+
+	let mut favourite = Tags::new();
+	favourite.insert(TagName::Favorite, TagInfo::new());
+	let room_tags: RoomTags<'_> = [(b, favourite)].into();
+
+	assert_eq!(sort_rooms(vec![a, b], &room_tags, &BumpStamps::new(), &previous), [b, a]);
+}
+
+#[test]
+fn rooms_new_to_list_follow_previously_sent_rooms() {
+	let a = room_id!("!a:example.org");
+	let b = room_id!("!b:example.org");
+	let z = room_id!("!z:example.org");
+	let previous = [z.to_owned()];
+
+	assert_eq!(sort_rooms(vec![b, a, z], &RoomTags::new(), &BumpStamps::new(), &previous), [
+		z, a, b
+	]);
+}
+
+#[test]
+fn to_device_kept_until_pos_advances_past_delivery() {
+	let message_count = 105;
+
+	// initial sync on the connection delivers the message
+	let first_batch = 110;
+	assert_eq!(to_device_acked(None, 0), None);
+
+	// a retry with the old pos acks nothing newer than the client has seen
+	let acked = to_device_acked(Some(first_batch), 100).expect("earlier events acked");
+	assert!(message_count > acked, "message delivered in the current batch was deleted");
+
+	// the next sync with an advanced pos acks the delivered message
+	let acked = to_device_acked(Some(first_batch), first_batch).expect("delivery acked");
+	assert!(message_count <= acked);
+}
+
+#[test]
+fn to_device_not_acked_without_prior_delivery() {
+	// pos advanced while the extension was off; nothing was delivered yet
+	assert_eq!(to_device_acked(None, 500), None);
+}
+
+#[test]
+fn room_subscriptions_over_cap_are_truncated() {
+	let subscriptions: BTreeMap<OwnedRoomId, ()> = (0..20)
+		.map(|i| {
+			let room_id = format!("!room{i:02}:example.org")
+				.try_into()
+				.expect("valid room id");
+
+			(room_id, ())
+		})
+		.collect();
+
+	let processed: Vec<_> = capped_subscriptions(&subscriptions, 5)
+		.map(|(room_id, ())| room_id.as_str())
+		.collect();
+
+	assert_eq!(processed, [
+		"!room00:example.org",
+		"!room01:example.org",
+		"!room02:example.org",
+		"!room03:example.org",
+		"!room04:example.org",
+	]);
+
+	assert_eq!(capped_subscriptions(&subscriptions, 100).count(), subscriptions.len());
+}
+
+#[test]
+fn departed_typer_excluded_from_typing_list() {
+	let room_id = room_id!("!a:example.org").to_owned();
+	let other_room = room_id!("!b:example.org").to_owned();
+	let alice = user_id!("@alice:example.org").to_owned();
+	let bob = user_id!("@bob:example.org").to_owned();
+
+	let typing: BTreeMap<OwnedRoomId, Vec<OwnedUserId>> = [
+		(room_id.clone(), vec![alice.clone(), bob.clone()]),
+		(other_room.clone(), vec![bob.clone()]),
+	]
+	.into();
+
+	// bob left the first room but is still joined to the other
+	let typing = without_departed(typing, &[(room_id.clone(), bob.clone())]);
+
+	assert_eq!(typing[&room_id], [alice]);
+	assert_eq!(typing[&other_room], [bob]);
+}
+
+#[test]
+fn stopped_typing_sent_as_empty() {
+	let room_id = room_id!("!a:example.org").to_owned();
+	let alice = user_id!("@alice:example.org").to_owned();
+	let content = |typing: &Typing| {
+		typing.rooms[&room_id]
+			.deserialize()
+			.expect("typing event")
+			.content
+			.user_ids
+	};
+
+	let started = typing_response([(room_id.clone(), vec![alice.clone()])].into())
+		.expect("typing response");
+	assert_eq!(content(&started), [alice]);
+
+	// the room changed again when alice stopped, so it is sent with no typers
+	let stopped =
+		typing_response([(room_id.clone(), Vec::new())].into()).expect("typing response");
+	assert!(!stopped.is_empty());
+	assert!(content(&stopped).is_empty());
+}
+
+fn list_rooms(lists: &[(&str, &[&str])]) -> ListRooms {
+	lists
+		.iter()
+		.map(|(list_id, rooms)| {
+			let rooms = rooms
+				.iter()
+				.map(|room_id| OwnedRoomId::try_from(*room_id).unwrap())
+				.collect();
+
+			((*list_id).to_owned(), rooms)
+		})
+		.collect()
+}
+
+#[test]
+fn receipts_unscoped_cover_every_list() {
+	let lists = list_rooms(&[("all", &["!a:example.org"]), ("dms", &["!b:example.org"])]);
+	let joined = [
+		room_id!("!a:example.org"),
+		room_id!("!b:example.org"),
+		room_id!("!c:example.org"),
+	];
+
+	let rooms = extension_rooms(None, None, &lists, [].into_iter(), joined.into_iter());
+
+	assert_eq!(rooms, [
+		room_id!("!a:example.org").to_owned(),
+		room_id!("!b:example.org").to_owned()
+	]);
+}
+
+#[test]
+fn receipts_scoped_to_named_lists_and_rooms() {
+	let lists = list_rooms(&[("all", &["!a:example.org"]), ("dms", &["!b:example.org"])]);
+	let joined = [
+		room_id!("!a:example.org"),
+		room_id!("!b:example.org"),
+		room_id!("!c:example.org"),
+	];
+	let requested = [ExtensionRoomConfig::Room(room_id!("!c:example.org").to_owned())];
+
+	let rooms = extension_rooms(
+		Some(&requested),
+		Some(&["dms".to_owned()]),
+		&lists,
+		[].into_iter(),
+		joined.into_iter(),
+	);
+
+	assert_eq!(rooms, [
+		room_id!("!b:example.org").to_owned(),
+		room_id!("!c:example.org").to_owned()
+	]);
+}
+
+#[test]
+fn receipts_for_subscribed_rooms_stay_within_joined() {
+	let lists = ListRooms::new();
+	let joined = [room_id!("!a:example.org"), room_id!("!b:example.org")];
+	let subscribed = [room_id!("!b:example.org"), room_id!("!left:example.org")];
+
+	let rooms = extension_rooms(
+		Some(&[ExtensionRoomConfig::AllSubscribed]),
+		None,
+		&lists,
+		subscribed.into_iter(),
+		joined.into_iter(),
+	);
+
+	assert_eq!(rooms, [room_id!("!b:example.org").to_owned()]);
+}
+
+#[test]
+fn unscoped_extension_covers_subscriptions_and_lists() {
+	let lists = list_rooms(&[("all", &["!a:example.org"])]);
+	let joined = [
+		room_id!("!a:example.org"),
+		room_id!("!b:example.org"),
+		room_id!("!c:example.org"),
+	];
+	let subscribed = [room_id!("!c:example.org")];
+
+	let rooms = extension_rooms(None, None, &lists, subscribed.into_iter(), joined.into_iter());
+
+	assert_eq!(rooms, [
+		room_id!("!a:example.org").to_owned(),
+		room_id!("!c:example.org").to_owned()
+	]);
+}
+
+#[test]
+fn named_lists_leave_out_subscriptions() {
+	let lists = list_rooms(&[("all", &["!a:example.org"]), ("dms", &["!b:example.org"])]);
+	let joined = [
+		room_id!("!a:example.org"),
+		room_id!("!b:example.org"),
+		room_id!("!c:example.org"),
+	];
+	let subscribed = [room_id!("!c:example.org")];
+
+	let rooms = extension_rooms(
+		None,
+		Some(&["all".to_owned()]),
+		&lists,
+		subscribed.into_iter(),
+		joined.into_iter(),
+	);
+
+	assert_eq!(rooms, [room_id!("!a:example.org").to_owned()]);
+}
+
+#[test]
+fn typing_since_prefers_the_rooms_own_since() {
+	let sent = room_id!("!sent:example.org");
+	let todo_rooms: TodoRooms = [(sent.to_owned(), (BTreeSet::new(), 10, 7, false))].into();
+
+	assert_eq!(typing_room_since(&todo_rooms, sent, 20), 7);
+	assert_eq!(typing_room_since(&todo_rooms, room_id!("!other:example.org"), 20), 20);
+}
+
+#[test]
+fn unsubscribe_rooms_read_from_body() {
+	let body: CanonicalJsonValue = serde_json::from_value(json!({
+		"unsubscribe_rooms": ["!a:example.org", "not a room", 7]
+	}))
+	.expect("canonical json body");
+
+	assert_eq!(unsubscribe_rooms(Some(&body)), [room_id!("!a:example.org").to_owned()]);
+	assert!(unsubscribe_rooms(None).is_empty());
+}
+
+#[test]
+fn capped_response_hint_set_when_truncated() {
+	assert!(response_capped(600, 512, [], 100));
+	assert!(response_capped(1, 512, [uint!(20), uint!(500)], 100));
+}
+
+#[test]
+fn capped_response_hint_follows_configured_limit() {
+	assert!(!response_capped(1, 512, [uint!(500)], 1000));
+	assert!(response_capped(1, 512, [uint!(20)], 10));
+}
+
+#[test]
+fn timeline_limit_clamped_to_configured_max() {
+	assert_eq!(timeline_limit(uint!(20), 100), 20);
+	assert_eq!(timeline_limit(uint!(5000), 100), 100);
+	assert_eq!(timeline_limit(uint!(500), 1000), 500);
+}
+
+#[test]
+fn capped_response_hint_unset_within_caps() {
+	assert!(!response_capped(512, 512, [uint!(20), uint!(100)], 100));
+	assert!(!response_capped(0, 512, [], 100));
+}
+
+#[test]
+fn capped_response_hint_sent_only_when_capped() {
+	let body = |capped| {
+		let mut unstable = Unstable::default();
+		report_capped(&mut unstable, capped);
+		response_body(unstable, Vec::new())
+	};
+
+	assert_eq!(body(response_capped(600, 512, [], 100))[RESPONSE_CAPPED_FIELD], true);
+	assert!(
+		body(response_capped(1, 512, [uint!(20)], 100))
+			.get(RESPONSE_CAPPED_FIELD)
+			.is_none()
+	);
+}
+
+fn dm_event(sender: &str, kind: &str, ts: u64) -> PduEvent {
+	serde_json::from_value(json!({
+		"event_id": format!("${ts}:example.org"),
+		"room_id": "!dm:example.org",
+		"sender": sender,
+		"origin_server_ts": ts,
+		"type": kind,
+		"content": {},
+		"prev_events": [],
+		"auth_events": [],
+		"depth": 1,
+		"hashes": { "sha256": "aGFzaA" },
+	}))
+	.expect("valid pdu")
+}
+
+#[test]
+fn dm_bump_stamp_follows_other_party_message() {
+	let timeline = [
+		dm_event("@alice:example.org", "m.room.message", 1_000),
+		dm_event("@bob:example.org", "m.room.message", 2_000),
+		// reactions don't bump the room
+		dm_event("@alice:example.org", "m.reaction", 3_000),
+	];
+
+	assert_eq!(bump_stamp(&timeline), Some(uint!(2_000)));
+}
+
+#[test]
+fn bump_stamp_absent_without_bump_events() {
+	let timeline = [dm_event("@bob:example.org", "m.reaction", 3_000)];
+
+	assert_eq!(bump_stamp(&timeline), None);
+	assert_eq!(bump_stamp([]), None);
+}
+
+#[test]
+fn upgraded_room_create_keeps_predecessor_in_required_state() {
+	let create: PduEvent = serde_json::from_value(json!({
+		"event_id": "$create:example.org",
+		"room_id": "!new:example.org",
+		"sender": "@alice:example.org",
+		"origin_server_ts": 1,
+		"type": "m.room.create",
+		"state_key": "",
+		"content": {
+			"room_version": "11",
+			"predecessor": { "room_id": "!old:example.org" },
+		},
+		"prev_events": [],
+		"auth_events": [],
+		"depth": 1,
+		"hashes": { "sha256": "aGFzaA" },
+	}))
+	.expect("valid create pdu");
+
+	// required_state events are formatted the same way in process_rooms
+	let event: Raw<AnySyncStateEvent> = create.into_format();
+	let content: serde_json::Value = event
+		.get_field("content")
+		.expect("content deserializes")
+		.expect("content present");
+
+	assert_eq!(content["predecessor"]["room_id"], "!old:example.org");
+}
+
+#[test]
+fn list_opting_out_of_heroes_skips_only_its_own_rooms() {
+	let body: CanonicalJsonValue = serde_json::from_value(json!({
+		"lists": {
+			"named": { "include_heroes": false },
+			"all": { "ranges": [[0, 10]] },
+		}
+	}))
+	.expect("canonical json body");
+
+	let named = include_heroes(Some(&body), "named");
+	let all = include_heroes(Some(&body), "all");
+	assert!(!named);
+	assert!(all);
+	assert!(include_heroes(None, "named"));
+
+	// a room only in the opted-out list gets no heroes; one also in a
+	// hero-wanting list still does
+	let only_named = room_id!("!a:example.org");
+	let in_both = room_id!("!b:example.org");
+	let mut todo_rooms = TodoRooms::new();
+	add_todo_room(&mut todo_rooms, only_named, [].into_iter(), 10, 0, named);
+	add_todo_room(&mut todo_rooms, in_both, [].into_iter(), 10, 0, named);
+	add_todo_room(&mut todo_rooms, in_both, [].into_iter(), 10, 0, all);
+
+	assert!(!todo_rooms[only_named].3);
+	assert!(todo_rooms[in_both].3);
+}
+
+#[test]
+fn overlong_txn_id_rejected() {
+	assert!(!txn_id_valid(Some(&"x".repeat(MAX_TXN_ID_LEN.saturating_add(1)))));
+	assert!(txn_id_valid(Some(&"x".repeat(MAX_TXN_ID_LEN))));
+	assert!(txn_id_valid(Some("abc")));
+	assert!(txn_id_valid(None));
+}
+
+#[test]
+fn missing_conn_id_keeps_one_connection_per_device() {
+	let alice = user_id!("@alice:example.org");
+	let device = device_id!("PHONE");
+
+	// consecutive requests without conn_id resolve to the same cached connection
+	let first = into_snake_key(alice, device, effective_conn_id(None));
+	let second = into_snake_key(alice, device, effective_conn_id(None));
+	assert_eq!(first, second);
+
+	// it is a real connection, so known rooms are kept for it
+	assert_eq!(first.2.as_deref(), Some(DEFAULT_CONN_ID));
+
+	// other devices have their own
+	let other = into_snake_key(alice, device_id!("LAPTOP"), effective_conn_id(None));
+	assert_ne!(first, other);
+}
+
+#[test]
+fn explicit_conn_id_kept() {
+	assert_eq!(effective_conn_id(Some("main")), "main");
+}
+
+#[test]
+fn invite_accepted_mid_request_gets_timeline() {
+	// snapshot taken while invited, joined by the time the room is processed
+	assert!(!invite_pending(true, true));
+
+	// still invited: stripped invite_state instead of a timeline
+	assert!(invite_pending(true, false));
+
+	// never in the invited snapshot
+	assert!(!invite_pending(false, false));
+}
+
+#[test]
+fn typing_only_mode_returns_only_typing() {
+	let body: CanonicalJsonValue =
+		serde_json::from_value(json!({ "org.matrix.tuwunel.typing_only": true }))
+			.expect("canonical json body");
+
+	assert!(typing_only_requested(Some(&body)));
+	assert!(!typing_only_requested(None));
+
+	let mut typing = Typing::default();
+	typing.rooms.insert(
+		room_id!("!a:example.org").to_owned(),
+		Raw::new(&SyncTypingEvent {
+			content: TypingEventContent::new(vec![user_id!("@alice:example.org").to_owned()]),
+		})
+		.expect("typing event serializes"),
+	);
+
+	let response = typing_only_response(None, "10".to_owned(), typing);
+
+	assert!(response.rooms.is_empty());
+	assert!(response.lists.is_empty());
+	assert_eq!(response.extensions.typing.rooms.len(), 1);
+	assert!(response.extensions.account_data.rooms.is_empty());
+	assert!(response.extensions.receipts.rooms.is_empty());
+	assert!(response.extensions.to_device.is_none());
+}
+
+#[test]
+fn oversized_invite_state_truncated_to_limit() {
+	let mut state: Vec<u32> = (0..200).collect();
+
+	assert_eq!(truncate_invite_state(&mut state, 50), Some(150));
+	assert_eq!(state.len(), 50);
+	assert_eq!(state.last(), Some(&49));
+
+	assert_eq!(truncate_invite_state(&mut state, 50), None);
+	assert_eq!(truncate_invite_state(&mut state, 100), None);
+	assert_eq!(state.len(), 50);
+}
+
+#[test]
+fn legacy_bare_pos_accepted() {
+	assert_eq!(parse_pos(None), Some(0));
+	assert_eq!(parse_pos(Some("0")), Some(0));
+	assert_eq!(parse_pos(Some("12345")), Some(12345));
+}
+
+#[test]
+fn versioned_pos_accepted() {
+	assert_eq!(parse_pos(Some("v1:12345")), Some(12345));
+	assert_eq!(parse_pos(Some("v1:")), None);
+	assert_eq!(parse_pos(Some("v1:12:34")), None);
+}
+
+#[test]
+fn unknown_or_malformed_pos_rejected() {
+	assert_eq!(parse_pos(Some("v2:12345")), None);
+	assert_eq!(parse_pos(Some(":12345")), None);
+	assert_eq!(parse_pos(Some("+12345")), None);
+	assert_eq!(parse_pos(Some("-1")), None);
+	assert_eq!(parse_pos(Some("99999999999999999999999")), None);
+	assert_eq!(parse_pos(Some("")), None);
+	assert_eq!(parse_pos(Some("abc")), None);
+}
+
+#[test]
+fn sender_device_changes_changed_once_never_left() {
+	let sender = user_id!("@alice:example.org");
+	let other = user_id!("@bob:example.org");
+
+	// keys_changed and a room's room_keys_changed both report the sender
+	let changed: HashSet<OwnedUserId> = [sender, other, sender]
+		.into_iter()
+		.map(ToOwned::to_owned)
+		.collect();
+
+	let left: HashSet<OwnedUserId> = [sender.to_owned()].into();
+	let lists = device_lists(sender, changed, left);
+
+	let sender_count = lists
+		.changed
+		.iter()
+		.filter(|user_id| *user_id == sender)
+		.count();
+
+	assert_eq!(sender_count, 1);
+	assert_eq!(lists.changed.len(), 2);
+	assert!(lists.left.is_empty());
+}
+
+#[test]
+fn others_kept_in_left() {
+	let sender = user_id!("@alice:example.org");
+	let other = user_id!("@bob:example.org");
+
+	let left: HashSet<OwnedUserId> = [other.to_owned()].into();
+	let lists = device_lists(sender, HashSet::new(), left);
+
+	assert!(lists.changed.is_empty());
+	assert_eq!(lists.left, vec![other.to_owned()]);
+}
+
+#[test]
+fn global_account_data_type_edited_twice_delivered_once() {
+	let event = |event_type: &str, value: u32| -> Raw<AnyGlobalAccountDataEvent> {
+		serde_json::from_value(json!({ "type": event_type, "content": { "value": value } }))
+			.expect("valid account data")
+	};
+
+	let changes = vec![
+		event("org.example.edited", 1),
+		event("org.example.other", 2),
+		event("org.example.edited", 3),
+	];
+
+	let delivered: Vec<_> = latest_by_type(changes)
+		.iter()
+		.map(|event| {
+			let event_type: String = event
+				.get_field("type")
+				.expect("valid event")
+				.expect("has type");
+			let content: serde_json::Value = event
+				.get_field("content")
+				.expect("valid event")
+				.expect("has content");
+
+			(event_type, content["value"].clone())
+		})
+		.collect();
+
+	assert_eq!(delivered, [
+		("org.example.other".to_owned(), json!(2)),
+		("org.example.edited".to_owned(), json!(3)),
+	]);
+}
+
+#[test]
+fn notification_breakdown_sums_to_notification_count() {
+	let counts = NotificationCounts::new(7, 4, 2);
+
+	assert_eq!(counts, NotificationCounts { message: 4, other: 3, highlight: 2 });
+	assert_eq!(counts.message.saturating_add(counts.other), 7);
+
+	let none = NotificationCounts::new(0, 0, 0);
+	assert_eq!(none, NotificationCounts::default());
+
+	// counts never tracked as notifications can't exceed the total
+	let counts = NotificationCounts::new(1, 3, 3);
+	assert_eq!(counts, NotificationCounts { message: 1, other: 0, highlight: 1 });
+	assert_eq!(counts.message.saturating_add(counts.other), 1);
+}
+
+#[test]
+fn notification_breakdown_sent_on_the_room() {
+	let room_id = room_id!("!busy:example.org");
+	let mut unstable = Unstable::default();
+	unstable.set_room(
+		room_id,
+		NOTIFICATION_COUNTS_FIELD,
+		json!(NotificationCounts::new(7, 4, 2)),
+	);
+
+	let body = response_body(unstable, vec![(room_id, Room::default())]);
+
+	assert_eq!(
+		body["rooms"][room_id.as_str()][NOTIFICATION_COUNTS_FIELD],
+		json!({ "message_count": 4, "other_count": 3, "highlight_count": 2 })
+	);
+}
+
+#[test]
+fn list_with_empty_ranges_expands_no_rooms() {
+	assert!(list_ranges(&[], 3).is_empty());
+	assert!(list_ranges(&[(uint!(0), uint!(0))], 3).is_empty());
+	assert!(list_ranges(&[(uint!(0), uint!(5))], 0).is_empty());
+}
+
+#[test]
+fn list_window_ends_at_the_furthest_range() {
+	let rooms = ["a", "b", "c", "d"];
+
+	assert_eq!(list_window(&rooms, &[(uint!(0), uint!(1)), (uint!(0), uint!(2))]), ["a", "b"]);
+	assert_eq!(list_window(&rooms, &[(uint!(0), uint!(9))]), rooms);
+	assert!(list_window(&rooms, &[]).is_empty());
+}
+
+#[test]
+fn list_ranges_clamped_to_list_length() {
+	let ranges = [(uint!(0), uint!(2)), (uint!(0), uint!(0)), (uint!(1), uint!(10))];
+
+	assert_eq!(list_ranges(&ranges, 3), [0..2, 0..3]);
+}
+
+#[test]
+fn is_dm_list_filter_selects_direct_rooms() {
+	let content: DirectEventContent = serde_json::from_value(json!({
+		"@bob:example.org": ["!dm:example.org"],
+		"@carol:example.org": ["!dm:example.org", "!group_dm:example.org"],
+	}))
+	.expect("valid m.direct content");
+
+	let dm_rooms = direct_rooms(content);
+	let rooms = [
+		room_id!("!dm:example.org"),
+		room_id!("!group_dm:example.org"),
+		room_id!("!public:example.org"),
+	];
+
+	let filtered = |is_dm: Option<bool>| {
+		rooms
+			.iter()
+			.filter(|room_id| dm_filter_matches(is_dm, &dm_rooms, room_id))
+			.map(ToString::to_string)
+			.collect::<Vec<_>>()
+	};
+
+	assert_eq!(filtered(Some(true)), ["!dm:example.org", "!group_dm:example.org"]);
+	assert_eq!(filtered(Some(false)), ["!public:example.org"]);
+	assert_eq!(filtered(None).len(), rooms.len());
+}
+
+#[test]
+fn rooms_without_m_direct_are_not_dms() {
+	// an absent `m.direct` reads as empty, so every room reports `is_dm: false`
+	let content: DirectEventContent =
+		serde_json::from_value(json!({})).expect("valid m.direct content");
+
+	let dm_rooms = direct_rooms(content);
+	let room_id = room_id!("!dm:example.org");
+
+	assert!(!dm_rooms.contains(room_id));
+	assert!(dm_filter_matches(Some(false), &dm_rooms, room_id));
+	assert!(!dm_filter_matches(Some(true), &dm_rooms, room_id));
+}
+
+#[test]
+fn is_dm_list_filter_read_from_request() {
+	let body: CanonicalJsonValue = serde_json::from_value(json!({
+		"lists": {
+			"dms": { "filters": { "is_dm": true } },
+			"rooms": { "filters": { "is_dm": false } },
+			"all": { "filters": { "is_dm": "yes" } },
+		}
+	}))
+	.expect("canonical json body");
+
+	assert_eq!(list_is_dm(Some(&body), "dms"), Some(true));
+	assert_eq!(list_is_dm(Some(&body), "rooms"), Some(false));
+	assert_eq!(list_is_dm(Some(&body), "all"), None);
+	assert_eq!(list_is_dm(Some(&body), "missing"), None);
+	assert_eq!(list_is_dm(None, "dms"), None);
+}
+
+fn typed_rooms() -> [(&'static str, RoomTypeFilter); 3] {
+	[
+		("!space", RoomTypeFilter::from(Some(RoomType::Space))),
+		("!room", RoomTypeFilter::Default),
+		("!custom", RoomTypeFilter::from(Some(RoomType::from("org.example.custom")))),
+	]
+}
+
+fn list_rooms_of_types(
+	room_types: &[RoomTypeFilter],
+	not_room_types: &[RoomTypeFilter],
+) -> Vec<&'static str> {
+	typed_rooms()
+		.into_iter()
+		.filter(|(_, room_type)| room_type_matches(room_types, false, room_type))
+		.filter(|(_, room_type)| room_type_matches(not_room_types, true, room_type))
+		.map(|(room_id, _)| room_id)
+		.collect()
+}
+
+#[test]
+fn space_only_list_keeps_spaces() {
+	let spaces = [RoomTypeFilter::from(Some(RoomType::Space))];
+
+	assert_eq!(list_rooms_of_types(&spaces, &[]), ["!space"]);
+}
+
+#[test]
+fn non_space_list_drops_spaces() {
+	let spaces = [RoomTypeFilter::from(Some(RoomType::Space))];
+
+	assert_eq!(list_rooms_of_types(&[], &spaces), ["!room", "!custom"]);
+	assert_eq!(list_rooms_of_types(&[], &[]).len(), typed_rooms().len());
+}
+
+#[test]
+fn included_room_types_less_excluded() {
+	let included = [RoomTypeFilter::from(Some(RoomType::Space)), RoomTypeFilter::Default];
+	let excluded = [RoomTypeFilter::from(Some(RoomType::Space))];
+
+	assert_eq!(list_rooms_of_types(&included, &excluded), ["!room"]);
+}
+
+#[test]
+fn room_types_list_filter_read_from_request() {
+	let body: CanonicalJsonValue = serde_json::from_value(json!({
+		"lists": {
+			"spaces": { "filters": { "room_types": ["m.space", null, 7] } },
+			"all": { "filters": { "room_types": "m.space" } },
+		}
+	}))
+	.expect("canonical json body");
+
+	assert_eq!(list_room_types(Some(&body), "spaces"), [
+		RoomTypeFilter::from(Some(RoomType::Space)),
+		RoomTypeFilter::Default
+	]);
+	assert!(list_room_types(Some(&body), "all").is_empty());
+	assert!(list_room_types(Some(&body), "missing").is_empty());
+}
+
+#[test]
+fn initial_prev_batch_suppressed_unless_limited() {
+	let token = || Some("42".to_owned());
+
+	// unlimited initial sync omits prev_batch under the flag
+	assert_eq!(initial_prev_batch(token(), true, false, true), None);
+
+	// a limited initial sync keeps it
+	assert_eq!(initial_prev_batch(token(), true, true, true), token());
+
+	// incremental syncs and the flag being off are unaffected
+	assert_eq!(initial_prev_batch(token(), false, false, true), token());
+	assert_eq!(initial_prev_batch(token(), true, false, false), token());
+}
+
+#[test]
+fn ignored_users_public_receipts_filtered() {
+	let event_id = event_id!("$read:example.org");
+	let content: IgnoredUserListEventContent = serde_json::from_value(json!({
+		"ignored_users": { "@spammer:example.org": {} },
+	}))
+	.expect("valid m.ignored_user_list content");
+
+	let ignored = ignored_user_ids(content);
+	let read = |user_id: &str| -> Raw<AnySyncEphemeralRoomEvent> {
+		serde_json::from_value(json!({
+			"type": "m.receipt",
+			"content": {
+				event_id.as_str(): { "m.read": { user_id: { "ts": 1 } } },
+			},
+		}))
+		.expect("valid receipt event")
+	};
+
+	let receipts = [
+		(user_id!("@bob:example.org"), read("@bob:example.org")),
+		(user_id!("@spammer:example.org"), read("@spammer:example.org")),
+	];
+
+	let visible = receipts
+		.into_iter()
+		.filter_map(|(read_user, receipt)| {
+			receipt_visible(&ignored, read_user).then_some(receipt)
+		});
+
+	let packed = pack_receipts(visible);
+	let packed: serde_json::Value =
+		serde_json::from_str(packed.json().get()).expect("packed receipts");
+	let readers = &packed["content"][event_id.as_str()]["m.read"];
+
+	assert!(readers["@bob:example.org"].is_object());
+	assert!(readers["@spammer:example.org"].is_null());
+}
+
+#[test]
+fn room_in_two_memberships_counted_once_as_strongest() {
+	let joined = room_id!("!joined:example.org").to_owned();
+	let invited = room_id!("!invited:example.org").to_owned();
+	let knocked = room_id!("!knocked:example.org").to_owned();
+
+	let [all_joined, all_invited, all_knocked] = strongest_memberships([
+		vec![joined.clone()],
+		vec![invited.clone(), joined.clone()],
+		vec![knocked.clone(), invited.clone(), knocked.clone()],
+	]);
+
+	assert_eq!(all_joined, [joined]);
+	assert_eq!(all_invited, [invited]);
+	assert_eq!(all_knocked, [knocked]);
+
+	let all_rooms = all_joined
+		.iter()
+		.chain(&all_invited)
+		.chain(&all_knocked)
+		.count();
+
+	assert_eq!(all_rooms, 3);
+}
+
+#[test]
+fn knocked_room_returns_name_and_join_rules() {
+	let knock_state: Vec<Raw<AnyStrippedStateEvent>> = serde_json::from_value(json!([
+		{
+			"type": "m.room.join_rules",
+			"state_key": "",
+			"sender": "@admin:example.org",
+			"content": { "join_rule": "knock" },
+		},
+		{
+			"type": "m.room.name",
+			"state_key": "",
+			"sender": "@admin:example.org",
+			"content": { "name": "Knock Knock" },
+		},
+	]))
+	.expect("valid stripped state");
+
+	assert_eq!(stripped_room_name(&knock_state).as_deref(), Some("Knock Knock"));
+
+	let mut delivered = knock_state;
+	assert_eq!(truncate_invite_state(&mut delivered, 50), None);
+	assert!(
+		delivered
+			.iter()
+			.filter_map(|event| event.get_field::<String>("type").ok().flatten())
+			.any(|event_type| event_type == "m.room.join_rules")
+	);
+
+	// without a name event the name is left to heroes
+	assert_eq!(stripped_room_name(&delivered[..1]), None);
+}
+
+#[tokio::test]
+async fn closed_watcher_ends_long_poll() {
+	let watcher = future::ready(Err!("watcher channel closed"));
+	let poll = long_poll(Duration::from_secs(3600), watcher, future::pending());
+
+	assert!(
+		tokio::time::timeout(Duration::from_secs(5), poll)
+			.await
+			.is_ok()
+	);
+}
+
+#[tokio::test]
+async fn idle_long_poll_waits_for_duration() {
+	let duration = Duration::from_millis(50);
+	let started = Instant::now();
+	long_poll(duration, future::pending(), future::pending()).await;
+
+	assert!(started.elapsed() >= duration);
+}
@@ -0,0 +1,136 @@
+//! Unstable fields carried in the v5 sync response alongside the ones ruma's
+//! response type knows about.
+
+use std::collections::BTreeMap;
+
+use bytes::BufMut;
+use ruma::{
+	OwnedRoomId, OwnedUserId, RoomId, UserId,
+	api::{OutgoingResponse, client::sync::sync_events::v5, error::IntoHttpError},
+};
+use serde_json::{Map, Value as JsonValue};
+
+/// A v5 sync response with its unstable fields.
+pub(crate) struct SyncResponse {
+	pub(super) response: v5::Response,
+	pub(super) unstable: Unstable,
+}
+
+/// Unstable fields merged into the serialized response: top-level ones, ones
+/// on a room, and ones on one of a room's heroes.
+#[derive(Debug, Default)]
+pub(super) struct Unstable {
+	fields: Map<String, JsonValue>,
+	rooms: BTreeMap<OwnedRoomId, Map<String, JsonValue>>,
+	heroes: BTreeMap<OwnedRoomId, BTreeMap<OwnedUserId, Map<String, JsonValue>>>,
+}
+
+impl From<v5::Response> for SyncResponse {
+	fn from(response: v5::Response) -> Self { Self { response, unstable: Unstable::default() } }
+}
+
+impl OutgoingResponse for SyncResponse {
+	fn try_into_http_response<T: Default + BufMut>(
+		self,
+	) -> Result<http::Response<T>, IntoHttpError> {
+		if self.unstable.is_empty() {
+			return self.response.try_into_http_response();
+		}
+
+		let (parts, body) = self
+			.response
+			.try_into_http_response::<Vec<u8>>()?
+			.into_parts();
+
+		let mut body: JsonValue = serde_json::from_slice(&body)?;
+		self.unstable.merge_into(&mut body);
+
+		let mut writer = T::default().writer();
+		serde_json::to_writer(&mut writer, &body)?;
+
+		Ok(http::Response::from_parts(parts, writer.into_inner()))
+	}
+}
+
+impl Unstable {
+	pub(super) fn is_empty(&self) -> bool {
+		self.fields.is_empty() && self.rooms.is_empty() && self.heroes.is_empty()
+	}
+
+	pub(super) fn set(&mut self, name: &str, value: JsonValue) {
+		self.fields.insert(name.to_owned(), value);
+	}
+
+	pub(super) fn set_room(&mut self, room_id: &RoomId, name: &str, value: JsonValue) {
+		self.rooms
+			.entry(room_id.to_owned())
+			.or_default()
+			.insert(name.to_owned(), value);
+	}
+
+	pub(super) fn set_hero(
+		&mut self,
+		room_id: &RoomId,
+		user_id: &UserId,
+		name: &str,
+		value: JsonValue,
+	) {
+		self.heroes
+			.entry(room_id.to_owned())
+			.or_default()
+			.entry(user_id.to_owned())
+			.or_default()
+			.insert(name.to_owned(), value);
+	}
+
+	/// Adds the fields to a serialized response. Fields for rooms or heroes
+	/// missing from the response are dropped.
+	pub(super) fn merge_into(self, body: &mut JsonValue) {
+		let Some(body) = body.as_object_mut() else {
+			return;
+		};
+
+		body.extend(self.fields);
+
+		let Some(rooms) = body
+			.get_mut("rooms")
+			.and_then(JsonValue::as_object_mut)
+		else {
+			return;
+		};
+
+		for (room_id, fields) in self.rooms {
+			if let Some(room) = rooms
+				.get_mut(room_id.as_str())
+				.and_then(JsonValue::as_object_mut)
+			{
+				room.extend(fields);
+			}
+		}
+
+		for (room_id, mut heroes) in self.heroes {
+			let Some(room_heroes) = rooms
+				.get_mut(room_id.as_str())
+				.and_then(|room| room.get_mut("heroes"))
+				.and_then(JsonValue::as_array_mut)
+			else {
+				continue;
+			};
+
+			for hero in room_heroes
+				.iter_mut()
+				.filter_map(JsonValue::as_object_mut)
+			{
+				let fields = hero
+					.get("user_id")
+					.and_then(JsonValue::as_str)
+					.and_then(|user_id| <&UserId>::try_from(user_id).ok())
+					.and_then(|user_id| heroes.remove(user_id));
+
+				if let Some(fields) = fields {
+					hero.extend(fields);
+				}
+			}
+		}
+	}
+}
@@ -51,9 +51,7 @@ pub(crate) async fn create_typing_event_route(
 				.typing_add(
 					sender_user,
 					&body.room_id,
-					utils::millis_since_unix_epoch()
-						.checked_add(duration)
-						.expect("user typing timeout should not get this high"),
+					utils::millis_since_unix_epoch().saturating_add(duration),
 				)
 				.await?;
 		},
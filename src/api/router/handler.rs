@@ -8,7 +8,7 @@
 };
 use futures::{Future, TryFutureExt};
 use http::Method;
-use ruma::api::IncomingRequest;
+use ruma::api::{IncomingRequest, OutgoingResponse};
 use tuwunel_core::Result;
 
 use super::{Ruma, RumaResponse, State};
@@ -36,13 +36,13 @@ fn ruma_route<H, T>(self, handler: &'static H) -> Self
 macro_rules! ruma_handler {
 	( $($tx:ident),* $(,)? ) => {
 		#[allow(non_snake_case)]
-		impl<Err, Req, Fut, Fun, $($tx,)*> RumaHandler<($($tx,)* Ruma<Req>,)> for Fun
+		impl<Err, Req, Res, Fut, Fun, $($tx,)*> RumaHandler<($($tx,)* Ruma<Req>,)> for Fun
 		where
 			Fun: Fn($($tx,)* Ruma<Req>,) -> Fut + Send + Sync + 'static,
-			Fut: Future<Output = Result<Req::OutgoingResponse, Err>> + Send,
+			Fut: Future<Output = Result<Res, Err>> + Send,
 			Req: IncomingRequest + Debug + Send + Sync + 'static,
 			Err: IntoResponse + Send,
-			<Req as IncomingRequest>::OutgoingResponse: Send,
+			Res: OutgoingResponse + Send,
 			$( $tx: FromRequestParts<State> + Send + Sync + 'static, )*
 		{
 			fn add_routes(&'static self, router: Router<State>) -> Router<State> {
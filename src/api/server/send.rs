@@ -1,3 +1,6 @@
+#[cfg(test)]
+mod tests;
+
 use std::{collections::BTreeMap, net::IpAddr, time::Instant};
 
 use axum::extract::State;
@@ -22,7 +25,7 @@
 	to_device::DeviceIdOrAllDevices,
 };
 use tuwunel_core::{
-	Err, Error, Result, debug,
+	Config, Err, Error, Result, debug,
 	debug::INFO_SPAN_LEVEL,
 	debug_warn, err, error,
 	result::LogErr,
@@ -216,19 +219,18 @@ async fn handle_room(
 }
 
 async fn handle_edu(services: &Services, client: &IpAddr, origin: &ServerName, edu: Edu) {
+	if !edu_allowed(&services.server.config, &edu) {
+		trace!(?edu, "skipped by config");
+		return;
+	}
+
 	match edu {
-		| Edu::Presence(presence) if services.server.config.allow_incoming_presence =>
+		| Edu::Presence(presence) =>
 			handle_edu_presence(services, client, origin, presence).await,
 
-		| Edu::Receipt(receipt)
-			if services
-				.server
-				.config
-				.allow_incoming_read_receipts =>
-			handle_edu_receipt(services, client, origin, receipt).await,
+		| Edu::Receipt(receipt) => handle_edu_receipt(services, client, origin, receipt).await,
 
-		| Edu::Typing(typing) if services.server.config.allow_incoming_typing =>
-			handle_edu_typing(services, client, origin, typing).await,
+		| Edu::Typing(typing) => handle_edu_typing(services, client, origin, typing).await,
 
 		| Edu::DeviceListUpdate(content) =>
 			handle_edu_device_list_update(services, client, origin, content).await,
@@ -245,6 +247,17 @@ async fn handle_edu(services: &Services, client: &IpAddr, origin: &ServerName, e
 	}
 }
 
+/// Whether the configuration accepts incoming EDUs of this kind. Refused EDUs
+/// are dropped silently.
+fn edu_allowed(config: &Config, edu: &Edu) -> bool {
+	match edu {
+		| Edu::Presence(_) => config.allow_incoming_presence,
+		| Edu::Receipt(_) => config.allow_incoming_read_receipts,
+		| Edu::Typing(_) => config.allow_incoming_typing,
+		| _ => true,
+	}
+}
+
 async fn handle_edu_presence(
 	services: &Services,
 	_client: &IpAddr,
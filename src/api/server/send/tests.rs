@@ -0,0 +1,34 @@
+use ruma::{
+	api::federation::transactions::edu::{Edu, TypingContent},
+	owned_room_id, owned_user_id,
+};
+use tuwunel_core::{Config, config::Figment};
+
+use super::edu_allowed;
+
+fn config(allow_incoming_typing: bool) -> Config {
+	let raw = Figment::new()
+		.merge(("server_name", "example.org"))
+		.merge(("database_path", "/var/lib/tuwunel"))
+		.merge(("allow_incoming_typing", allow_incoming_typing));
+
+	Config::new(&raw).expect("valid config")
+}
+
+fn typing_edu() -> Edu {
+	Edu::Typing(TypingContent::new(
+		owned_room_id!("!a:remote.example.org"),
+		owned_user_id!("@bob:remote.example.org"),
+		true,
+	))
+}
+
+#[test]
+fn incoming_typing_dropped_when_disallowed() {
+	assert!(!edu_allowed(&config(false), &typing_edu()));
+}
+
+#[test]
+fn incoming_typing_accepted_by_default() {
+	assert!(edu_allowed(&config(true), &typing_edu()));
+}
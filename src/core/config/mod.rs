@@ -273,6 +273,67 @@ pub struct Config {
 	#[serde(default = "default_client_sync_timeout_max")]
 	pub client_sync_timeout_max: u64,
 
+	/// Exclude a room from sliding sync lists filtered by room type when its
+	/// type cannot be read due to an error other than the room having none. By
+	/// default such rooms are kept with a warning, so a transient database
+	/// error does not make them flicker out of the client's lists.
+	#[serde(default)]
+	pub sync_exclude_rooms_on_room_type_error: bool,
+
+	/// Maximum age of a sliding sync `pos`, measured in database counts behind
+	/// the current position, before the client is told to restart its sync
+	/// stream instead of the server computing a very large incremental
+	/// response. Set to 0 to accept any position.
+	///
+	/// default: 0
+	#[serde(default)]
+	pub sync_max_pos_age: u64,
+
+	/// Maximum number of room subscriptions processed for a sliding sync
+	/// connection. Subscriptions beyond this are ignored, in room ID order, so
+	/// a client cannot make the server check thousands of rooms per request.
+	///
+	/// default: 512
+	#[serde(default = "default_sync_max_subscriptions")]
+	pub sync_max_subscriptions: usize,
+
+	/// Largest timeline_limit honored for a room in sliding sync, for both
+	/// lists and room subscriptions. Requests for longer timelines are
+	/// clamped to this many events.
+	///
+	/// default: 100
+	#[serde(default = "default_sync_timeline_limit_max")]
+	pub sync_timeline_limit_max: usize,
+
+	/// Maximum number of stripped state events included in a sliding sync
+	/// room's invite_state. The stripped state comes from the inviting server
+	/// and is truncated to this many events, with a warning, to bound the
+	/// response size.
+	///
+	/// default: 50
+	#[serde(default = "default_sync_max_invite_state_events")]
+	pub sync_max_invite_state_events: usize,
+
+	/// Include the stripped state kept when knocking, such as the room's name,
+	/// avatar, topic and join rules, in sliding sync's knocked rooms so clients
+	/// can show a pending knock. The room's full state and timeline are not
+	/// visible to a knocker and are never fetched for knocked rooms.
+	#[serde(default = "true_fn")]
+	pub sync_knocked_room_state: bool,
+
+	/// Omit prev_batch from rooms in an initial sliding sync unless the room's
+	/// timeline was limited. Clients which don't paginate back on an initial
+	/// sync then receive no token they won't use; a limited timeline keeps its
+	/// prev_batch so older events remain reachable.
+	#[serde(default)]
+	pub sync_suppress_initial_prev_batch: bool,
+
+	/// Always query per-room account data for invited rooms in sliding sync.
+	/// By default it is only queried when the user has account data in the
+	/// invited room, which is rare before joining.
+	#[serde(default)]
+	pub sync_invited_room_account_data: bool,
+
 	/// Maximum entries stored in DNS memory-cache. The size of an entry may
 	/// vary so please take care if raising this value excessively. Only
 	/// decrease this when using an external DNS cache. Please note that
@@ -1299,10 +1360,19 @@ pub struct Config {
 	#[serde(default = "true_fn")]
 	pub allow_outgoing_typing: bool,
 
-	/// Allow incoming typing updates from federation.
+	/// Allow incoming typing updates from federation. When disabled, typing
+	/// EDUs received from remote servers are dropped without being recorded;
+	/// this is independent of allow_outgoing_typing.
 	#[serde(default = "true_fn")]
 	pub allow_incoming_typing: bool,
 
+	/// Disables typing indicators in encrypted rooms, both from local clients
+	/// and from other servers. Typing notifications are never encrypted, so
+	/// even who is typing in an encrypted room is visible to the servers in
+	/// it.
+	#[serde(default)]
+	pub disable_typing_in_encrypted_rooms: bool,
+
 	/// Maximum time federation user can indicate typing.
 	///
 	/// default: 30
@@ -1323,6 +1393,105 @@ pub struct Config {
 	#[serde(default = "default_typing_client_timeout_max_s")]
 	pub typing_client_timeout_max_s: u64,
 
+	/// Interval in seconds at which expired typing indicators are cleared in
+	/// the background, also in rooms nobody is syncing, and federation told
+	/// that local users stopped typing. Set to 0 to only clear them when a room
+	/// is synced.
+	///
+	/// default: 5
+	#[serde(default = "default_typing_sweep_interval_s")]
+	pub typing_sweep_interval_s: u64,
+
+	/// Minimum interval in milliseconds between accepted typing updates from
+	/// the same user in the same room. Updates arriving sooner, e.g. a client
+	/// sending one per keystroke, are dropped without waking clients or
+	/// federating; local clients are answered with a rate limit error.
+	/// Stopping to type is never dropped. Set to 0 to accept every update.
+	///
+	/// default: 500
+	#[serde(default = "default_typing_min_interval_ms")]
+	pub typing_min_interval_ms: u64,
+
+	/// Window in milliseconds during which a local user's typing start is
+	/// coalesced into the previous start federated for the same user and room,
+	/// as when typing is stopped and restarted in quick succession. Set to 0 to
+	/// disable coalescing and federate every change of typing status.
+	///
+	/// default: 300
+	#[serde(default = "default_typing_edu_coalesce_window_ms")]
+	pub typing_edu_coalesce_window_ms: u64,
+
+	/// Grace period in milliseconds before a local user's typing start is
+	/// federated. The start is only sent if the user is still typing once the
+	/// period has passed, so a start immediately followed by a stop sends
+	/// nothing to other servers. Stops are always sent immediately. The typing
+	/// request does not wait for the grace period. Set to 0 to send starts
+	/// immediately.
+	///
+	/// default: 0
+	#[serde(default)]
+	pub typing_federation_grace_ms: u64,
+
+	/// Window in milliseconds during which typing changes in the same room wake
+	/// syncing clients only once. The first change wakes them immediately;
+	/// later changes within the window are delivered together, as a single
+	/// update, at its end. Set to 0 to wake clients on every change.
+	///
+	/// default: 100
+	#[serde(default = "default_typing_broadcast_coalesce_ms")]
+	pub typing_broadcast_coalesce_ms: u64,
+
+	/// Number of typing updates buffered for subscribers to typing across all
+	/// rooms. A subscriber falling further behind misses updates and treats
+	/// that as a wakeup. Raise it on busy servers with many such subscribers.
+	/// Syncing clients wait on their own rooms and are not affected.
+	///
+	/// default: 100
+	#[serde(default = "default_typing_broadcast_capacity")]
+	pub typing_broadcast_capacity: usize,
+
+	/// Which rooms local typing updates are federated to, based on the
+	/// room's joined member count.
+	///
+	/// Available options are "all", "small_rooms_only" (only rooms with at
+	/// most `typing_federation_small_room_size` members, e.g. DMs), or
+	/// "all_but_huge" (every room below `typing_federation_huge_room_size`
+	/// members).
+	///
+	/// default: "all"
+	#[serde(default)]
+	pub typing_federation_room_size_mode: TypingFederationRoomSizeMode,
+
+	/// Largest joined member count considered a small room when
+	/// `typing_federation_room_size_mode` is "small_rooms_only".
+	///
+	/// default: 10
+	#[serde(default = "default_typing_federation_small_room_size")]
+	pub typing_federation_small_room_size: u64,
+
+	/// Smallest joined member count considered a huge room when
+	/// `typing_federation_room_size_mode` is "all_but_huge".
+	///
+	/// default: 1000
+	#[serde(default = "default_typing_federation_huge_room_size")]
+	pub typing_federation_huge_room_size: u64,
+
+	/// Maximum number of distinct typing starts federated per room within
+	/// `typing_federation_burst_window_ms`. Further starts in that window are
+	/// not federated, guarding remote servers against many local users (e.g. a
+	/// bot farm) typing at once. Stops are always sent. Set to 0 to disable
+	/// the cap.
+	///
+	/// default: 20
+	#[serde(default = "default_typing_federation_burst_max")]
+	pub typing_federation_burst_max: u64,
+
+	/// Window in milliseconds over which `typing_federation_burst_max` applies.
+	///
+	/// default: 1000
+	#[serde(default = "default_typing_federation_burst_window_ms")]
+	pub typing_federation_burst_window_ms: u64,
+
 	/// Set this to true for tuwunel to compress HTTP response bodies using
 	/// zstd. This option does nothing if tuwunel was not built with
 	/// `zstd_compression` feature. Please be aware that enabling HTTP
@@ -2328,6 +2497,17 @@ fn from(conf: AppServiceNamespace) -> Self {
 	}
 }
 
+/// Which rooms local typing updates are federated to, based on the room's
+/// joined member count.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TypingFederationRoomSizeMode {
+	#[default]
+	All,
+	SmallRoomsOnly,
+	AllButHuge,
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(transparent)]
 struct ListeningPort {
@@ -2578,6 +2758,24 @@ fn default_typing_client_timeout_min_s() -> u64 { 15 }
 
 fn default_typing_client_timeout_max_s() -> u64 { 45 }
 
+fn default_typing_sweep_interval_s() -> u64 { 5 }
+
+fn default_typing_min_interval_ms() -> u64 { 500 }
+
+fn default_typing_edu_coalesce_window_ms() -> u64 { 300 }
+
+fn default_typing_broadcast_coalesce_ms() -> u64 { 100 }
+
+fn default_typing_broadcast_capacity() -> usize { 100 }
+
+fn default_typing_federation_small_room_size() -> u64 { 10 }
+
+fn default_typing_federation_huge_room_size() -> u64 { 1000 }
+
+fn default_typing_federation_burst_max() -> u64 { 20 }
+
+fn default_typing_federation_burst_window_ms() -> u64 { 1000 }
+
 fn default_rocksdb_recovery_mode() -> u8 { 1 }
 
 fn default_rocksdb_log_level() -> String { "error".to_owned() }
@@ -2740,4 +2938,10 @@ fn default_client_sync_timeout_default() -> u64 { 30000 }
 
 fn default_client_sync_timeout_max() -> u64 { 90000 }
 
+fn default_sync_max_subscriptions() -> usize { 512 }
+
+fn default_sync_timeline_limit_max() -> usize { 100 }
+
+fn default_sync_max_invite_state_events() -> usize { 50 }
+
 fn default_access_token_ttl() -> u64 { 604_800 }
@@ -201,6 +201,11 @@ pub(super) fn open_list(db: &Arc<Engine>, maps: &[Descriptor]) -> Result<Maps> {
 		val_size_hint: Some(8),
 		..descriptor::RANDOM_SMALL
 	},
+	Descriptor {
+		name: "roomuserid_invitets",
+		val_size_hint: Some(8),
+		..descriptor::RANDOM_SMALL
+	},
 	Descriptor {
 		name: "roomuserid_joined",
 		..descriptor::RANDOM_SMALL
@@ -342,6 +347,10 @@ pub(super) fn open_list(db: &Arc<Engine>, maps: &[Descriptor]) -> Result<Maps> {
 		name: "url_previews",
 		..descriptor::RANDOM
 	},
+	Descriptor {
+		name: "userdeviceid_fallbackkeys",
+		..descriptor::RANDOM_SMALL
+	},
 	Descriptor {
 		name: "userdeviceid_metadata",
 		..descriptor::RANDOM_SMALL
@@ -434,6 +443,10 @@ pub(super) fn open_list(db: &Arc<Engine>, maps: &[Descriptor]) -> Result<Maps> {
 		name: "userroomid_knockedstate",
 		..descriptor::RANDOM_SMALL
 	},
+	Descriptor {
+		name: "userroomid_messagenotificationcount",
+		..descriptor::RANDOM
+	},
 	Descriptor {
 		name: "userroomid_notificationcount",
 		..descriptor::RANDOM
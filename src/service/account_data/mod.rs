@@ -14,7 +14,7 @@
 	Err, Result, err, implement,
 	utils::{ReadyExt, result::LogErr, stream::TryIgnore},
 };
-use tuwunel_database::{Deserialized, Handle, Ignore, Json, Map};
+use tuwunel_database::{Deserialized, Handle, Ignore, Interfix, Json, Map};
 
 use crate::{Dep, globals};
 
@@ -134,6 +134,38 @@ pub async fn get_raw(
 		.await
 }
 
+/// Returns the count at which the room account data of a specific kind was
+/// last updated.
+#[implement(Service)]
+pub async fn room_data_count(
+	&self,
+	room_id: &RoomId,
+	user_id: &UserId,
+	kind: RoomAccountDataEventType,
+) -> Result<u64> {
+	type Key<'a> = (Option<&'a RoomId>, &'a UserId, u64, Ignore);
+
+	let key = (Some(room_id), user_id, kind.to_string());
+	self.db
+		.roomusertype_roomuserdataid
+		.qry(&key)
+		.await
+		.deserialized()
+		.map(|(_, _, count, _): Key<'_>| count)
+}
+
+/// Whether the user has any account data in the room.
+#[implement(Service)]
+pub async fn has_room_data(&self, room_id: &RoomId, user_id: &UserId) -> bool {
+	let prefix = (Some(room_id), user_id, Interfix);
+	self.db
+		.roomusertype_roomuserdataid
+		.keys_prefix_raw(&prefix)
+		.ignore_err()
+		.ready_any(|_| true)
+		.await
+}
+
 /// Returns all changes to the account data that happened after `since`.
 #[implement(Service)]
 pub fn changes_since<'a>(
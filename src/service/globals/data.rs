@@ -61,11 +61,13 @@ pub(super) async fn wait_count(&self, count: &u64) -> Result<u64> {
 
 	#[inline]
 	pub(super) fn next_count(&self) -> Permit {
-		self.counter
-			.next()
+		self.try_next_count()
 			.expect("failed to obtain next sequence number")
 	}
 
+	#[inline]
+	pub(super) fn try_next_count(&self) -> Result<Permit> { self.counter.next() }
+
 	#[inline]
 	pub(super) fn current_count(&self) -> u64 { self.counter.current() }
 
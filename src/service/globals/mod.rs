@@ -129,6 +129,15 @@ pub async fn wait_count(&self, count: &u64) -> Result<u64> { self.db.wait_count(
 	#[must_use]
 	pub fn next_count(&self) -> data::Permit { self.db.next_count() }
 
+	/// Like [`Self::next_count`], but returns the error rather than panicking
+	/// when no sequence number can be obtained.
+	#[tracing::instrument(
+		level = "debug",
+		skip_all,
+		fields(pending = ?self.pending_count()),
+	)]
+	pub fn try_next_count(&self) -> Result<data::Permit> { self.db.try_next_count() }
+
 	#[must_use]
 	pub fn current_count(&self) -> u64 { self.db.current_count() }
 
@@ -1,9 +1,12 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use futures::{Stream, StreamExt};
 use ruma::{
 	CanonicalJsonObject, RoomId, UserId,
-	events::{AnySyncEphemeralRoomEvent, receipt::ReceiptEvent},
+	events::{
+		AnySyncEphemeralRoomEvent,
+		receipt::{ReceiptEvent, ReceiptEventContent, ReceiptThread},
+	},
 	serde::Raw,
 };
 use tuwunel_core::{
@@ -47,13 +50,21 @@ pub(super) async fn readreceipt_update(
 		room_id: &RoomId,
 		event: &ReceiptEvent,
 	) {
-		// Remove old entry
+		// Remove old entry for the same thread; receipts in other threads remain
+		let thread = receipt_thread(&event.content, user_id);
 		let last_possible_key = (room_id, u64::MAX);
 		self.readreceiptid_readreceipt
-			.rev_keys_from_raw(&last_possible_key)
+			.rev_stream_from_raw(&last_possible_key)
 			.ignore_err()
-			.ready_take_while(|key| key.starts_with(room_id.as_bytes()))
-			.ready_filter_map(|key| key.ends_with(user_id.as_bytes()).then_some(key))
+			.ready_take_while(|(key, _)| key.starts_with(room_id.as_bytes()))
+			.ready_filter(|(key, _)| key.ends_with(user_id.as_bytes()))
+			.ready_filter_map(|(key, val)| {
+				// only the user's own receipts are deserialized to compare threads
+				serde_json::from_slice::<ReceiptEvent>(val)
+					.ok()
+					.is_none_or(|old| receipt_thread(&old.content, user_id) == thread)
+					.then_some(key)
+			})
 			.ready_for_each(|key| self.readreceiptid_readreceipt.del(key))
 			.await;
 
@@ -129,3 +140,12 @@ pub(super) async fn last_privateread_update(
 			.unwrap_or(0)
 	}
 }
+
+/// The thread of the user's receipt in a receipt event.
+fn receipt_thread(content: &ReceiptEventContent, user_id: &UserId) -> ReceiptThread {
+	content
+		.values()
+		.flat_map(BTreeMap::values)
+		.find_map(|users| users.get(user_id))
+		.map_or(ReceiptThread::Unthreaded, |receipt| receipt.thread.clone())
+}
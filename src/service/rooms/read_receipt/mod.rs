@@ -170,8 +170,14 @@ pub fn pack_receipts<I>(receipts: I) -> Raw<SyncEphemeralRoomEvent<ReceiptEventC
 		);
 		match receipt {
 			| Ok(value) =>
-				for (event, receipt) in value.content {
-					json.insert(event, receipt);
+				for (event, receipts) in value.content {
+					let entry: &mut Receipts = json.entry(event).or_default();
+					for (receipt_type, users) in receipts {
+						entry
+							.entry(receipt_type)
+							.or_default()
+							.extend(users);
+					}
 				},
 			| _ => {
 				debug!("failed to parse receipt: {:?}", receipt);
@@ -34,6 +34,10 @@ struct Services {
 	globals: Dep<globals::Service>,
 	metadata: Dep<rooms::metadata::Service>,
 	state_accessor: Dep<rooms::state_accessor::Service>,
+	// Circular: typing depends on state_cache for membership. Leaving a room
+	// awaits typing_remove_on_leave, which must not call back into
+	// update_membership.
+	typing: Dep<rooms::typing::Service>,
 	users: Dep<users::Service>,
 }
 
@@ -44,6 +48,7 @@ struct Data {
 	roomid_joinedcount: Arc<Map>,
 	roomserverids: Arc<Map>,
 	roomuserid_invitecount: Arc<Map>,
+	roomuserid_invitets: Arc<Map>,
 	roomuserid_joined: Arc<Map>,
 	roomuserid_leftcount: Arc<Map>,
 	roomuserid_knockedcount: Arc<Map>,
@@ -70,6 +75,7 @@ fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 				metadata: args.depend::<rooms::metadata::Service>("rooms::metadata"),
 				state_accessor: args
 					.depend::<rooms::state_accessor::Service>("rooms::state_accessor"),
+				typing: args.depend::<rooms::typing::Service>("rooms::typing"),
 				users: args.depend::<users::Service>("users"),
 			},
 			db: Data {
@@ -79,6 +85,7 @@ fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 				roomid_joinedcount: args.db["roomid_joinedcount"].clone(),
 				roomserverids: args.db["roomserverids"].clone(),
 				roomuserid_invitecount: args.db["roomuserid_invitecount"].clone(),
+				roomuserid_invitets: args.db["roomuserid_invitets"].clone(),
 				roomuserid_joined: args.db["roomuserid_joined"].clone(),
 				roomuserid_leftcount: args.db["roomuserid_leftcount"].clone(),
 				roomuserid_knockedcount: args.db["roomuserid_knockedcount"].clone(),
@@ -353,6 +360,19 @@ pub async fn get_invite_count(&self, room_id: &RoomId, user_id: &UserId) -> Resu
 		.deserialized()
 }
 
+/// Returns the local timestamp (milliseconds since the unix epoch) at which
+/// the invite for this user was received.
+#[implement(Service)]
+#[tracing::instrument(skip(self), level = "trace")]
+pub async fn get_invite_ts(&self, room_id: &RoomId, user_id: &UserId) -> Result<u64> {
+	let key = (room_id, user_id);
+	self.db
+		.roomuserid_invitets
+		.qry(&key)
+		.await
+		.deserialized()
+}
+
 #[implement(Service)]
 #[tracing::instrument(skip(self), level = "trace")]
 pub async fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<u64> {
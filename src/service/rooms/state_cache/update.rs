@@ -14,7 +14,11 @@
 	},
 	serde::Raw,
 };
-use tuwunel_core::{Result, implement, is_not_empty, utils::ReadyExt, warn};
+use tuwunel_core::{
+	Result, implement, is_not_empty,
+	utils::{self, ReadyExt},
+	warn,
+};
 use tuwunel_database::{Json, serialize_key};
 
 /// Update current membership data.
@@ -147,6 +151,16 @@ pub async fn update_membership(
 		| MembershipState::Leave | MembershipState::Ban => {
 			self.mark_as_left(user_id, room_id);
 
+			// typing depends on this service in turn; see the typing Dep
+			if let Err(e) = self
+				.services
+				.typing
+				.typing_remove_on_leave(user_id, room_id)
+				.await
+			{
+				warn!(%room_id, %user_id, "failed to clear typing on leave: {e}");
+			}
+
 			if self.services.globals.user_is_local(user_id)
 				&& (self.services.config.forget_forced_upon_leave
 					|| self.services.metadata.is_banned(room_id).await
@@ -257,6 +271,7 @@ pub(crate) fn mark_as_joined(&self, user_id: &UserId, room_id: &RoomId) {
 	self.db
 		.roomuserid_invitecount
 		.remove(&roomuser_id);
+	self.db.roomuserid_invitets.remove(&roomuser_id);
 
 	self.db.userroomid_leftstate.remove(&userroom_id);
 	self.db.roomuserid_leftcount.remove(&roomuser_id);
@@ -304,6 +319,7 @@ pub(crate) fn mark_as_left(&self, user_id: &UserId, room_id: &RoomId) {
 	self.db
 		.roomuserid_invitecount
 		.remove(&roomuser_id);
+	self.db.roomuserid_invitets.remove(&roomuser_id);
 
 	self.db
 		.userroomid_knockedstate
@@ -350,6 +366,7 @@ pub(crate) fn mark_as_knocked(
 	self.db
 		.roomuserid_invitecount
 		.remove(&roomuser_id);
+	self.db.roomuserid_invitets.remove(&roomuser_id);
 
 	self.db.userroomid_leftstate.remove(&userroom_id);
 	self.db.roomuserid_leftcount.remove(&roomuser_id);
@@ -398,6 +415,9 @@ pub(crate) async fn mark_as_invited(
 	self.db
 		.roomuserid_invitecount
 		.raw_aput::<8, _, _>(&roomuser_id, *count);
+	self.db
+		.roomuserid_invitets
+		.raw_aput::<8, _, _>(&roomuser_id, utils::millis_since_unix_epoch());
 
 	self.db.userroomid_joined.remove(&userroom_id);
 	self.db.roomuserid_joined.remove(&roomuser_id);
@@ -282,7 +282,8 @@ pub async fn append_pdu<'a, Leafs>(
 			.await;
 	}
 
-	self.increment_notification_counts(pdu.room_id(), notifies, highlights);
+	let message = is_message_notification(pdu.kind());
+	self.increment_notification_counts(pdu.room_id(), notifies, highlights, message);
 
 	match *pdu.kind() {
 		| TimelineEventType::RoomRedaction => {
@@ -510,6 +511,7 @@ fn increment_notification_counts(
 	room_id: &RoomId,
 	notifies: Vec<OwnedUserId>,
 	highlights: Vec<OwnedUserId>,
+	message: bool,
 ) {
 	let _cork = self.db.db.cork();
 
@@ -518,6 +520,9 @@ fn increment_notification_counts(
 		userroom_id.push(0xFF);
 		userroom_id.extend_from_slice(room_id.as_bytes());
 		increment(&self.db.userroomid_notificationcount, &userroom_id);
+		if message {
+			increment(&self.db.userroomid_messagenotificationcount, &userroom_id);
+		}
 	}
 
 	for user in highlights {
@@ -528,6 +533,17 @@ fn increment_notification_counts(
 	}
 }
 
+/// Whether a notification for an event of this type counts as a message
+/// notification rather than an invite or other notification.
+fn is_message_notification(kind: &TimelineEventType) -> bool {
+	matches!(
+		kind,
+		TimelineEventType::RoomMessage
+			| TimelineEventType::RoomEncrypted
+			| TimelineEventType::Sticker
+	)
+}
+
 //TODO: this is an ABA
 fn increment(db: &Arc<Map>, key: &[u8]) {
 	let old = db.get_blocking(key);
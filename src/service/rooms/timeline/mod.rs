@@ -74,6 +74,7 @@ struct Data {
 	pduid_pdu: Arc<Map>,
 	userroomid_highlightcount: Arc<Map>,
 	userroomid_notificationcount: Arc<Map>,
+	userroomid_messagenotificationcount: Arc<Map>,
 	db: Arc<Database>,
 }
 
@@ -138,6 +139,9 @@ fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 				pduid_pdu: args.db["pduid_pdu"].clone(),
 				userroomid_highlightcount: args.db["userroomid_highlightcount"].clone(),
 				userroomid_notificationcount: args.db["userroomid_notificationcount"].clone(),
+				userroomid_messagenotificationcount: args.db
+					["userroomid_messagenotificationcount"]
+					.clone(),
 				db: args.db.clone(),
 			},
 			mutex_insert: RoomMutexMap::new(),
@@ -1,82 +1,366 @@
-use std::{collections::BTreeMap, sync::Arc};
+#[cfg(test)]
+mod tests;
 
-use futures::StreamExt;
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt::{self, Write},
+	sync::{
+		Arc, Mutex, Weak,
+		atomic::{AtomicUsize, Ordering},
+	},
+	time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{FutureExt, StreamExt, TryStreamExt, future};
+use http::StatusCode;
 use ruma::{
-	OwnedRoomId, OwnedUserId, RoomId, UserId,
-	api::federation::transactions::edu::{Edu, TypingContent},
+	OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, RoomVersionId, ServerName, UserId,
+	api::{
+		client::error::ErrorKind,
+		federation::transactions::edu::{Edu, TypingContent},
+	},
+	events::{
+		GlobalAccountDataEventType, StateEventType, push_rules::PushRulesEvent,
+		room::create::RoomCreateEventContent, typing::TypingEventContent,
+	},
+	push::{PredefinedOverrideRuleId, RuleKind, Ruleset},
+	room::RoomType,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	sync::{RwLock, broadcast, watch},
+	time::sleep,
 };
-use tokio::sync::{RwLock, broadcast};
 use tuwunel_core::{
-	Result, Server, debug_info, trace,
-	utils::{self, IterStream},
+	Err, Error, Result, Server,
+	config::TypingFederationRoomSizeMode,
+	debug_info, err, error, info,
+	info::room_version::{
+		EXPERIMENTAL_ROOM_VERSIONS, STABLE_ROOM_VERSIONS, UNSTABLE_ROOM_VERSIONS,
+	},
+	is_equal_to, trace,
+	utils::{self, IterStream, ReadyExt, stream::TryBroadbandExt},
+	warn,
 };
 
-use crate::{Dep, globals, sending, sending::EduBuf, users};
+use crate::{
+	Dep, account_data, appservice, globals, rooms, rooms::short::ShortStateHash, sending,
+	sending::EduBuf, users,
+};
 
 pub struct Service {
 	server: Arc<Server>,
 	services: Services,
+	/// this service, for the tasks it spawns
+	this: Weak<Self>,
 	/// u64 is unix timestamp of timeout
-	pub typing: RwLock<BTreeMap<OwnedRoomId, BTreeMap<OwnedUserId, u64>>>,
+	pub typing: RwLock<TypingMap>,
 	/// timestamp of the last change to typing users
-	pub last_typing_update: RwLock<BTreeMap<OwnedRoomId, u64>>,
+	pub last_typing_update: Arc<RwLock<BTreeMap<OwnedRoomId, u64>>>,
 	pub typing_update_sender: broadcast::Sender<OwnedRoomId>,
+	/// timestamp of the last typing start federated for a user in a room
+	federation_sent: Mutex<HashMap<(OwnedRoomId, OwnedUserId), u64>>,
+	/// timestamp of the last typing start federated for a user in a room, kept
+	/// across stops for as long as it coalesces further starts
+	federation_started: Mutex<HashMap<(OwnedRoomId, OwnedUserId), u64>>,
+	/// start of the current burst window and typing starts counted in it, per
+	/// room
+	federation_burst: Mutex<HashMap<OwnedRoomId, (u64, u64)>>,
+	/// typing EDUs enqueued and dropped per federation destination
+	federation_stats: Mutex<BTreeMap<OwnedServerName, DestinationStats>>,
+	/// typing-related room state, cached until the room's state changes
+	room_config: Mutex<HashMap<OwnedRoomId, (ShortStateHash, RoomConfig)>>,
+	/// number of users currently typing across all rooms
+	typing_count: AtomicUsize,
+	/// timestamp at which the last typing update of a room was or will be
+	/// broadcast to waiting clients
+	broadcast_at: Mutex<HashMap<OwnedRoomId, u64>>,
+	/// timestamp of the last accepted typing update of a user in a room
+	last_accepted: RwLock<BTreeMap<(OwnedUserId, OwnedRoomId), u64>>,
+	/// pauses typing while sequence numbers cannot be obtained
+	count_breaker: Mutex<CountBreaker>,
+	/// count of the last broadcast typing update per room, created when a
+	/// waiter first watches the room
+	room_watch: Arc<RoomWatch>,
 }
 
+type TypingMap = BTreeMap<OwnedRoomId, BTreeMap<OwnedUserId, u64>>;
+type RoomWatch = Mutex<HashMap<OwnedRoomId, watch::Sender<u64>>>;
+
 struct Services {
+	account_data: Dep<account_data::Service>,
+	appservice: Dep<appservice::Service>,
 	globals: Dep<globals::Service>,
 	sending: Dep<sending::Service>,
+	state: Dep<rooms::state::Service>,
+	state_accessor: Dep<rooms::state_accessor::Service>,
+	// Circular: state_cache calls typing_remove_on_leave from
+	// update_membership, so typing only reads membership through it and never
+	// updates it.
+	state_cache: Dep<rooms::state_cache::Service>,
 	users: Dep<users::Service>,
 }
 
+/// Typing-related configuration carried in a room's state.
+#[derive(Clone, Copy, Debug, Default)]
+struct RoomConfig {
+	/// Maximum typing duration in milliseconds from `m.room.typing_config`.
+	timeout: Option<u64>,
+	/// The room is a space; typing there is meaningless and not recorded.
+	space: bool,
+	/// The room version is unknown to this server, so typing is kept local
+	/// rather than risking protocol errors with strict remote servers.
+	unsupported_version: bool,
+	/// The room has `m.room.encryption` state.
+	encrypted: bool,
+}
+
+/// Content of the unstable `m.room.typing_config` state event.
+#[derive(Deserialize)]
+struct TypingConfigEventContent {
+	/// Maximum duration in milliseconds a user may be shown as typing.
+	timeout: u64,
+}
+
+/// Why a typing update failed. Converts into the matching client error at the
+/// API boundary.
+#[derive(Debug)]
+pub enum TypingError {
+	/// The user updated their typing status too frequently.
+	RateLimited,
+	/// The user is not joined to the room.
+	NotMember,
+	/// Any other failure, e.g. of the database or of sending to federation.
+	Internal(Error),
+}
+
+/// Whether a typing start began composing or refreshed ongoing typing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TypingTransition {
+	/// The user was not typing in the room before.
+	Started,
+	/// The user was already typing; only the timeout was extended.
+	Refreshed,
+}
+
+/// Bounds on the typing duration clients may request, advertised to them so
+/// they can send compliant values instead of being clamped silently.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct TimeoutPolicy {
+	/// Shortest typing duration in milliseconds.
+	pub min_timeout_ms: u64,
+	/// Longest typing duration in milliseconds; a room may lower it further.
+	pub max_timeout_ms: u64,
+}
+
+/// Circuit breaker for failures to obtain a sequence number. While open,
+/// typing updates are dropped instead of failing every request, and a streak
+/// of failures is only logged once.
+#[derive(Debug, Default)]
+struct CountBreaker {
+	/// Timestamp until which typing updates are skipped.
+	open_until: u64,
+	/// Consecutive failures since the last success.
+	failures: u64,
+}
+
+/// Typing EDUs sent towards a federation destination.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DestinationStats {
+	/// EDUs queued for sending.
+	pub enqueued: u64,
+	/// EDUs dropped because the destination was backing off.
+	pub dropped: u64,
+}
+
+/// Outcome of [`Service::typing_self_test`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SelfTest {
+	/// Waiting clients were woken up when typing started.
+	pub notified: bool,
+	/// The user was reported as typing.
+	pub appeared: bool,
+	/// The user was no longer reported as typing once removed.
+	pub cleared: bool,
+}
+
+const TYPING_CONFIG_EVENT_TYPE: &str = "m.room.typing_config";
+
+/// Maximum number of typing stops sent to federation concurrently when
+/// several users time out together.
+const FEDERATION_STOP_WIDTH: usize = 8;
+
+/// How long typing stays paused after failing to obtain a sequence number.
+const COUNT_FAILURE_BACKOFF_MS: u64 = 30_000;
+
+/// Maximum number of rooms cleared of expired typing per background sweep.
+const SWEEP_ROOMS_PER_TICK: usize = 256;
+
+/// How long the last typing update of a room nobody is typing in is kept
+/// after its broadcast; see `evict_stale_updates`.
+const TYPING_UPDATE_RETENTION_MS: u64 = 3_600_000;
+
+#[async_trait]
 impl crate::Service for Service {
 	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
-		Ok(Arc::new(Self {
+		// a broadcast channel cannot be empty
+		let capacity = args
+			.server
+			.config
+			.typing_broadcast_capacity
+			.max(1);
+
+		Ok(Arc::new_cyclic(|this| Self {
 			server: args.server.clone(),
 			services: Services {
+				account_data: args.depend::<account_data::Service>("account_data"),
+				appservice: args.depend::<appservice::Service>("appservice"),
 				globals: args.depend::<globals::Service>("globals"),
 				sending: args.depend::<sending::Service>("sending"),
+				state: args.depend::<rooms::state::Service>("rooms::state"),
+				state_accessor: args
+					.depend::<rooms::state_accessor::Service>("rooms::state_accessor"),
+				state_cache: args.depend::<rooms::state_cache::Service>("rooms::state_cache"),
 				users: args.depend::<users::Service>("users"),
 			},
+			this: this.clone(),
 			typing: RwLock::new(BTreeMap::new()),
-			last_typing_update: RwLock::new(BTreeMap::new()),
-			typing_update_sender: broadcast::channel(100).0,
+			last_typing_update: Arc::new(RwLock::new(BTreeMap::new())),
+			typing_update_sender: broadcast::channel(capacity).0,
+			federation_sent: Mutex::new(HashMap::new()),
+			federation_started: Mutex::new(HashMap::new()),
+			federation_burst: Mutex::new(HashMap::new()),
+			federation_stats: Mutex::new(BTreeMap::new()),
+			room_config: Mutex::new(HashMap::new()),
+			typing_count: AtomicUsize::new(0),
+			broadcast_at: Mutex::new(HashMap::new()),
+			last_accepted: RwLock::new(BTreeMap::new()),
+			count_breaker: Mutex::new(CountBreaker::default()),
+			room_watch: Arc::new(Mutex::new(HashMap::new())),
 		}))
 	}
 
+	async fn worker(self: Arc<Self>) -> Result {
+		let interval = self.server.config.typing_sweep_interval_s;
+		if interval == 0 {
+			return Ok(());
+		}
+
+		while self.server.running() {
+			tokio::select! {
+				() = sleep(Duration::from_secs(interval)) => self.typings_sweep().await,
+				() = self.server.until_shutdown() => break,
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn memory_usage(&self, out: &mut (dyn Write + Send)) -> Result {
+		let typing_users = self.typing_count();
+		writeln!(out, "typing_users: {typing_users}")?;
+
+		for (server, stats) in self.federation_stats() {
+			let DestinationStats { enqueued, dropped } = stats;
+			writeln!(out, "typing_edus[{server}]: enqueued {enqueued} dropped {dropped}")?;
+		}
+
+		Ok(())
+	}
+
 	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
 }
 
 impl Service {
 	/// Sets a user as typing until the timeout timestamp is reached or
-	/// roomtyping_remove is called.
-	pub async fn typing_add(&self, user_id: &UserId, room_id: &RoomId, timeout: u64) -> Result {
-		debug_info!("typing started {user_id:?} in {room_id:?} timeout:{timeout:?}");
+	/// roomtyping_remove is called. The timeout is clamped to
+	/// `typing_client_timeout_max_s` (and the room's own maximum) before it is
+	/// stored, so clients and federation only ever see the clamped value.
+	pub async fn typing_add(
+		&self,
+		user_id: &UserId,
+		room_id: &RoomId,
+		timeout: u64,
+	) -> Result<(), TypingError> {
+		if self.count_unavailable() {
+			trace!(%room_id, %user_id, "typing paused while sequence numbers are unavailable");
+			return Ok(());
+		}
+
+		let joined = self.sees_typing(room_id, user_id).await;
+		if !member_typing_accepted(joined, self.services.globals.user_is_local(user_id))? {
+			trace!(%room_id, %user_id, "ignoring typing from remote user not in room");
+			return Ok(());
+		}
+
+		if self.services.globals.user_is_local(user_id) && self.do_not_disturb(user_id).await {
+			trace!(%room_id, %user_id, "suppressing typing while do not disturb is on");
+			return Ok(());
+		}
+
+		let config = self.room_config(room_id).await;
+		if typing_suppressed(
+			config,
+			self.server
+				.config
+				.disable_typing_in_encrypted_rooms,
+		) {
+			trace!(%room_id, %user_id, "ignoring typing in encrypted room");
+			return Ok(());
+		}
+
+		// only updates which are not suppressed count towards the interval
+		let elapsed = self
+			.typing_interval_elapsed(user_id, room_id)
+			.await;
+
+		if !interval_typing_accepted(elapsed, self.services.globals.user_is_local(user_id))? {
+			trace!(%room_id, %user_id, "dropping typing update within the minimum interval");
+			return Ok(());
+		}
+
+		let timeout = self.stored_timeout(user_id, config, timeout);
 
 		// update clients
-		self.typing
-			.write()
-			.await
-			.entry(room_id.to_owned())
-			.or_default()
-			.insert(user_id.to_owned(), timeout);
+		let (recorded, transition) = {
+			let mut typing = self.typing.write().await;
+			let before = room_typing_len(&typing, room_id);
+			let transition = typing_transition(&typing, room_id, user_id);
+			let recorded = record_typing(&mut typing, config, room_id, user_id, timeout);
+			adjust_gauge(&self.typing_count, before, room_typing_len(&typing, room_id));
+			(recorded, transition)
+		};
 
-		let count = self.services.globals.next_count();
-		self.last_typing_update
-			.write()
-			.await
-			.insert(room_id.to_owned(), *count);
+		if !recorded {
+			trace!(%room_id, %user_id, "ignoring typing in space room");
+			return Ok(());
+		}
 
-		if self
-			.typing_update_sender
-			.send(room_id.to_owned())
-			.is_err()
-		{
-			trace!("receiver found what it was looking for and is no longer interested");
+		match transition {
+			| TypingTransition::Started => {
+				debug_info!("typing started {user_id:?} in {room_id:?} timeout:{timeout:?}");
+			},
+			| TypingTransition::Refreshed => {
+				trace!(%room_id, %user_id, timeout, "typing refreshed");
+			},
+		}
+
+		self.update_clients(room_id).await;
+
+		// update appservices
+		if self.services.globals.user_is_local(user_id) {
+			self.appservice_send(room_id).await?;
 		}
 
 		// update federation
 		if self.services.globals.user_is_local(user_id) {
+			let grace = self.server.config.typing_federation_grace_ms;
+			if grace > 0 {
+				self.federation_send_after_grace(room_id, user_id, timeout, grace);
+				return Ok(());
+			}
+
 			self.federation_send(room_id, user_id, true)
 				.await?;
 		}
@@ -84,30 +368,34 @@ pub async fn typing_add(&self, user_id: &UserId, room_id: &RoomId, timeout: u64)
 		Ok(())
 	}
 
-	/// Removes a user from typing before the timeout is reached.
-	pub async fn typing_remove(&self, user_id: &UserId, room_id: &RoomId) -> Result {
-		debug_info!("typing stopped {user_id:?} in {room_id:?}");
-
-		// update clients
-		self.typing
+	/// Removes a user from typing before the timeout is reached. The typer is
+	/// removed even while typing is paused for lack of sequence numbers; only
+	/// waking up clients is skipped then.
+	pub async fn typing_remove(
+		&self,
+		user_id: &UserId,
+		room_id: &RoomId,
+	) -> Result<(), TypingError> {
+		// stops always go through, and typing again right after is a fresh start
+		self.last_accepted
 			.write()
 			.await
-			.entry(room_id.to_owned())
-			.or_default()
-			.remove(user_id);
+			.remove(&(user_id.to_owned(), room_id.to_owned()));
 
-		let count = self.services.globals.next_count();
-		self.last_typing_update
-			.write()
-			.await
-			.insert(room_id.to_owned(), *count);
+		// a user who was not typing has nothing to stop
+		if !remove_typer(&mut *self.typing.write().await, room_id, user_id) {
+			return Ok(());
+		}
 
-		if self
-			.typing_update_sender
-			.send(room_id.to_owned())
-			.is_err()
-		{
-			trace!("receiver found what it was looking for and is no longer interested");
+		debug_info!("typing stopped {user_id:?} in {room_id:?}");
+		adjust_gauge(&self.typing_count, 1, 0);
+
+		// update clients
+		self.update_clients(room_id).await;
+
+		// update appservices
+		if self.services.globals.user_is_local(user_id) {
+			self.appservice_send(room_id).await?;
 		}
 
 		// update federation
@@ -119,13 +407,79 @@ pub async fn typing_remove(&self, user_id: &UserId, room_id: &RoomId) -> Result
 		Ok(())
 	}
 
+	/// Whether typing in the room is suppressed because the room is encrypted
+	/// and `disable_typing_in_encrypted_rooms` is set.
+	pub async fn typing_suppressed(&self, room_id: &RoomId) -> bool {
+		let disable_in_encrypted = self
+			.server
+			.config
+			.disable_typing_in_encrypted_rooms;
+
+		disable_in_encrypted
+			&& typing_suppressed(self.room_config(room_id).await, disable_in_encrypted)
+	}
+
+	/// The typing durations local clients may request.
+	#[must_use]
+	pub fn timeout_policy(&self) -> TimeoutPolicy {
+		let config = &self.server.config;
+
+		TimeoutPolicy::new(config.typing_client_timeout_min_s, config.typing_client_timeout_max_s)
+	}
+
+	/// Number of users currently typing across all rooms.
+	#[inline]
+	pub fn typing_count(&self) -> usize { self.typing_count.load(Ordering::Relaxed) }
+
+	/// Waits for the next typing update in the room.
 	pub async fn wait_for_update(&self, room_id: &RoomId) {
-		let mut receiver = self.typing_update_sender.subscribe();
-		while let Ok(next) = receiver.recv().await {
-			if next == room_id {
-				break;
-			}
+		let mut receiver = self.watch_room(room_id).await;
+		receiver.changed().await.ok();
+	}
+
+	/// Waits until typing in one of the rooms is updated with a count after
+	/// `since`, returning at once if that already happened. Only the given
+	/// rooms' channels are awaited, so updates elsewhere cost nothing.
+	pub async fn wait_for_rooms_update<'a, I>(&self, room_ids: I, since: u64)
+	where
+		I: Iterator<Item = &'a RoomId> + Send,
+	{
+		let receivers = room_ids
+			.stream()
+			.then(|room_id| self.watch_room(room_id))
+			.collect::<Vec<_>>()
+			.await;
+
+		wait_for_room_updates(receivers, since).await;
+	}
+
+	/// Watches the count of the room's last broadcast typing update. A new
+	/// channel starts from the room's last update, so one made just before it
+	/// was created is not missed.
+	async fn watch_room(&self, room_id: &RoomId) -> watch::Receiver<u64> {
+		if let Some(sender) = self
+			.room_watch
+			.lock()
+			.expect("locked")
+			.get(room_id)
+		{
+			return sender.subscribe();
 		}
+
+		let last_update = self
+			.last_typing_update
+			.read()
+			.await
+			.get(room_id)
+			.copied()
+			.unwrap_or(0);
+
+		self.room_watch
+			.lock()
+			.expect("locked")
+			.entry(room_id.to_owned())
+			.or_insert_with(|| watch::channel(last_update).0)
+			.subscribe()
 	}
 
 	/// Makes sure that typing events with old timestamps get removed.
@@ -147,40 +501,446 @@ async fn typings_maintain(&self, room_id: &RoomId) -> Result {
 		};
 
 		if !removable.is_empty() {
-			let typing = &mut self.typing.write().await;
-			let room = typing.entry(room_id.to_owned()).or_default();
+			// the lock is released here; notifying below reads typing again
+			let removable =
+				take_expired(&self.typing, &self.typing_count, room_id, current_timestamp).await;
 
+			let mut last_accepted = self.last_accepted.write().await;
 			for user in &removable {
 				debug_info!("typing timeout {user:?} in {room_id:?}");
-				room.remove(user);
+				last_accepted.remove(&(user.clone(), room_id.to_owned()));
 			}
 
+			drop(last_accepted);
+
 			// update clients
-			let count = self.services.globals.next_count();
-			self.last_typing_update
-				.write()
-				.await
-				.insert(room_id.to_owned(), *count);
+			self.update_clients(room_id).await;
 
-			if self
-				.typing_update_sender
-				.send(room_id.to_owned())
-				.is_err()
+			// update appservices
+			if removable
+				.iter()
+				.any(|user| self.services.globals.user_is_local(user))
 			{
-				trace!("receiver found what it was looking for and is no longer interested");
+				self.appservice_send(room_id).await?;
 			}
 
 			// update federation
-			for user in &removable {
-				if self.services.globals.user_is_local(user) {
-					self.federation_send(room_id, user, false).await?;
-				}
+			let allow_outgoing = self.server.config.allow_outgoing_typing;
+			let local = federated_stops(&removable, allow_outgoing, |user| {
+				self.services.globals.user_is_local(user)
+			});
+
+			send_stops(local, FEDERATION_STOP_WIDTH, |user| {
+				self.federation_send(room_id, user, false)
+			})
+			.await?;
+		}
+
+		Ok(())
+	}
+
+	/// Clears expired typing in up to `SWEEP_ROOMS_PER_TICK` rooms, including
+	/// rooms nobody syncs, whose typing is otherwise never maintained. Further
+	/// rooms are left for the next tick. Also forgets the last typing update of
+	/// rooms idle for longer than `TYPING_UPDATE_RETENTION_MS`.
+	async fn typings_sweep(&self) {
+		let now = utils::millis_since_unix_epoch();
+		let rooms = expired_rooms(&*self.typing.read().await, now, SWEEP_ROOMS_PER_TICK);
+		for room_id in &rooms {
+			if let Err(e) = self.typings_maintain(room_id).await {
+				warn!(%room_id, "failed to clear expired typing: {e}");
 			}
 		}
 
+		if !rooms.is_empty() {
+			trace!(rooms = rooms.len(), "swept expired typing");
+		}
+
+		self.room_watch
+			.lock()
+			.expect("locked")
+			.retain(|_, sender| sender.receiver_count() > 0);
+
+		let window = self.server.config.typing_edu_coalesce_window_ms;
+		self.federation_started
+			.lock()
+			.expect("locked")
+			.retain(|_, &mut at| !coalesce_window_passed(Some(at), now, window));
+
+		let typing = self.typing.read().await;
+		let mut last_update = self.last_typing_update.write().await;
+		let evicted = evict_stale_updates(
+			&typing,
+			&mut last_update,
+			&mut self.broadcast_at.lock().expect("locked"),
+			now,
+			TYPING_UPDATE_RETENTION_MS,
+		);
+
+		if evicted > 0 {
+			trace!(evicted, "forgot typing updates of idle rooms");
+		}
+	}
+
+	/// Removes a user's typing in a room they left or were kicked or banned
+	/// from. Unlike the timeout sweep this happens at once on the membership
+	/// change; clients are updated, and federation told of the stop when the
+	/// user is local.
+	pub async fn typing_remove_on_leave(&self, user_id: &UserId, room_id: &RoomId) -> Result {
+		if !remove_typer(&mut *self.typing.write().await, room_id, user_id) {
+			return Ok(());
+		}
+
+		adjust_gauge(&self.typing_count, 1, 0);
+		self.last_accepted
+			.write()
+			.await
+			.remove(&(user_id.to_owned(), room_id.to_owned()));
+
+		debug_info!("typing cleared for {user_id:?} leaving {room_id:?}");
+
+		// update clients
+		self.update_clients(room_id).await;
+
+		// update appservices and federation
+		if self.services.globals.user_is_local(user_id) {
+			self.appservice_send(room_id).await?;
+			self.federation_send(room_id, user_id, false)
+				.await?;
+		}
+
 		Ok(())
 	}
 
+	/// Removes the typing indicators of all users belonging to a remote
+	/// server, e.g. after it was defederated or its users left. Returns the
+	/// number of indicators removed.
+	pub async fn purge_server_typing(&self, server: &ServerName) -> Result<usize> {
+		debug_assert!(
+			!self.services.globals.server_is_ours(server),
+			"tried to purge typing of our own server",
+		);
+
+		let (rooms, removed) = remove_server_typers(&mut *self.typing.write().await, server);
+		adjust_gauge(&self.typing_count, removed, 0);
+
+		for room_id in &rooms {
+			self.update_clients(room_id).await;
+		}
+
+		debug_info!("purged {removed} typing indicators of {server} in {} rooms", rooms.len());
+		Ok(removed)
+	}
+
+	/// Adds the server user as typing in the room, checks that it is reported
+	/// and broadcast, then removes it and checks that it is gone. Exercises the
+	/// add, broadcast, maintain and remove paths end-to-end. The server user
+	/// must be joined to the room, as typing is only accepted from members.
+	pub async fn typing_self_test(&self, room_id: &RoomId) -> Result<SelfTest> {
+		let user_id = &self.services.globals.server_user;
+		if !self.sees_typing(room_id, user_id).await {
+			return Err!(
+				"The server user {user_id} is not joined to {room_id}; run the self test in a \
+				 room it has joined, such as the admin room."
+			);
+		}
+
+		let mut updates = self.typing_update_sender.subscribe();
+		let timeout = utils::millis_since_unix_epoch().saturating_add(
+			self.server
+				.config
+				.typing_client_timeout_min_s
+				.saturating_mul(1000),
+		);
+
+		self.typing_add(user_id, room_id, timeout).await?;
+		let notified =
+			std::iter::from_fn(|| updates.try_recv().ok()).any(|updated| *updated == *room_id);
+
+		self.last_typing_update(room_id).await?;
+		let appeared = self
+			.typing_users_for_user(room_id, user_id)
+			.await?
+			.contains(user_id);
+
+		self.typing_remove(user_id, room_id).await?;
+		let cleared = !self
+			.typing_users_for_user(room_id, user_id)
+			.await?
+			.contains(user_id);
+
+		Ok(SelfTest { notified, appeared, cleared })
+	}
+
+	/// Whether the user muted all notifications with the master push rule,
+	/// which clients present as "do not disturb".
+	async fn do_not_disturb(&self, user_id: &UserId) -> bool {
+		self.services
+			.account_data
+			.get_global(user_id, GlobalAccountDataEventType::PushRules)
+			.await
+			.is_ok_and(|event: PushRulesEvent| do_not_disturb(&event.content.global))
+	}
+
+	/// Absolute typing timeout to store for the user, bounded to the configured
+	/// typing durations measured from the server's clock, so a timeout computed
+	/// against a skewed clock neither expires immediately nor lingers, and to
+	/// the maximum duration of the room's `m.room.typing_config`, if any.
+	/// Remote users are held to `typing_federation_timeout_s`.
+	fn stored_timeout(&self, user_id: &UserId, config: RoomConfig, timeout: u64) -> u64 {
+		let server = &self.server.config;
+		let bounds = timeout_bounds(
+			self.services.globals.user_is_local(user_id),
+			server.typing_client_timeout_min_s,
+			server.typing_client_timeout_max_s,
+			server.typing_federation_timeout_s,
+		);
+
+		let server_max = server
+			.typing_client_timeout_max_s
+			.saturating_mul(1000);
+
+		stored_timeout(
+			timeout,
+			utils::millis_since_unix_epoch(),
+			bounds,
+			config.timeout,
+			server_max,
+		)
+	}
+
+	/// Returns the typing-related configuration from the room's current state,
+	/// cached by the room's state hash.
+	async fn room_config(&self, room_id: &RoomId) -> RoomConfig {
+		let Ok(shortstatehash) = self
+			.services
+			.state
+			.get_room_shortstatehash(room_id)
+			.await
+		else {
+			return RoomConfig::default();
+		};
+
+		let cached = self
+			.room_config
+			.lock()
+			.expect("locked")
+			.get(room_id)
+			.filter(|(cached_hash, _)| *cached_hash == shortstatehash)
+			.map(|(_, config)| *config);
+
+		if let Some(config) = cached {
+			return config;
+		}
+
+		let timeout = self
+			.services
+			.state_accessor
+			.state_get_content(
+				shortstatehash,
+				&StateEventType::from(TYPING_CONFIG_EVENT_TYPE),
+				"",
+			)
+			.await
+			.ok()
+			.map(|content: TypingConfigEventContent| content.timeout);
+
+		let create: Option<RoomCreateEventContent> = self
+			.services
+			.state_accessor
+			.state_get_content(shortstatehash, &StateEventType::RoomCreate, "")
+			.await
+			.ok();
+
+		let space = create
+			.as_ref()
+			.and_then(|content| content.room_type.clone())
+			.is_some_and(is_equal_to!(RoomType::Space));
+
+		let unsupported_version = create
+			.as_ref()
+			.is_some_and(|content| !room_version_federates_typing(&content.room_version));
+
+		let encrypted = self
+			.services
+			.state_accessor
+			.state_contains(shortstatehash, &StateEventType::RoomEncryption, "")
+			.await;
+
+		let config = RoomConfig {
+			timeout,
+			space,
+			unsupported_version,
+			encrypted,
+		};
+		self.room_config
+			.lock()
+			.expect("locked")
+			.insert(room_id.to_owned(), (shortstatehash, config));
+
+		config
+	}
+
+	/// Re-sends the typing state of the room's local typing users to `server`,
+	/// or to every server in the room, bypassing coalescing. Returns the number
+	/// of users whose typing was sent.
+	pub async fn resend_federation(
+		&self,
+		room_id: &RoomId,
+		server: Option<&ServerName>,
+	) -> Result<usize> {
+		if !self.server.config.allow_outgoing_typing {
+			return Err!("Outgoing typing is disabled by allow_outgoing_typing.");
+		}
+
+		self.typings_maintain(room_id).await?;
+		let contents =
+			local_typing_contents(&*self.typing.read().await, room_id, &self.server.name);
+
+		for content in &contents {
+			let edu = Edu::Typing(content.clone());
+
+			let mut buf = EduBuf::new();
+			serde_json::to_writer(&mut buf, &edu).expect("Serialized Edu::Typing");
+
+			match server {
+				| Some(server) => self
+					.services
+					.sending
+					.send_edu_server(server, buf)?,
+				| None =>
+					self.services
+						.sending
+						.send_edu_room(room_id, buf)
+						.await?,
+			}
+		}
+
+		Ok(contents.len())
+	}
+
+	/// Bumps the room's last typing update and wakes up waiting clients. Rapid
+	/// changes in the same room are coalesced into a single wakeup; woken
+	/// clients read the room's latest typing state either way.
+	async fn update_clients(&self, room_id: &RoomId) {
+		if self.count_unavailable() {
+			trace!(%room_id, "skipping typing update while sequence numbers are unavailable");
+			return;
+		}
+
+		let now = utils::millis_since_unix_epoch();
+		let window = self.server.config.typing_broadcast_coalesce_ms;
+		let due = {
+			let mut broadcast_at = self.broadcast_at.lock().expect("locked");
+			let due = broadcast_due(broadcast_at.get(room_id).copied(), now, window);
+			if let Some(at) = due {
+				broadcast_at.insert(room_id.to_owned(), at);
+			}
+
+			due
+		};
+
+		match due {
+			| None => trace!(%room_id, "typing update coalesced into pending broadcast"),
+			| Some(at) if at > now => {
+				let Some(this) = self.this.upgrade() else {
+					return;
+				};
+
+				// counted when broadcast, so the changes coalesced into it share one
+				// count and the last of them is still delivered
+				let room_id = room_id.to_owned();
+				let delay = Duration::from_millis(at.saturating_sub(now));
+				self.server.runtime().spawn(async move {
+					sleep(delay).await;
+					if let Some(count) = this.next_update_count(&room_id) {
+						publish_update(
+							&this.last_typing_update,
+							&this.room_watch,
+							&this.typing_update_sender,
+							room_id,
+							count,
+						)
+						.await;
+					}
+				});
+			},
+			| Some(_) =>
+				if let Some(count) = self.next_update_count(room_id) {
+					publish_update(
+						&self.last_typing_update,
+						&self.room_watch,
+						&self.typing_update_sender,
+						room_id.to_owned(),
+						count,
+					)
+					.await;
+				},
+		}
+	}
+
+	/// Obtains the sequence number for a typing update, recording the outcome
+	/// in the count breaker. Returns `None` when none could be obtained; only
+	/// the first failure of a streak is logged as an error.
+	fn next_update_count(&self, room_id: &RoomId) -> Option<u64> {
+		match self.services.globals.try_next_count() {
+			| Ok(count) => {
+				let recovered = self
+					.count_breaker
+					.lock()
+					.expect("locked")
+					.succeeded();
+
+				if recovered > 0 {
+					info!(recovered, "typing resumed after sequence numbers became available");
+				}
+
+				Some(*count)
+			},
+			| Err(e) => {
+				let now = utils::millis_since_unix_epoch();
+				let first = self
+					.count_breaker
+					.lock()
+					.expect("locked")
+					.failed(now, COUNT_FAILURE_BACKOFF_MS);
+
+				if first {
+					error!(%room_id, "pausing typing, failed to obtain sequence number: {e}");
+				} else {
+					trace!(%room_id, "typing still paused, failed to obtain sequence number: {e}");
+				}
+
+				None
+			},
+		}
+	}
+
+	/// Records a typing update of the user in the room, returning whether it
+	/// came at least `typing_min_interval_ms` after the last accepted one.
+	async fn typing_interval_elapsed(&self, user_id: &UserId, room_id: &RoomId) -> bool {
+		let interval = self.server.config.typing_min_interval_ms;
+		let now = utils::millis_since_unix_epoch();
+		let key = (user_id.to_owned(), room_id.to_owned());
+
+		let mut last_accepted = self.last_accepted.write().await;
+		if typing_rate_limited(last_accepted.get(&key).copied(), now, interval) {
+			return false;
+		}
+
+		last_accepted.insert(key, now);
+		true
+	}
+
+	/// Whether typing is paused after failing to obtain a sequence number.
+	fn count_unavailable(&self) -> bool {
+		let now = utils::millis_since_unix_epoch();
+		self.count_breaker
+			.lock()
+			.expect("locked")
+			.is_open(now)
+	}
+
 	/// Returns the count of the last typing update in this room.
 	pub async fn last_typing_update(&self, room_id: &RoomId) -> Result<u64> {
 		self.typings_maintain(room_id).await?;
@@ -193,20 +953,108 @@ pub async fn last_typing_update(&self, room_id: &RoomId) -> Result<u64> {
 			.unwrap_or(0))
 	}
 
+	/// Returns every room with its typers and their absolute timeouts, after
+	/// clearing expired typing in all rooms.
+	pub async fn typing_snapshot(
+		&self,
+	) -> Result<BTreeMap<OwnedRoomId, Vec<(OwnedUserId, u64)>>> {
+		let rooms: Vec<_> = self.typing.read().await.keys().cloned().collect();
+		for room_id in &rooms {
+			self.typings_maintain(room_id).await?;
+		}
+
+		Ok(typing_snapshot(&*self.typing.read().await))
+	}
+
+	/// Returns the number of users typing in the room, after clearing expired
+	/// typing. Cheaper than listing them when only the count is needed.
+	pub async fn typing_user_count(&self, room_id: &RoomId) -> Result<usize> {
+		self.typings_maintain(room_id).await?;
+		Ok(room_typing_len(&*self.typing.read().await, room_id))
+	}
+
+	/// Returns the users typing in the room as seen by `sender_user`, or `None`
+	/// when typing in the room has not changed since `since` and need not be
+	/// resent.
+	pub async fn typing_users_since(
+		&self,
+		room_id: &RoomId,
+		sender_user: &UserId,
+		since: u64,
+	) -> Result<Option<Vec<OwnedUserId>>> {
+		let last_update = self.last_typing_update(room_id).await?;
+		let user_ids =
+			typing_users_changed_since(&*self.typing.read().await, room_id, last_update, since);
+
+		let Some(user_ids) = user_ids else {
+			return Ok(None);
+		};
+
+		Ok(Some(self.filter_ignored(user_ids, sender_user).await))
+	}
+
 	/// Returns a new typing EDU.
 	pub async fn typing_users_for_user(
 		&self,
 		room_id: &RoomId,
 		sender_user: &UserId,
 	) -> Result<Vec<OwnedUserId>> {
+		if !self.sees_typing(room_id, sender_user).await {
+			return Ok(Vec::new());
+		}
+
 		let room_typing_indicators = self.typing.read().await.get(room_id).cloned();
 
 		let Some(typing_indicators) = room_typing_indicators else {
 			return Ok(Vec::new());
 		};
 
-		let user_ids: Vec<_> = typing_indicators
-			.into_keys()
+		Ok(self
+			.filter_ignored(typing_indicators.into_keys(), sender_user)
+			.await)
+	}
+
+	/// Returns the users typing in each of the given rooms as seen by
+	/// `sender_user`, taking the typing lock only once for all rooms. Rooms
+	/// `sender_user` is not joined to are left out.
+	pub async fn typing_users_for_rooms(
+		&self,
+		room_ids: &[OwnedRoomId],
+		sender_user: &UserId,
+	) -> Result<BTreeMap<OwnedRoomId, Vec<OwnedUserId>>> {
+		let typing = typing_users_in_rooms(&*self.typing.read().await, room_ids);
+
+		Ok(typing
+			.into_iter()
+			.stream()
+			.filter_map(async |(room_id, user_ids)| {
+				self.sees_typing(&room_id, sender_user)
+					.await
+					.then_some((room_id, user_ids))
+			})
+			.then(async |(room_id, user_ids)| {
+				(room_id, self.filter_ignored(user_ids, sender_user).await)
+			})
+			.collect()
+			.await)
+	}
+
+	/// Whether `sender_user` may see typing in the room, i.e. is joined to it.
+	async fn sees_typing(&self, room_id: &RoomId, sender_user: &UserId) -> bool {
+		self.services
+			.state_cache
+			.is_joined(sender_user, room_id)
+			.await
+	}
+
+	/// Drops the users `sender_user` has ignored.
+	async fn filter_ignored<I>(&self, user_ids: I, sender_user: &UserId) -> Vec<OwnedUserId>
+	where
+		I: IntoIterator<Item = OwnedUserId> + Send,
+		I::IntoIter: Send,
+	{
+		user_ids
+			.into_iter()
 			.stream()
 			.filter_map(async |typing_user_id| {
 				self.services
@@ -217,9 +1065,7 @@ pub async fn typing_users_for_user(
 					.then_some(typing_user_id)
 			})
 			.collect()
-			.await;
-
-		Ok(user_ids)
+			.await
 	}
 
 	async fn federation_send(&self, room_id: &RoomId, user_id: &UserId, typing: bool) -> Result {
@@ -232,17 +1078,814 @@ async fn federation_send(&self, room_id: &RoomId, user_id: &UserId, typing: bool
 			return Ok(());
 		}
 
+		if self
+			.room_config(room_id)
+			.await
+			.unsupported_version
+		{
+			trace!(?room_id, "typing not federated for this room version");
+			return Ok(());
+		}
+
+		if !self.federation_room_size_allowed(room_id).await {
+			trace!(?room_id, "typing not federated for rooms of this size");
+			return Ok(());
+		}
+
+		if !self.federation_throttle(room_id, user_id, typing) {
+			return Ok(());
+		}
+
 		let content = TypingContent::new(room_id.to_owned(), user_id.to_owned(), typing);
 		let edu = Edu::Typing(content);
 
 		let mut buf = EduBuf::new();
 		serde_json::to_writer(&mut buf, &edu).expect("Serialized Edu::Typing");
 
+		let servers = self
+			.services
+			.state_cache
+			.room_servers(room_id)
+			.ready_filter(|server| !self.services.globals.server_is_ours(server))
+			.ready_filter(|server| self.federation_destination_admit(server));
+
 		self.services
 			.sending
-			.send_edu_room(room_id, buf)
+			.send_edu_servers(servers, buf)
 			.await?;
 
 		Ok(())
 	}
+
+	/// Federates a typing start once `grace` milliseconds have passed, unless
+	/// the user stopped or restarted typing meanwhile. The wait happens in the
+	/// background so the typing request is not held open for it.
+	fn federation_send_after_grace(
+		&self,
+		room_id: &RoomId,
+		user_id: &UserId,
+		timeout: u64,
+		grace: u64,
+	) {
+		let Some(this) = self.this.upgrade() else {
+			return;
+		};
+
+		let (room_id, user_id) = (room_id.to_owned(), user_id.to_owned());
+		self.server.runtime().spawn(async move {
+			sleep(Duration::from_millis(grace)).await;
+			if !start_due_after_grace(&*this.typing.read().await, &room_id, &user_id, timeout) {
+				trace!(%room_id, %user_id, "typing start superseded within grace period");
+				return;
+			}
+
+			if let Err(e) = this
+				.federation_send(&room_id, &user_id, true)
+				.await
+			{
+				warn!(%room_id, %user_id, "failed to federate typing start: {e}");
+			}
+		});
+	}
+
+	/// Counts a typing EDU for `server` and returns whether to enqueue it.
+	/// Typing is dropped for destinations backing off after a failed
+	/// transaction, since it would be stale by the time it was delivered.
+	fn federation_destination_admit(&self, server: &ServerName) -> bool {
+		let in_backoff = self.services.sending.in_backoff(server);
+		let mut stats = self.federation_stats.lock().expect("locked");
+		if count_destination(&mut stats, server, in_backoff) {
+			return true;
+		}
+
+		trace!(%server, "dropping typing for destination in backoff");
+		false
+	}
+
+	/// Typing EDUs enqueued for and dropped from each federation destination.
+	pub fn federation_stats(&self) -> BTreeMap<OwnedServerName, DestinationStats> {
+		self.federation_stats
+			.lock()
+			.expect("locked")
+			.clone()
+	}
+
+	/// Sends the room's typing users to the appservices that opted in to
+	/// ephemeral events (MSC2409) and are interested in the room.
+	async fn appservice_send(&self, room_id: &RoomId) -> Result {
+		let user_ids: Vec<_> = self
+			.typing
+			.read()
+			.await
+			.get(room_id)
+			.into_iter()
+			.flat_map(BTreeMap::keys)
+			.cloned()
+			.collect();
+
+		let edu = appservice_typing_edu(room_id, user_ids);
+		for appservice in self.services.appservice.read().await.values() {
+			let interested = appservice.rooms.is_match(room_id.as_str())
+				|| self
+					.services
+					.state_cache
+					.appservice_in_room(room_id, appservice)
+					.await;
+
+			if appservice_receives_typing(appservice.registration.receive_ephemeral, interested) {
+				self.services
+					.sending
+					.send_edu_appservice(appservice.registration.id.clone(), edu.clone())?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Whether typing in this room may be federated under the configured
+	/// `typing_federation_room_size_mode`.
+	async fn federation_room_size_allowed(&self, room_id: &RoomId) -> bool {
+		let config = &self.server.config;
+		let mode = config.typing_federation_room_size_mode;
+		if mode == TypingFederationRoomSizeMode::All {
+			return true;
+		}
+
+		let members = self
+			.services
+			.state_cache
+			.room_joined_count(room_id)
+			.await
+			.unwrap_or(0);
+
+		federate_for_room_size(
+			mode,
+			members,
+			config.typing_federation_small_room_size,
+			config.typing_federation_huge_room_size,
+		)
+	}
+
+	/// Throttles outgoing typing, recording the updates it lets through. It is
+	/// the only throttle on typing sent to federation:
+	///
+	/// - A start, whether it begins typing or refreshes it, is sent unless one
+	///   was sent for the user less than half of `typing_federation_timeout_s`
+	///   ago; remote servers still hold that one, and resending it before they
+	///   time it out keeps a user who types on shown as typing.
+	/// - A start within `typing_edu_coalesce_window_ms` of the last one sent
+	///   for the user is coalesced into it, even across a stop.
+	/// - At most `typing_federation_burst_max` starts are sent per room within
+	///   `typing_federation_burst_window_ms`.
+	/// - A stop is sent when its start was, or always without a grace period.
+	fn federation_throttle(&self, room_id: &RoomId, user_id: &UserId, typing: bool) -> bool {
+		let config = &self.server.config;
+		let key = (room_id.to_owned(), user_id.to_owned());
+		let mut sent = self.federation_sent.lock().expect("locked");
+		if !typing {
+			let start_sent = sent.remove(&key).is_some();
+			return stop_due(start_sent, config.typing_federation_grace_ms);
+		}
+
+		let now = utils::millis_since_unix_epoch();
+		let hold = config
+			.typing_federation_timeout_s
+			.saturating_mul(1000);
+		if !federation_start_due(sent.get(&key).copied(), now, hold) {
+			trace!(?room_id, ?user_id, "remote servers still hold typing, not federated");
+			return false;
+		}
+
+		let mut started = self.federation_started.lock().expect("locked");
+		let window = config.typing_edu_coalesce_window_ms;
+		if !coalesce_window_passed(started.get(&key).copied(), now, window) {
+			trace!(?room_id, ?user_id, "coalesced outgoing typing update");
+			return false;
+		}
+
+		let max = config.typing_federation_burst_max;
+		let mut bursts = self.federation_burst.lock().expect("locked");
+		let burst = bursts.entry(room_id.to_owned()).or_default();
+		if !burst_admit(burst, now, config.typing_federation_burst_window_ms, max) {
+			if burst.1 == max.saturating_add(1) {
+				warn!(
+					?room_id,
+					max, "too many users typing at once; not federating further starts"
+				);
+			} else {
+				trace!(?room_id, "typing start over the federation burst cap");
+			}
+
+			return false;
+		}
+
+		started.insert(key.clone(), now);
+		sent.insert(key, now);
+		true
+	}
+}
+
+/// Whether typing from a user is accepted given their membership. Local users
+/// not joined to the room are refused; remote users are ignored, as a server
+/// may well be behind on their membership.
+fn member_typing_accepted(joined: bool, local: bool) -> Result<bool, TypingError> {
+	match (joined, local) {
+		| (true, _) => Ok(true),
+		| (false, true) => Err(TypingError::NotMember),
+		| (false, false) => Ok(false),
+	}
+}
+
+/// Whether a typing update is accepted given whether the minimum interval since
+/// the user's last one has elapsed. Local users updating too often are refused;
+/// updates of remote users are dropped, as their server is not told off.
+fn interval_typing_accepted(elapsed: bool, local: bool) -> Result<bool, TypingError> {
+	match (elapsed, local) {
+		| (true, _) => Ok(true),
+		| (false, true) => Err(TypingError::RateLimited),
+		| (false, false) => Ok(false),
+	}
+}
+
+/// Whether a typing update at `now` comes within `interval` milliseconds of
+/// the last accepted one. An interval of zero accepts every update.
+fn typing_rate_limited(last_accepted: Option<u64>, now: u64, interval: u64) -> bool {
+	last_accepted.is_some_and(|last| interval > 0 && now.saturating_sub(last) < interval)
+}
+
+/// Counts a typing start against a room's burst of `(window start, starts)`,
+/// opening a new window once `window` milliseconds have passed. Returns whether
+/// the start is within `max`; a `max` of zero disables the cap.
+fn burst_admit(burst: &mut (u64, u64), now: u64, window: u64, max: u64) -> bool {
+	let (start, starts) = burst;
+	if now >= start.saturating_add(window) {
+		(*start, *starts) = (now, 0);
+	}
+
+	*starts = starts.saturating_add(1);
+	max == 0 || *starts <= max
+}
+
+/// Counts a typing EDU for `server` as enqueued, or as dropped while the
+/// destination is in backoff. Returns whether it should be enqueued.
+fn count_destination(
+	stats: &mut BTreeMap<OwnedServerName, DestinationStats>,
+	server: &ServerName,
+	in_backoff: bool,
+) -> bool {
+	let stats = stats.entry(server.to_owned()).or_default();
+	if in_backoff {
+		stats.dropped = stats.dropped.saturating_add(1);
+	} else {
+		stats.enqueued = stats.enqueued.saturating_add(1);
+	}
+
+	!in_backoff
+}
+
+/// Whether a typing start may be federated given when the previous start for
+/// the same user and room was. A window of zero disables coalescing.
+fn coalesce_window_passed(last_start: Option<u64>, now: u64, window: u64) -> bool {
+	last_start.is_none_or(|at| window == 0 || now.saturating_sub(at) >= window)
+}
+
+/// Whether a typing start of a user whose last start was federated at
+/// `last_sent` is federated again at `now`. It is only resent once half of the
+/// remote `hold` has passed, so remote servers do not time out a user who
+/// keeps typing. A start after a stop, or never federated, is sent.
+fn federation_start_due(last_sent: Option<u64>, now: u64, hold: u64) -> bool {
+	last_sent.is_none_or(|sent| now.saturating_sub(sent) >= hold / 2)
+}
+
+/// When a typing change in a room at `now` should be broadcast, given when the
+/// room's previous broadcast was or is scheduled to be sent. The first change
+/// in a window is broadcast immediately and later ones at the window's end;
+/// `None` when an already scheduled broadcast covers the change.
+fn broadcast_due(last: Option<u64>, now: u64, window: u64) -> Option<u64> {
+	match last {
+		| Some(last) if last > now => None,
+		| Some(last) if window > 0 && now < last.saturating_add(window) =>
+			Some(last.saturating_add(window)),
+		| _ => Some(now),
+	}
+}
+
+/// Wakes up clients waiting on typing in the room. Sending never blocks and
+/// only fails when nobody is listening, so there is nothing to retry: a full
+/// channel overwrites its oldest entries, which only a lagging receiver misses.
+/// That receiver sees `Lagged` on its next receive and treats it as a wakeup,
+/// while receivers keeping up still get every room id, including the latest.
+fn broadcast_update(sender: &broadcast::Sender<OwnedRoomId>, room_id: OwnedRoomId) {
+	if sender.send(room_id).is_err() {
+		trace!("receiver found what it was looking for and is no longer interested");
+	}
+}
+
+/// Records `count` as the room's last typing update, then wakes the clients
+/// waiting on typing in the room. A room channel made after the update was
+/// recorded starts from it, so no watcher misses it.
+async fn publish_update(
+	last_update: &RwLock<BTreeMap<OwnedRoomId, u64>>,
+	room_watch: &RoomWatch,
+	sender: &broadcast::Sender<OwnedRoomId>,
+	room_id: OwnedRoomId,
+	count: u64,
+) {
+	last_update
+		.write()
+		.await
+		.insert(room_id.clone(), count);
+
+	if let Some(room_watch) = room_watch.lock().expect("locked").get(&room_id) {
+		room_watch.send_replace(count);
+	}
+
+	broadcast_update(sender, room_id);
+}
+
+/// Waits until one of the rooms watched by `receivers` has a typing update
+/// with a count after `since`. Also returns when a channel is closed, since
+/// the caller can no longer know whether its room changed. Without any rooms
+/// to watch, never returns.
+async fn wait_for_room_updates(mut receivers: Vec<watch::Receiver<u64>>, since: u64) {
+	if receivers.is_empty() {
+		return future::pending().await;
+	}
+
+	loop {
+		if receivers
+			.iter()
+			.any(|receiver| *receiver.borrow() > since)
+		{
+			return;
+		}
+
+		let changes = receivers
+			.iter_mut()
+			.map(|receiver| receiver.changed().boxed());
+
+		if future::select_all(changes).await.0.is_err() {
+			return;
+		}
+	}
+}
+
+/// Whether an appservice gets typing for a room: it must have opted in to
+/// ephemeral events and be interested in the room.
+fn appservice_receives_typing(receive_ephemeral: bool, interested: bool) -> bool {
+	receive_ephemeral && interested
+}
+
+/// Serializes an `m.typing` event for an appservice transaction. Unlike sync,
+/// appservices receive ephemeral events with their `room_id`.
+fn appservice_typing_edu(room_id: &RoomId, user_ids: Vec<OwnedUserId>) -> EduBuf {
+	let event = serde_json::json!({
+		"type": "m.typing",
+		"room_id": room_id,
+		"content": TypingEventContent::new(user_ids),
+	});
+
+	let mut buf = EduBuf::new();
+	serde_json::to_writer(&mut buf, &event).expect("Serialized m.typing");
+
+	buf
+}
+
+/// The expired users whose typing stop is federated: the local ones, or none
+/// at all, without looking at any, when outgoing typing is disabled.
+fn federated_stops<'a, F>(
+	expired: &'a [OwnedUserId],
+	allow_outgoing: bool,
+	is_local: F,
+) -> impl Iterator<Item = &'a OwnedUserId> + Send + 'a
+where
+	F: Fn(&UserId) -> bool + Send + 'a,
+{
+	let expired = if allow_outgoing { expired } else { &[] };
+
+	expired.iter().filter(move |user| is_local(user))
+}
+
+/// Sends typing stops for `users` with up to `width` sends in flight, failing
+/// on the first error.
+async fn send_stops<'a, I, F, Fut>(users: I, width: usize, send: F) -> Result
+where
+	I: IntoIterator<Item = &'a OwnedUserId> + Send,
+	I::IntoIter: Send,
+	F: Fn(&'a OwnedUserId) -> Fut + Send,
+	Fut: Future<Output = Result> + Send,
+{
+	users
+		.try_stream()
+		.broadn_and_then(width, send)
+		.try_collect()
+		.await
+}
+
+/// Removes the users whose typing timed out before `now` from the room,
+/// returning them.
+fn remove_expired(typing: &mut TypingMap, room_id: &RoomId, now: u64) -> Vec<OwnedUserId> {
+	let mut expired = Vec::new();
+	if let Some(room) = typing.get_mut(room_id) {
+		room.retain(|user_id, timeout| {
+			let keep = *timeout >= now;
+			if !keep {
+				expired.push(user_id.clone());
+			}
+
+			keep
+		});
+	}
+
+	drop_if_empty(typing, room_id);
+	expired
+}
+
+/// Removes and returns the room's expired typers. The write lock is released
+/// before returning.
+async fn take_expired(
+	typing: &RwLock<TypingMap>,
+	gauge: &AtomicUsize,
+	room_id: &RoomId,
+	now: u64,
+) -> Vec<OwnedUserId> {
+	let mut typing = typing.write().await;
+	let expired = remove_expired(&mut typing, room_id, now);
+	adjust_gauge(gauge, expired.len(), 0);
+
+	expired
+}
+
+/// Drops the room's entry once nobody is typing in it, so rooms which once
+/// had a typer do not accumulate.
+fn drop_if_empty(typing: &mut TypingMap, room_id: &RoomId) {
+	if typing
+		.get(room_id)
+		.is_some_and(BTreeMap::is_empty)
+	{
+		typing.remove(room_id);
+	}
+}
+
+/// Forgets the last typing update of rooms nobody is typing in whose last
+/// broadcast is older than `retention`, returning how many were forgotten.
+///
+/// A forgotten room reads as never updated, so a client syncing from before
+/// its last update is not sent the room's (empty) typing again. Every typer
+/// it may have been shown has long since timed out by then, so only clients
+/// which stopped syncing for longer than `retention` are affected, and they
+/// learn of no typer that is not there.
+fn evict_stale_updates(
+	typing: &TypingMap,
+	last_update: &mut BTreeMap<OwnedRoomId, u64>,
+	broadcast_at: &mut HashMap<OwnedRoomId, u64>,
+	now: u64,
+	retention: u64,
+) -> usize {
+	let before = last_update.len();
+	last_update.retain(|room_id, _| {
+		let stale = !typing.contains_key(room_id)
+			&& broadcast_at
+				.get(room_id)
+				.is_none_or(|&at| now.saturating_sub(at) > retention);
+
+		if stale {
+			broadcast_at.remove(room_id);
+		}
+
+		!stale
+	});
+
+	before.saturating_sub(last_update.len())
+}
+
+/// Removes the user's typing in the room. Returns whether they were typing.
+fn remove_typer(typing: &mut TypingMap, room_id: &RoomId, user_id: &UserId) -> bool {
+	let removed = typing
+		.get_mut(room_id)
+		.is_some_and(|users| users.remove(user_id).is_some());
+
+	drop_if_empty(typing, room_id);
+	removed
+}
+
+/// Up to `max` rooms with a user whose typing expired before `now`.
+fn expired_rooms(typing: &TypingMap, now: u64, max: usize) -> Vec<OwnedRoomId> {
+	typing
+		.iter()
+		.filter(|(_, users)| users.values().any(|&timeout| timeout < now))
+		.map(|(room_id, _)| room_id.clone())
+		.take(max)
+		.collect()
+}
+
+fn room_typing_len(typing: &TypingMap, room_id: &RoomId) -> usize {
+	typing.get(room_id).map_or(0, BTreeMap::len)
+}
+
+/// Moves the typing users gauge by the change from `before` to `after`.
+fn adjust_gauge(gauge: &AtomicUsize, before: usize, after: usize) {
+	if after >= before {
+		gauge.fetch_add(after.abs_diff(before), Ordering::Relaxed);
+	} else {
+		gauge.fetch_sub(before.abs_diff(after), Ordering::Relaxed);
+	}
+}
+
+/// Whether a typing start held for the grace period should still be federated:
+/// the user must still be typing with the same timeout. A stop removes them and
+/// a restart replaces the timeout, federating itself after its own grace.
+fn start_due_after_grace(
+	typing: &TypingMap,
+	room_id: &RoomId,
+	user_id: &UserId,
+	timeout: u64,
+) -> bool {
+	typing
+		.get(room_id)
+		.and_then(|users| users.get(user_id))
+		.is_some_and(is_equal_to!(&timeout))
+}
+
+/// Whether a typing stop should be federated. With a grace period, a stop
+/// whose start was never federated has nothing to cancel and is dropped.
+fn stop_due(start_sent: bool, grace: u64) -> bool { start_sent || grace == 0 }
+
+/// Whether typing in a room is suppressed because it is encrypted and
+/// `disable_in_encrypted` is set.
+fn typing_suppressed(config: RoomConfig, disable_in_encrypted: bool) -> bool {
+	disable_in_encrypted && config.encrypted
+}
+
+/// Whether typing EDUs are federated for rooms of this version; only versions
+/// this server knows are assumed to support them.
+fn room_version_federates_typing(version: &RoomVersionId) -> bool {
+	STABLE_ROOM_VERSIONS
+		.iter()
+		.chain(UNSTABLE_ROOM_VERSIONS)
+		.chain(EXPERIMENTAL_ROOM_VERSIONS)
+		.any(is_equal_to!(version))
+}
+
+/// Whether typing is federated for a room with `members` joined members under
+/// the given `typing_federation_room_size_mode`.
+fn federate_for_room_size(
+	mode: TypingFederationRoomSizeMode,
+	members: u64,
+	small: u64,
+	huge: u64,
+) -> bool {
+	match mode {
+		| TypingFederationRoomSizeMode::All => true,
+		| TypingFederationRoomSizeMode::SmallRoomsOnly => members <= small,
+		| TypingFederationRoomSizeMode::AllButHuge => members < huge,
+	}
+}
+
+/// Removes every typer belonging to `server`, returning the rooms which were
+/// affected and the number of typers removed.
+fn remove_server_typers(
+	typing: &mut TypingMap,
+	server: &ServerName,
+) -> (Vec<OwnedRoomId>, usize) {
+	let mut rooms = Vec::new();
+	let mut removed = 0_usize;
+	for (room_id, users) in typing.iter_mut() {
+		let before = users.len();
+		users.retain(|user_id, _| user_id.server_name() != server);
+		if users.len() < before {
+			removed = removed.saturating_add(before.saturating_sub(users.len()));
+			rooms.push(room_id.clone());
+		}
+	}
+
+	typing.retain(|_, users| !users.is_empty());
+
+	(rooms, removed)
+}
+
+/// Shortest and longest typing durations in milliseconds for a user. Remote
+/// users are capped at the federation timeout rather than the client maximum,
+/// since their server's EDUs carry no timeout and may never be followed by a
+/// stop.
+fn timeout_bounds(
+	local: bool,
+	client_min_s: u64,
+	client_max_s: u64,
+	federation_s: u64,
+) -> (u64, u64) {
+	let max_s = if local { client_max_s } else { federation_s };
+
+	(client_min_s.min(max_s).saturating_mul(1000), max_s.saturating_mul(1000))
+}
+
+/// Absolute typing timeout stored for a requested one: bounded to `bounds`
+/// from `now`, then clamped to the room's maximum duration.
+fn stored_timeout(
+	timeout: u64,
+	now: u64,
+	(min, max): (u64, u64),
+	room_max: Option<u64>,
+	server_max: u64,
+) -> u64 {
+	let timeout = bound_timeout(timeout, now, min, max);
+
+	clamp_room_timeout(timeout, now, room_max, server_max)
+}
+
+/// Bounds an absolute typing timeout to between `now + min` and `now + max`;
+/// a timeout already in the past becomes the shortest allowed duration.
+fn bound_timeout(timeout: u64, now: u64, min: u64, max: u64) -> u64 {
+	timeout
+		.min(now.saturating_add(max))
+		.max(now.saturating_add(min))
+}
+
+/// Clamps an absolute typing timeout to `now` plus the room's maximum typing
+/// duration; the room may only shorten, never extend, the server's maximum.
+fn clamp_room_timeout(timeout: u64, now: u64, room_max: Option<u64>, server_max: u64) -> u64 {
+	room_max.map_or(timeout, |room_max| timeout.min(now.saturating_add(room_max.min(server_max))))
+}
+
+/// Whether the ruleset's master rule, which mutes all notifications, is on.
+fn do_not_disturb(ruleset: &Ruleset) -> bool {
+	ruleset
+		.get(RuleKind::Override, PredefinedOverrideRuleId::Master.as_str())
+		.is_some_and(|rule| rule.enabled())
+}
+
+/// Whether typing by the user in the room would start composing or refresh
+/// their ongoing typing.
+fn typing_transition(typing: &TypingMap, room_id: &RoomId, user_id: &UserId) -> TypingTransition {
+	if typing
+		.get(room_id)
+		.is_some_and(|users| users.contains_key(user_id))
+	{
+		TypingTransition::Refreshed
+	} else {
+		TypingTransition::Started
+	}
+}
+
+/// Records the user as typing until `timeout`, unless the room is a space.
+/// Returns whether the typing was recorded.
+fn record_typing(
+	typing: &mut TypingMap,
+	config: RoomConfig,
+	room_id: &RoomId,
+	user_id: &UserId,
+	timeout: u64,
+) -> bool {
+	if config.space {
+		return false;
+	}
+
+	typing
+		.entry(room_id.to_owned())
+		.or_default()
+		.insert(user_id.to_owned(), timeout);
+
+	true
+}
+
+/// Typing-started EDU contents for the room's users local to `server_name`.
+fn local_typing_contents(
+	typing: &TypingMap,
+	room_id: &RoomId,
+	server_name: &ServerName,
+) -> Vec<TypingContent> {
+	typing
+		.get(room_id)
+		.into_iter()
+		.flat_map(BTreeMap::keys)
+		.filter(|user_id| user_id.server_name() == server_name)
+		.map(|user_id| TypingContent::new(room_id.to_owned(), user_id.clone(), true))
+		.collect()
+}
+
+/// Selects the typing users of each requested room from the typing map.
+fn typing_users_in_rooms(
+	typing: &TypingMap,
+	room_ids: &[OwnedRoomId],
+) -> BTreeMap<OwnedRoomId, Vec<OwnedUserId>> {
+	room_ids
+		.iter()
+		.map(|room_id| {
+			let users = typing
+				.get(room_id)
+				.into_iter()
+				.flat_map(BTreeMap::keys)
+				.cloned()
+				.collect();
+
+			(room_id.clone(), users)
+		})
+		.collect()
+}
+
+/// Every room with its typers and their timeouts.
+fn typing_snapshot(typing: &TypingMap) -> BTreeMap<OwnedRoomId, Vec<(OwnedUserId, u64)>> {
+	typing
+		.iter()
+		.map(|(room_id, users)| {
+			let users = users
+				.iter()
+				.map(|(user_id, &timeout)| (user_id.clone(), timeout))
+				.collect();
+
+			(room_id.clone(), users)
+		})
+		.collect()
+}
+
+/// Typing users in the room when its `last_update` is newer than `since`,
+/// otherwise `None`.
+fn typing_users_changed_since(
+	typing: &TypingMap,
+	room_id: &RoomId,
+	last_update: u64,
+	since: u64,
+) -> Option<Vec<OwnedUserId>> {
+	(last_update > since).then(|| {
+		typing
+			.get(room_id)
+			.into_iter()
+			.flat_map(BTreeMap::keys)
+			.cloned()
+			.collect()
+	})
+}
+
+impl TimeoutPolicy {
+	fn new(min_s: u64, max_s: u64) -> Self {
+		Self {
+			min_timeout_ms: min_s.saturating_mul(1000),
+			max_timeout_ms: max_s.saturating_mul(1000),
+		}
+	}
+}
+
+impl CountBreaker {
+	fn is_open(&self, now: u64) -> bool { now < self.open_until }
+
+	/// Records a failure at `now` and opens the breaker for `backoff`
+	/// milliseconds. Returns whether this failure started a streak and should
+	/// be logged.
+	fn failed(&mut self, now: u64, backoff: u64) -> bool {
+		self.failures = self.failures.saturating_add(1);
+		self.open_until = now.saturating_add(backoff);
+		self.failures == 1
+	}
+
+	/// Records a success and closes the breaker. Returns the number of
+	/// failures recovered from.
+	fn succeeded(&mut self) -> u64 {
+		self.open_until = 0;
+		std::mem::take(&mut self.failures)
+	}
+}
+
+impl SelfTest {
+	#[must_use]
+	pub fn passed(&self) -> bool { self.notified && self.appeared && self.cleared }
+}
+
+impl From<Error> for TypingError {
+	fn from(error: Error) -> Self { Self::Internal(error) }
+}
+
+impl From<TypingError> for Error {
+	fn from(error: TypingError) -> Self {
+		match error {
+			| TypingError::RateLimited => Self::Request(
+				ErrorKind::LimitExceeded { retry_after: None },
+				"Typing status updated too frequently.".into(),
+				StatusCode::BAD_REQUEST,
+			),
+			| TypingError::NotMember => err!(Request(Forbidden("You are not in this room."))),
+			| TypingError::Internal(error) => error,
+		}
+	}
+}
+
+impl fmt::Display for TypingError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			| Self::RateLimited => write!(f, "typing status updated too frequently"),
+			| Self::NotMember => write!(f, "user is not in the room"),
+			| Self::Internal(error) => write!(f, "{error}"),
+		}
+	}
+}
+
+impl fmt::Display for SelfTest {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let check = |ok: bool| if ok { "ok" } else { "FAILED" };
+		let result = if self.passed() { "PASS" } else { "FAIL" };
+
+		writeln!(f, "{result}")?;
+		writeln!(f, "- broadcast on start: {}", check(self.notified))?;
+		writeln!(f, "- reported while typing: {}", check(self.appeared))?;
+		write!(f, "- cleared after removal: {}", check(self.cleared))
+	}
 }
@@ -0,0 +1,1180 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::{
+		Mutex,
+		atomic::{AtomicUsize, Ordering},
+	},
+	time::Duration,
+};
+
+use http::StatusCode;
+use ruma::{
+	OwnedRoomId, OwnedUserId, RoomVersionId, UserId,
+	api::{client::error::ErrorKind, federation::transactions::edu::TypingContent},
+	owned_room_id, owned_user_id,
+	push::{PredefinedOverrideRuleId, RuleKind, Ruleset},
+	room_id, server_name, user_id,
+};
+use tokio::{
+	sync::{
+		RwLock,
+		broadcast::{self, error::RecvError},
+		watch,
+	},
+	time::timeout,
+};
+use tuwunel_core::{Error, err};
+
+use super::{
+	CountBreaker, DestinationStats, RoomConfig, SelfTest, TimeoutPolicy, TypingError,
+	TypingFederationRoomSizeMode, TypingMap, TypingTransition, adjust_gauge,
+	appservice_receives_typing, appservice_typing_edu, bound_timeout, broadcast_due,
+	broadcast_update, burst_admit, clamp_room_timeout, coalesce_window_passed, count_destination,
+	do_not_disturb, evict_stale_updates, expired_rooms, federate_for_room_size, federated_stops,
+	federation_start_due, interval_typing_accepted, local_typing_contents,
+	member_typing_accepted, publish_update, record_typing, remove_expired, remove_server_typers,
+	remove_typer, room_typing_len, room_version_federates_typing, send_stops,
+	start_due_after_grace, stop_due, stored_timeout, take_expired, timeout_bounds,
+	typing_rate_limited, typing_snapshot, typing_suppressed, typing_transition,
+	typing_users_changed_since, typing_users_in_rooms, wait_for_room_updates,
+};
+
+/// Feeds typing starts at the given timestamps, each after a stop, through the
+/// coalescing decision and returns how many would have been federated.
+fn federated_starts(window: u64, times: &[u64]) -> usize {
+	let mut last_start = None;
+	times
+		.iter()
+		.filter(|&&now| {
+			let due = coalesce_window_passed(last_start, now, window);
+			if due {
+				last_start = Some(now);
+			}
+
+			due
+		})
+		.count()
+}
+
+#[test]
+fn coalesce_window_zero_sends_every_change() {
+	let times: Vec<u64> = (0..10).map(|i| i * 50).collect();
+	assert_eq!(federated_starts(0, &times), times.len());
+}
+
+#[test]
+fn coalesce_window_merges_rapid_starts() {
+	let times: Vec<u64> = (0..10).map(|i| i * 50).collect();
+	let sent = federated_starts(300, &times);
+	assert_eq!(sent, 2, "starts at 0 and 300 should be the only ones sent");
+	assert!(sent < federated_starts(0, &times));
+}
+
+/// Feeds repeated typing from one user at the given timestamps through the
+/// federation throttle's start decision and returns how many would have been
+/// federated.
+fn federated_typing(hold: u64, times: &[u64]) -> usize {
+	let mut last_sent = None;
+	times
+		.iter()
+		.filter(|&&now| {
+			let due = federation_start_due(last_sent, now, hold);
+			if due {
+				last_sent = Some(now);
+			}
+
+			due
+		})
+		.count()
+}
+
+#[test]
+fn repeated_typing_federated_once() {
+	let times: Vec<u64> = (0..10).map(|i| i * 1_000).collect();
+	assert_eq!(federated_typing(30_000, &times), 1);
+}
+
+#[test]
+fn long_typing_refreshed_before_remote_timeout() {
+	let times: Vec<u64> = (0..=60).map(|i| i * 1_000).collect();
+	assert_eq!(federated_typing(30_000, &times), 5, "sent at 0, 15, 30, 45 and 60 seconds");
+	assert!(federation_start_due(None, 0, 30_000));
+	assert!(!federation_start_due(Some(1_000), 15_999, 30_000));
+}
+
+#[test]
+fn room_size_mode_all() {
+	assert!(federate_for_room_size(TypingFederationRoomSizeMode::All, 2, 10, 1000));
+	assert!(federate_for_room_size(TypingFederationRoomSizeMode::All, 5000, 10, 1000));
+}
+
+#[test]
+fn room_size_mode_small_rooms_only() {
+	assert!(federate_for_room_size(
+		TypingFederationRoomSizeMode::SmallRoomsOnly,
+		2,
+		10,
+		1000
+	));
+	assert!(!federate_for_room_size(
+		TypingFederationRoomSizeMode::SmallRoomsOnly,
+		5000,
+		10,
+		1000
+	));
+}
+
+#[test]
+fn room_size_mode_all_but_huge() {
+	assert!(federate_for_room_size(TypingFederationRoomSizeMode::AllButHuge, 2, 10, 1000));
+	assert!(!federate_for_room_size(
+		TypingFederationRoomSizeMode::AllButHuge,
+		5000,
+		10,
+		1000
+	));
+}
+
+#[test]
+fn purge_server_typers_only_removes_target_server() {
+	let mut typing = TypingMap::new();
+	typing.insert(
+		owned_room_id!("!a:example.org"),
+		BTreeMap::from([
+			(owned_user_id!("@alice:example.org"), 10),
+			(owned_user_id!("@mallory:evil.example"), 10),
+		]),
+	);
+	typing.insert(
+		owned_room_id!("!b:example.org"),
+		BTreeMap::from([(owned_user_id!("@bob:example.org"), 10)]),
+	);
+
+	let evil = server_name!("evil.example");
+	let (rooms, removed) = remove_server_typers(&mut typing, evil);
+
+	assert_eq!(removed, 1);
+	assert_eq!(rooms, [owned_room_id!("!a:example.org")]);
+	assert!(
+		typing
+			.values()
+			.flat_map(BTreeMap::keys)
+			.all(|user| user.server_name() != evil)
+	);
+	assert_eq!(typing.values().map(BTreeMap::len).sum::<usize>(), 2);
+}
+
+#[test]
+fn room_typing_timeout_clamps_stored_timeout() {
+	let now = 1_000_000;
+	let requested = now + 30_000;
+
+	assert_eq!(clamp_room_timeout(requested, now, Some(5_000), 45_000), now + 5_000);
+	assert_eq!(clamp_room_timeout(requested, now, None, 45_000), requested);
+}
+
+#[test]
+fn room_typing_timeout_bounded_by_server_max() {
+	let now = 1_000_000;
+	let requested = now + 30_000;
+
+	assert_eq!(clamp_room_timeout(requested, now, Some(600_000), 10_000), now + 10_000);
+	assert_eq!(clamp_room_timeout(now + 1_000, now, Some(5_000), 45_000), now + 1_000);
+}
+
+#[test]
+fn bulk_typing_users_match_per_room_lookup() {
+	let mut typing = TypingMap::new();
+	typing.insert(
+		owned_room_id!("!a:example.org"),
+		BTreeMap::from([
+			(owned_user_id!("@alice:example.org"), 10),
+			(owned_user_id!("@bob:example.org"), 10),
+		]),
+	);
+	typing.insert(
+		owned_room_id!("!b:example.org"),
+		BTreeMap::from([(owned_user_id!("@carol:example.org"), 10)]),
+	);
+
+	let room_ids = [
+		owned_room_id!("!a:example.org"),
+		owned_room_id!("!b:example.org"),
+		owned_room_id!("!c:example.org"),
+	];
+
+	let bulk = typing_users_in_rooms(&typing, &room_ids);
+	assert_eq!(bulk.len(), room_ids.len());
+	for room_id in &room_ids {
+		let per_room: Vec<_> = typing
+			.get(room_id)
+			.map(|users| users.keys().cloned().collect())
+			.unwrap_or_default();
+
+		assert_eq!(bulk[room_id], per_room, "mismatch for {room_id}");
+	}
+}
+
+#[test]
+fn past_dated_timeout_becomes_short_timeout() {
+	let now = 1_000_000;
+	let past = now - 3_600_000;
+
+	let timeout = bound_timeout(past, now, 15_000, 45_000);
+	assert_eq!(timeout, now + 15_000);
+	assert!(timeout > now, "entry must not expire immediately");
+}
+
+#[test]
+fn far_future_timeout_bounded_to_max() {
+	let now = 1_000_000;
+
+	assert_eq!(bound_timeout(now + 86_400_000, now, 15_000, 45_000), now + 45_000);
+	assert_eq!(bound_timeout(now + 30_000, now, 15_000, 45_000), now + 30_000);
+}
+
+#[test]
+fn self_test_reports_success_when_healthy() {
+	let report = SelfTest {
+		notified: true,
+		appeared: true,
+		cleared: true,
+	};
+
+	assert!(report.passed());
+	assert!(report.to_string().starts_with("PASS"));
+}
+
+#[test]
+fn self_test_reports_failed_step() {
+	let report = SelfTest {
+		notified: true,
+		appeared: true,
+		cleared: false,
+	};
+
+	assert!(!report.passed());
+	assert!(report.to_string().starts_with("FAIL"));
+	assert!(
+		report
+			.to_string()
+			.contains("cleared after removal: FAILED")
+	);
+}
+
+#[test]
+fn typing_in_space_room_not_recorded() {
+	let mut typing = TypingMap::new();
+	let space = room_id!("!space:example.org");
+	let chat = room_id!("!chat:example.org");
+	let alice = user_id!("@alice:example.org");
+	let space_config = RoomConfig { space: true, ..Default::default() };
+
+	assert!(!record_typing(&mut typing, space_config, space, alice, 10));
+	assert!(record_typing(&mut typing, RoomConfig::default(), chat, alice, 10));
+
+	let rooms = [space.to_owned(), chat.to_owned()];
+	let users = typing_users_in_rooms(&typing, &rooms);
+	assert!(users[space].is_empty());
+	assert_eq!(users[chat], [alice.to_owned()]);
+}
+
+#[test]
+fn resend_covers_each_local_typing_user() {
+	let mut typing = TypingMap::new();
+	let room_id = owned_room_id!("!a:example.org");
+	typing.insert(
+		room_id.clone(),
+		BTreeMap::from([
+			(owned_user_id!("@alice:example.org"), 10),
+			(owned_user_id!("@bob:example.org"), 10),
+			(owned_user_id!("@remote:other.example"), 10),
+		]),
+	);
+
+	let contents = local_typing_contents(&typing, &room_id, server_name!("example.org"));
+
+	let users: Vec<_> = contents
+		.iter()
+		.map(|content| content.user_id.as_str())
+		.collect();
+
+	assert_eq!(users, ["@alice:example.org", "@bob:example.org"]);
+	assert!(
+		contents
+			.iter()
+			.all(|content| content.typing && content.room_id == room_id)
+	);
+}
+
+#[test]
+fn enormous_relative_timeout_saturates_to_server_max() {
+	let now = 1_000_000;
+	let (client_min_s, client_max_s, federation_s) = (15, 45, 30);
+	let bounds = timeout_bounds(true, client_min_s, client_max_s, federation_s);
+	let server_max = client_max_s * 1000;
+	let requested = now.saturating_add(u64::MAX);
+
+	assert!(
+		now.wrapping_add(u64::MAX) < now,
+		"wrapping would store an already-expired timeout"
+	);
+	assert_eq!(stored_timeout(requested, now, bounds, None, server_max), now + server_max);
+	assert_eq!(
+		stored_timeout(requested, now, bounds, Some(u64::MAX), server_max),
+		now + server_max
+	);
+}
+
+#[test]
+fn do_not_disturb_follows_master_rule() {
+	let user_id = user_id!("@alice:example.org");
+	let mut ruleset = Ruleset::server_default(user_id);
+	assert!(!do_not_disturb(&ruleset), "master rule is off by default");
+
+	ruleset
+		.set_enabled(RuleKind::Override, PredefinedOverrideRuleId::Master.as_str(), true)
+		.expect("master rule exists");
+	assert!(do_not_disturb(&ruleset));
+}
+
+#[test]
+fn typing_since_unchanged_is_none() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	record_typing(
+		&mut typing,
+		RoomConfig::default(),
+		room_id,
+		user_id!("@alice:example.org"),
+		10,
+	);
+
+	assert_eq!(typing_users_changed_since(&typing, room_id, 5, 5), None);
+	assert_eq!(typing_users_changed_since(&typing, room_id, 5, 7), None);
+}
+
+#[test]
+fn typing_since_changed_returns_users() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let alice = user_id!("@alice:example.org");
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, 10);
+
+	assert_eq!(typing_users_changed_since(&typing, room_id, 8, 5), Some(vec![alice.to_owned()]));
+
+	// a stop since the last sync is a change to an empty list
+	typing.remove(room_id);
+	assert_eq!(typing_users_changed_since(&typing, room_id, 9, 8), Some(Vec::new()));
+}
+
+#[test]
+fn start_then_stop_within_grace_sends_nothing() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let alice = user_id!("@alice:example.org");
+	let grace = 500;
+
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, 10);
+	typing
+		.get_mut(room_id)
+		.expect("room recorded")
+		.remove(alice);
+
+	// once the grace period passes the start is dropped...
+	let start_sent = start_due_after_grace(&typing, room_id, alice, 10);
+	assert!(!start_sent);
+
+	// ...and the stop has nothing to cancel
+	assert!(!stop_due(start_sent, grace));
+}
+
+#[test]
+fn start_still_typing_after_grace_is_sent() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let alice = user_id!("@alice:example.org");
+
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, 10);
+	assert!(start_due_after_grace(&typing, room_id, alice, 10));
+
+	// a restart during the grace period federates itself instead
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, 20);
+	assert!(!start_due_after_grace(&typing, room_id, alice, 10));
+	assert!(stop_due(true, 500));
+}
+
+#[test]
+fn stops_always_sent_without_grace() {
+	assert!(stop_due(false, 0));
+	assert!(stop_due(true, 0));
+}
+
+#[test]
+fn typing_gauge_follows_adds_removes_and_timeouts() {
+	let gauge = AtomicUsize::new(0);
+	let mut typing = TypingMap::new();
+	let a = room_id!("!a:example.org");
+	let b = room_id!("!b:example.org");
+	let alice = user_id!("@alice:example.org");
+	let bob = user_id!("@bob:example.org");
+
+	let add = |typing: &mut TypingMap, room_id, user_id, timeout| {
+		let before = room_typing_len(typing, room_id);
+		record_typing(typing, RoomConfig::default(), room_id, user_id, timeout);
+		adjust_gauge(&gauge, before, room_typing_len(typing, room_id));
+		gauge.load(Ordering::Relaxed)
+	};
+
+	assert_eq!(add(&mut typing, a, alice, 10), 1);
+	assert_eq!(add(&mut typing, a, bob, 20), 2);
+	assert_eq!(add(&mut typing, b, alice, 10), 3);
+
+	// refreshing a typer does not count them twice
+	assert_eq!(add(&mut typing, a, alice, 15), 3);
+
+	// explicit stop
+	typing
+		.get_mut(b)
+		.and_then(|users| users.remove(alice))
+		.expect("alice was typing");
+	adjust_gauge(&gauge, 1, 0);
+	assert_eq!(gauge.load(Ordering::Relaxed), 2);
+
+	// alice times out in room a, bob is still typing
+	let expired = remove_expired(&mut typing, a, 16);
+	assert_eq!(expired, [alice.to_owned()]);
+	adjust_gauge(&gauge, expired.len(), 0);
+	assert_eq!(gauge.load(Ordering::Relaxed), 1);
+
+	let expired = remove_expired(&mut typing, a, 21);
+	adjust_gauge(&gauge, expired.len(), 0);
+	assert_eq!(gauge.load(Ordering::Relaxed), 0);
+	assert_eq!(typing.values().map(BTreeMap::len).sum::<usize>(), 0);
+}
+
+#[tokio::test]
+async fn local_typing_timeout_releases_lock_before_notifying() {
+	let room_id = room_id!("!a:example.org");
+	let alice = user_id!("@alice:example.org");
+	let typing = RwLock::new(TypingMap::from([(
+		room_id.to_owned(),
+		BTreeMap::from([(alice.to_owned(), 10)]),
+	)]));
+	let gauge = AtomicUsize::new(1);
+
+	let expired = take_expired(&typing, &gauge, room_id, 11).await;
+	assert_eq!(expired, [alice.to_owned()]);
+	assert_eq!(gauge.load(Ordering::Relaxed), 0);
+
+	// appservice_send and update_clients read typing next; this must not wait
+	// on the expiry's write lock
+	let typing = timeout(Duration::from_secs(1), typing.read())
+		.await
+		.expect("typing lock released after expiry");
+	assert!(typing.get(room_id).is_none());
+}
+
+#[test]
+fn typing_not_federated_for_unknown_room_version() {
+	let custom = RoomVersionId::try_from("org.example.experimental").expect("valid room version");
+
+	assert!(!room_version_federates_typing(&custom));
+	assert!(room_version_federates_typing(&RoomVersionId::V11));
+	assert!(room_version_federates_typing(&RoomVersionId::V1));
+}
+
+/// Feeds typing changes in one room at the given timestamps through the
+/// broadcast coalescing decision, returning the times broadcasts are sent.
+fn broadcasts(window: u64, times: &[u64]) -> Vec<u64> {
+	let mut last = None;
+	times
+		.iter()
+		.filter_map(|&now| {
+			let due = broadcast_due(last, now, window)?;
+			last = Some(due);
+			Some(due)
+		})
+		.collect()
+}
+
+#[test]
+fn rapid_typing_changes_coalesce_broadcasts() {
+	let times: Vec<u64> = (0..10)
+		.map(|i| 1000_u64.saturating_add(i))
+		.collect();
+	let sent = broadcasts(100, &times);
+
+	// one immediate wakeup and one trailing wakeup covering the rest
+	assert_eq!(sent, [1000, 1100]);
+	assert!(sent.len() < times.len());
+	assert!(
+		sent.last()
+			.is_some_and(|&last| times.iter().all(|&now| now <= last))
+	);
+}
+
+#[test]
+fn typing_broadcasts_not_coalesced_when_disabled_or_spaced() {
+	let times: Vec<u64> = (0..10)
+		.map(|i| 1000_u64.saturating_add(i))
+		.collect();
+	assert_eq!(broadcasts(0, &times), times);
+
+	assert_eq!(broadcasts(100, &[1000, 1100, 1250]), [1000, 1100, 1250]);
+	assert_eq!(broadcasts(100, &[1000, 1050, 1150, 1220]), [1000, 1100, 1200, 1300]);
+}
+
+#[test]
+fn typing_errors_map_to_client_errors() {
+	let status = |error: TypingError| Error::from(error).status_code();
+
+	assert_eq!(status(TypingError::RateLimited), StatusCode::TOO_MANY_REQUESTS);
+	assert_eq!(status(TypingError::NotMember), StatusCode::FORBIDDEN);
+	assert!(matches!(
+		Error::from(TypingError::RateLimited).kind(),
+		ErrorKind::LimitExceeded { .. }
+	));
+
+	let internal = TypingError::from(err!(Database("typing database error")));
+	assert!(matches!(internal, TypingError::Internal(_)));
+	assert_eq!(status(internal), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[test]
+fn typing_suppressed_in_encrypted_rooms_when_disabled() {
+	let encrypted = RoomConfig { encrypted: true, ..Default::default() };
+	let unencrypted = RoomConfig::default();
+
+	assert!(typing_suppressed(encrypted, true));
+	assert!(!typing_suppressed(encrypted, false));
+	assert!(!typing_suppressed(unencrypted, true));
+	assert!(!typing_suppressed(unencrypted, false));
+
+	// with the option off, typing in the encrypted room is recorded normally
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!encrypted:example.org");
+	let user_id = user_id!("@alice:example.org");
+	assert!(record_typing(&mut typing, encrypted, room_id, user_id, 10));
+	assert_eq!(room_typing_len(&typing, room_id), 1);
+}
+
+#[test]
+fn timeout_policy_advertises_configured_bounds() {
+	let policy = TimeoutPolicy::new(15, 45);
+
+	assert_eq!(policy.min_timeout_ms, 15_000);
+	assert_eq!(policy.max_timeout_ms, 45_000);
+	assert_eq!(
+		serde_json::to_value(policy).expect("policy serializes"),
+		serde_json::json!({ "min_timeout_ms": 15_000, "max_timeout_ms": 45_000 }),
+	);
+}
+
+#[test]
+fn typing_start_flagged_refreshes_not() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let other_room = room_id!("!b:example.org");
+	let alice = user_id!("@alice:example.org");
+
+	assert_eq!(typing_transition(&typing, room_id, alice), TypingTransition::Started);
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, 10);
+
+	// keepalive refreshes only extend the timeout
+	assert_eq!(typing_transition(&typing, room_id, alice), TypingTransition::Refreshed);
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, 20);
+	assert_eq!(typing_transition(&typing, room_id, alice), TypingTransition::Refreshed);
+
+	// typing in another room is its own start
+	assert_eq!(typing_transition(&typing, other_room, alice), TypingTransition::Started);
+
+	// after a stop, typing again is a new start
+	remove_expired(&mut typing, room_id, 21);
+	assert_eq!(typing_transition(&typing, room_id, alice), TypingTransition::Started);
+}
+
+#[test]
+fn count_failures_back_off_and_log_once() {
+	let mut breaker = CountBreaker::default();
+	let backoff = 1_000;
+	let (mut attempts, mut logged) = (0_u64, 0_u64);
+
+	// sequence numbers keep failing while typing requests arrive every 100ms
+	for step in 0..25_u64 {
+		let now = step.saturating_mul(100);
+		if breaker.is_open(now) {
+			continue;
+		}
+
+		attempts = attempts.saturating_add(1);
+		if breaker.failed(now, backoff) {
+			logged = logged.saturating_add(1);
+		}
+	}
+
+	// one attempt per backoff period, and only the first failure is logged
+	assert_eq!(attempts, 3);
+	assert_eq!(logged, 1);
+	assert!(breaker.is_open(2_400));
+	assert!(!breaker.is_open(3_000));
+
+	// recovery closes the breaker and reports the streak
+	assert_eq!(breaker.succeeded(), 3);
+	assert!(!breaker.is_open(0));
+	assert_eq!(breaker.succeeded(), 0);
+
+	// a later failure starts a new streak and is logged again
+	assert!(breaker.failed(5_000, backoff));
+}
+
+#[tokio::test]
+async fn expired_typers_stopped_concurrently() {
+	let users: Vec<OwnedUserId> = (0..6)
+		.map(|i| format!("@user{i}:example.org"))
+		.map(|user| user.try_into().expect("valid user id"))
+		.collect();
+
+	let sent = Mutex::new(Vec::new());
+	let (in_flight, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+	let (sent_ref, in_flight_ref, peak_ref) = (&sent, &in_flight, &peak);
+	send_stops(&users, 4, |user| async move {
+		let (sent, in_flight, peak) = (sent_ref, in_flight_ref, peak_ref);
+		let current = in_flight
+			.fetch_add(1, Ordering::SeqCst)
+			.saturating_add(1);
+		peak.fetch_max(current, Ordering::SeqCst);
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		in_flight.fetch_sub(1, Ordering::SeqCst);
+
+		sent.lock().expect("locked").push(user.clone());
+		Ok(())
+	})
+	.await
+	.expect("all stops sent");
+
+	// every expired user got a stop
+	let mut sent = sent.into_inner().expect("not poisoned");
+	sent.sort();
+	assert_eq!(sent, users);
+
+	// sends overlapped, but never beyond the width
+	let peak = peak.load(Ordering::SeqCst);
+	assert!(peak > 1, "stops were sent one at a time");
+	assert!(peak <= 4, "more stops in flight than allowed: {peak}");
+}
+
+#[tokio::test]
+async fn stopping_expired_typers_fails_on_error() {
+	let users = vec![owned_user_id!("@alice:example.org"), owned_user_id!("@bob:example.org")];
+
+	let result = send_stops(&users, 4, |user| async move {
+		if user.localpart() == "bob" {
+			return Err(err!("federation unavailable"));
+		}
+
+		Ok(())
+	})
+	.await;
+
+	assert!(result.is_err());
+}
+
+#[test]
+fn typing_delivered_to_interested_appservice() {
+	let room_id = room_id!("!bridged:example.org");
+	let alice = owned_user_id!("@alice:example.org");
+
+	assert!(appservice_receives_typing(true, true));
+
+	let edu = appservice_typing_edu(room_id, vec![alice]);
+	let event: serde_json::Value = serde_json::from_slice(&edu).expect("valid json");
+	assert_eq!(
+		event,
+		serde_json::json!({
+			"type": "m.typing",
+			"room_id": "!bridged:example.org",
+			"content": { "user_ids": ["@alice:example.org"] },
+		}),
+	);
+
+	// a stop is delivered as the room's remaining typers
+	let edu = appservice_typing_edu(room_id, Vec::new());
+	let event: serde_json::Value = serde_json::from_slice(&edu).expect("valid json");
+	assert_eq!(event["content"]["user_ids"], serde_json::json!([]));
+}
+
+#[test]
+fn typing_withheld_from_uninterested_appservice() {
+	// not opted in to ephemeral events
+	assert!(!appservice_receives_typing(false, true));
+
+	// opted in, but not interested in the room
+	assert!(!appservice_receives_typing(true, false));
+}
+
+#[tokio::test]
+async fn lagging_receiver_does_not_starve_fast_receiver() {
+	let (sender, mut fast) = broadcast::channel(2);
+	let mut slow = sender.subscribe();
+	let tracked = room_id!("!tracked:example.org");
+
+	// the tracked room's update is overwritten before the slow receiver reads
+	broadcast_update(&sender, tracked.to_owned());
+	assert_eq!(fast.recv().await.expect("fast receiver woken"), tracked);
+
+	for i in 0..5 {
+		let room_id: OwnedRoomId = format!("!room{i}:example.org")
+			.try_into()
+			.expect("valid room id");
+
+		broadcast_update(&sender, room_id.clone());
+		assert_eq!(fast.recv().await.expect("fast receiver woken"), room_id);
+	}
+
+	// the slow receiver lagged; it is woken rather than left waiting
+	let woken = timeout(Duration::from_secs(5), slow.recv())
+		.await
+		.expect("lagging receiver woken");
+
+	assert!(matches!(woken, Err(RecvError::Lagged(_))));
+}
+
+#[test]
+fn federated_typing_burst_capped_per_window() {
+	let (window, max) = (1_000, 5);
+	let mut burst = (0, 0);
+
+	// fifty local users start typing within the same window
+	let federated = (0..50_u64)
+		.filter(|i| burst_admit(&mut burst, 10_000_u64.saturating_add(*i), window, max))
+		.count();
+
+	assert_eq!(federated, 5);
+
+	// the next window admits starts again
+	assert!(burst_admit(&mut burst, 11_000, window, max));
+	assert_eq!(burst, (11_000, 1));
+}
+
+#[test]
+fn federated_typing_burst_uncapped_when_disabled() {
+	let mut burst = (0, 0);
+
+	assert!((0..50).all(|_| burst_admit(&mut burst, 10_000, 1_000, 0)));
+}
+
+#[test]
+fn typing_dropped_for_destination_in_backoff() {
+	let mut stats = BTreeMap::new();
+	let healthy = server_name!("healthy.example.org");
+	let failing = server_name!("failing.example.org");
+
+	assert!(count_destination(&mut stats, healthy, false));
+	assert!(!count_destination(&mut stats, failing, true));
+	assert!(!count_destination(&mut stats, failing, true));
+
+	assert_eq!(stats[healthy], DestinationStats { enqueued: 1, dropped: 0 });
+	assert_eq!(stats[failing], DestinationStats { enqueued: 0, dropped: 2 });
+
+	// the destination recovered
+	assert!(count_destination(&mut stats, failing, false));
+	assert_eq!(stats[failing], DestinationStats { enqueued: 1, dropped: 2 });
+}
+
+#[test]
+fn remote_typing_expires_after_federation_cap() {
+	let now = 1_000_000;
+	let room_id = room_id!("!a:example.org");
+	let remote = user_id!("@bob:remote.example.org");
+	let content = TypingContent::new(room_id.to_owned(), remote.to_owned(), true);
+	assert!(content.typing);
+
+	// the remote server never sends a stop, and its start claims forever
+	let (min, max) = timeout_bounds(false, 15, 45, 30);
+	assert_eq!((min, max), (15_000, 30_000));
+	let timeout = bound_timeout(u64::MAX, now, min, max);
+
+	let mut typing = TypingMap::new();
+	record_typing(
+		&mut typing,
+		RoomConfig::default(),
+		&content.room_id,
+		&content.user_id,
+		timeout,
+	);
+
+	assert!(remove_expired(&mut typing, room_id, now + 30_000).is_empty());
+	assert_eq!(remove_expired(&mut typing, room_id, now + 30_001), [remote.to_owned()]);
+}
+
+#[test]
+fn local_typing_bounded_by_client_timeouts() {
+	assert_eq!(timeout_bounds(true, 15, 45, 30), (15_000, 45_000));
+
+	// a federation cap below the client minimum still bounds remote typing
+	assert_eq!(timeout_bounds(false, 15, 45, 10), (10_000, 10_000));
+}
+
+#[test]
+fn keystroke_typing_updates_rate_limited() {
+	let interval = 500;
+
+	// the first update is always accepted
+	assert!(!typing_rate_limited(None, 1_000, interval));
+
+	// keystrokes within the interval are dropped
+	assert!(typing_rate_limited(Some(1_000), 1_100, interval));
+	assert!(typing_rate_limited(Some(1_000), 1_499, interval));
+
+	// once the interval passed, the next update is accepted
+	assert!(!typing_rate_limited(Some(1_000), 1_500, interval));
+}
+
+#[test]
+fn typing_rate_limit_disabled_with_zero_interval() {
+	assert!(!typing_rate_limited(Some(1_000), 1_000, 0));
+}
+
+#[test]
+fn leaving_clears_local_typer() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let other_room = room_id!("!b:example.org");
+	let alice = user_id!("@alice:example.org");
+	let bob = user_id!("@bob:example.org");
+
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, u64::MAX);
+	record_typing(&mut typing, RoomConfig::default(), room_id, bob, u64::MAX);
+	record_typing(&mut typing, RoomConfig::default(), other_room, alice, u64::MAX);
+
+	// cleared at once, however long the timeout
+	assert!(remove_typer(&mut typing, room_id, alice));
+	assert!(!typing[room_id].contains_key(alice));
+	assert!(typing[room_id].contains_key(bob));
+	assert!(typing[other_room].contains_key(alice));
+}
+
+#[test]
+fn leaving_clears_remote_typer() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let remote = user_id!("@carol:remote.example");
+
+	record_typing(&mut typing, RoomConfig::default(), room_id, remote, u64::MAX);
+	assert!(remove_typer(&mut typing, room_id, remote));
+	assert_eq!(room_typing_len(&typing, room_id), 0);
+
+	// a user who was not typing has nothing to clear, update or federate
+	assert!(!remove_typer(&mut typing, room_id, remote));
+	assert!(!remove_typer(&mut typing, room_id!("!none:example.org"), remote));
+}
+
+#[test]
+fn sweep_finds_rooms_with_expired_typing() {
+	let mut typing = TypingMap::new();
+	let alice = user_id!("@alice:example.org");
+	let bob = user_id!("@bob:example.org");
+	let expired = room_id!("!expired:example.org");
+	let live = room_id!("!live:example.org");
+	let mixed = room_id!("!mixed:example.org");
+
+	record_typing(&mut typing, RoomConfig::default(), expired, alice, 100);
+	record_typing(&mut typing, RoomConfig::default(), live, alice, 2_000);
+	record_typing(&mut typing, RoomConfig::default(), mixed, alice, 100);
+	record_typing(&mut typing, RoomConfig::default(), mixed, bob, 2_000);
+
+	let rooms = expired_rooms(&typing, 1_000, usize::MAX);
+	assert_eq!(rooms, [expired.to_owned(), mixed.to_owned()]);
+}
+
+#[test]
+fn sweep_bounded_per_tick() {
+	let mut typing = TypingMap::new();
+	let alice = user_id!("@alice:example.org");
+	let rooms: Vec<OwnedRoomId> = (0..10)
+		.map(|i| {
+			format!("!room{i}:example.org")
+				.try_into()
+				.unwrap()
+		})
+		.collect();
+
+	for room_id in &rooms {
+		record_typing(&mut typing, RoomConfig::default(), room_id, alice, 100);
+	}
+
+	let first = expired_rooms(&typing, 1_000, 4);
+	assert_eq!(first.len(), 4);
+
+	// rooms swept on one tick leave the rest for the next
+	for room_id in &first {
+		remove_expired(&mut typing, room_id, 1_000);
+	}
+
+	let second = expired_rooms(&typing, 1_000, 4);
+	assert_eq!(second.len(), 4);
+	assert!(
+		second
+			.iter()
+			.all(|room_id| !first.contains(room_id))
+	);
+}
+
+#[test]
+fn local_typing_within_interval_rate_limited() {
+	assert!(matches!(interval_typing_accepted(true, true), Ok(true)));
+	assert!(matches!(interval_typing_accepted(false, false), Ok(false)));
+
+	let refused = interval_typing_accepted(false, true);
+	assert!(matches!(refused, Err(TypingError::RateLimited)));
+	assert_eq!(
+		refused
+			.map_err(Error::from)
+			.unwrap_err()
+			.status_code(),
+		StatusCode::TOO_MANY_REQUESTS
+	);
+}
+
+#[test]
+fn non_member_local_typing_rejected() {
+	let refused = member_typing_accepted(false, true);
+	assert!(matches!(refused, Err(TypingError::NotMember)));
+	assert_eq!(
+		refused
+			.map_err(Error::from)
+			.unwrap_err()
+			.status_code(),
+		StatusCode::FORBIDDEN
+	);
+
+	assert!(matches!(member_typing_accepted(true, true), Ok(true)));
+}
+
+#[test]
+fn non_member_remote_typing_ignored() {
+	assert!(matches!(member_typing_accepted(false, false), Ok(false)));
+	assert!(matches!(member_typing_accepted(true, false), Ok(true)));
+}
+
+#[test]
+fn typing_user_count_per_room() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let alice = user_id!("@alice:example.org");
+	let bob = user_id!("@bob:example.org");
+
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, u64::MAX);
+	record_typing(&mut typing, RoomConfig::default(), room_id, bob, u64::MAX);
+	assert_eq!(room_typing_len(&typing, room_id), 2);
+	assert_eq!(room_typing_len(&typing, room_id!("!unknown:example.org")), 0);
+}
+
+#[tokio::test]
+async fn room_update_wakes_waiter() {
+	let (tracked, receiver) = watch::channel(10_u64);
+	let (_other, other_receiver) = watch::channel(10_u64);
+	let waiter = tokio::spawn(wait_for_room_updates(vec![receiver, other_receiver], 10));
+
+	tracked.send_replace(11);
+
+	timeout(Duration::from_secs(5), waiter)
+		.await
+		.expect("woken within the poll window")
+		.expect("waiter completed");
+}
+
+#[tokio::test]
+async fn room_updated_since_returns_at_once() {
+	let (_sender, receiver) = watch::channel(11_u64);
+	let waiter = wait_for_room_updates(vec![receiver], 10);
+
+	assert!(
+		timeout(Duration::from_secs(5), waiter)
+			.await
+			.is_ok()
+	);
+}
+
+#[tokio::test]
+async fn stale_room_update_keeps_waiting() {
+	let (sender, receiver) = watch::channel(5_u64);
+	let waiter = tokio::spawn(wait_for_room_updates(vec![receiver], 10));
+
+	// an update counted before `since` was already seen by the caller
+	sender.send_replace(8);
+
+	assert!(
+		timeout(Duration::from_millis(50), waiter)
+			.await
+			.is_err()
+	);
+}
+
+#[tokio::test]
+async fn no_rooms_keeps_waiting() {
+	assert!(
+		timeout(Duration::from_millis(50), wait_for_room_updates(Vec::new(), 0))
+			.await
+			.is_err()
+	);
+}
+
+#[tokio::test]
+async fn closed_room_channel_ends_wait() {
+	let (sender, receiver) = watch::channel(0_u64);
+	let waiter = tokio::spawn(wait_for_room_updates(vec![receiver], 10));
+	drop(sender);
+
+	timeout(Duration::from_secs(5), waiter)
+		.await
+		.expect("woken within the poll window")
+		.expect("waiter completed");
+}
+
+#[test]
+fn last_typer_leaving_drops_room() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let alice = user_id!("@alice:example.org");
+	let bob = user_id!("@bob:example.org");
+
+	record_typing(&mut typing, RoomConfig::default(), room_id, alice, 100);
+	record_typing(&mut typing, RoomConfig::default(), room_id, bob, 2_000);
+
+	remove_expired(&mut typing, room_id, 1_000);
+	assert!(typing.contains_key(room_id));
+
+	remove_typer(&mut typing, room_id, bob);
+	assert!(!typing.contains_key(room_id));
+
+	// removing nobody does not leave an empty room behind
+	remove_typer(&mut typing, room_id, bob);
+	assert!(typing.is_empty());
+}
+
+#[test]
+fn server_typers_leave_no_empty_rooms() {
+	let mut typing = TypingMap::new();
+	let room_id = room_id!("!a:example.org");
+	let remote = user_id!("@carol:remote.example");
+
+	record_typing(&mut typing, RoomConfig::default(), room_id, remote, u64::MAX);
+	remove_server_typers(&mut typing, server_name!("remote.example"));
+	assert!(typing.is_empty());
+}
+
+#[test]
+fn idle_room_updates_evicted_after_retention() {
+	let mut typing = TypingMap::new();
+	let idle = room_id!("!idle:example.org");
+	let recent = room_id!("!recent:example.org");
+	let busy = room_id!("!busy:example.org");
+	let alice = user_id!("@alice:example.org");
+
+	record_typing(&mut typing, RoomConfig::default(), busy, alice, u64::MAX);
+
+	let mut last_update: BTreeMap<OwnedRoomId, u64> =
+		[(idle.to_owned(), 1), (recent.to_owned(), 2), (busy.to_owned(), 3)].into();
+
+	let mut broadcast_at: HashMap<OwnedRoomId, u64> =
+		[(idle.to_owned(), 1_000), (recent.to_owned(), 9_000), (busy.to_owned(), 1_000)].into();
+
+	let evicted =
+		evict_stale_updates(&typing, &mut last_update, &mut broadcast_at, 10_000, 5_000);
+
+	assert_eq!(evicted, 1);
+	assert!(!last_update.contains_key(idle));
+	assert!(!broadcast_at.contains_key(idle));
+
+	// kept while recently broadcast, or while someone is typing
+	assert!(last_update.contains_key(recent));
+	assert!(last_update.contains_key(busy));
+}
+
+#[test]
+fn snapshot_lists_typers_with_timeouts() {
+	let mut typing = TypingMap::new();
+	let a = room_id!("!a:example.org");
+	let b = room_id!("!b:example.org");
+	let alice = user_id!("@alice:example.org");
+	let bob = user_id!("@bob:example.org");
+
+	record_typing(&mut typing, RoomConfig::default(), a, alice, 1_000);
+	record_typing(&mut typing, RoomConfig::default(), a, bob, 2_000);
+	record_typing(&mut typing, RoomConfig::default(), b, bob, u64::MAX);
+
+	let snapshot = typing_snapshot(&typing);
+	assert_eq!(snapshot.len(), 2);
+	assert_eq!(snapshot[a], [(alice.to_owned(), 1_000), (bob.to_owned(), 2_000)]);
+	assert_eq!(snapshot[b], [(bob.to_owned(), u64::MAX)]);
+}
+
+#[tokio::test]
+async fn sweep_without_outgoing_typing_sends_no_stops() {
+	let expired = vec![
+		owned_user_id!("@alice:example.org"),
+		owned_user_id!("@bob:example.org"),
+		owned_user_id!("@carol:remote.example"),
+	];
+
+	let checked = AtomicUsize::new(0);
+	let sent = AtomicUsize::new(0);
+	let is_local = |user: &UserId| {
+		checked.fetch_add(1, Ordering::SeqCst);
+		user.server_name() == "example.org"
+	};
+
+	let sent_ref = &sent;
+	send_stops(federated_stops(&expired, false, is_local), 4, |_| async move {
+		sent_ref.fetch_add(1, Ordering::SeqCst);
+		Ok(())
+	})
+	.await
+	.expect("nothing to send");
+
+	assert_eq!(sent.load(Ordering::SeqCst), 0);
+	assert_eq!(checked.load(Ordering::SeqCst), 0);
+
+	send_stops(federated_stops(&expired, true, is_local), 4, |_| async move {
+		sent_ref.fetch_add(1, Ordering::SeqCst);
+		Ok(())
+	})
+	.await
+	.expect("all stops sent");
+
+	assert_eq!(sent.load(Ordering::SeqCst), 2, "only local users are sent a stop");
+}
+
+#[test]
+fn coalesced_changes_share_one_count() {
+	// each broadcast takes one count when sent, however many changes it covers
+	let changes = [1000, 1010, 1020, 1030];
+	let counted = broadcasts(100, &changes);
+	assert_eq!(counted, [1000, 1100]);
+
+	// and the last change is never left uncounted
+	assert!(counted.last() >= changes.last());
+}
+
+#[tokio::test]
+async fn published_update_recorded_before_waking() {
+	let last_update = RwLock::new(BTreeMap::new());
+	let room_watch = Mutex::new(HashMap::new());
+	let (sender, mut updates) = broadcast::channel(16);
+	let room_id = owned_room_id!("!a:example.org");
+
+	let (watch_sender, mut watcher) = watch::channel(0_u64);
+	room_watch
+		.lock()
+		.expect("locked")
+		.insert(room_id.clone(), watch_sender);
+
+	publish_update(&last_update, &room_watch, &sender, room_id.clone(), 42).await;
+
+	assert_eq!(last_update.read().await.get(&room_id), Some(&42));
+	assert!(watcher.has_changed().expect("sender alive"));
+	assert_eq!(*watcher.borrow_and_update(), 42);
+	assert_eq!(updates.try_recv().expect("broadcast sent"), room_id);
+}
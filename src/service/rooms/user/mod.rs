@@ -15,6 +15,7 @@ struct Data {
 	db: Arc<Database>,
 	userroomid_notificationcount: Arc<Map>,
 	userroomid_highlightcount: Arc<Map>,
+	userroomid_messagenotificationcount: Arc<Map>,
 	roomuserid_lastnotificationread: Arc<Map>,
 	roomsynctoken_shortstatehash: Arc<Map>,
 }
@@ -31,6 +32,9 @@ fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 				db: args.db.clone(),
 				userroomid_notificationcount: args.db["userroomid_notificationcount"].clone(),
 				userroomid_highlightcount: args.db["userroomid_highlightcount"].clone(),
+				userroomid_messagenotificationcount: args.db
+					["userroomid_messagenotificationcount"]
+					.clone(),
 				roomuserid_lastnotificationread: args.db["userroomid_highlightcount"].clone(),
 				roomsynctoken_shortstatehash: args.db["roomsynctoken_shortstatehash"].clone(),
 			},
@@ -56,6 +60,9 @@ pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) {
 	self.db
 		.userroomid_notificationcount
 		.put(userroom_id, 0_u64);
+	self.db
+		.userroomid_messagenotificationcount
+		.put(userroom_id, 0_u64);
 
 	let roomuser_id = (room_id, user_id);
 	self.db
@@ -85,6 +92,19 @@ pub async fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> u64 {
 		.unwrap_or(0)
 }
 
+/// Unread notifications for message events, a part of the
+/// `notification_count`.
+#[implement(Service)]
+pub async fn message_notification_count(&self, user_id: &UserId, room_id: &RoomId) -> u64 {
+	let key = (user_id, room_id);
+	self.db
+		.userroomid_messagenotificationcount
+		.qry(&key)
+		.await
+		.deserialized()
+		.unwrap_or(0)
+}
+
 #[implement(Service)]
 pub async fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> u64 {
 	let key = (room_id, user_id);
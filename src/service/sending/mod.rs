@@ -4,16 +4,17 @@
 mod sender;
 
 use std::{
+	collections::HashSet,
 	fmt::Debug,
 	hash::{DefaultHasher, Hash, Hasher},
 	iter::once,
-	sync::Arc,
+	sync::{Arc, Mutex},
 };
 
 use async_trait::async_trait;
 use futures::{FutureExt, Stream, StreamExt};
 use ruma::{
-	RoomId, ServerName, UserId,
+	OwnedServerName, RoomId, ServerName, UserId,
 	api::{OutgoingRequest, appservice::Registration},
 };
 use tokio::{task, task::JoinSet};
@@ -39,6 +40,8 @@ pub struct Service {
 	server: Arc<Server>,
 	services: Services,
 	channels: Vec<(loole::Sender<Msg>, loole::Receiver<Msg>)>,
+	/// federation destinations whose last transaction failed
+	backoff: Mutex<HashSet<OwnedServerName>>,
 }
 
 struct Services {
@@ -103,6 +106,7 @@ fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 			channels: (0..num_senders)
 				.map(|_| loole::unbounded())
 				.collect(),
+			backoff: Mutex::new(HashSet::new()),
 		}))
 	}
 
@@ -185,6 +189,22 @@ pub fn send_pdu_appservice(&self, appservice_id: String, pdu_id: RawPduId) -> Re
 		})
 	}
 
+	#[tracing::instrument(skip(self, serialized), level = "debug")]
+	pub fn send_edu_appservice(&self, appservice_id: String, serialized: EduBuf) -> Result {
+		let dest = Destination::Appservice(appservice_id);
+		let event = SendingEvent::Edu(serialized);
+		let _cork = self.db.db.cork();
+		let keys = self.db.queue_requests(once((&event, &dest)));
+		self.dispatch(Msg {
+			dest,
+			event,
+			queue_id: keys
+				.into_iter()
+				.next()
+				.expect("request queue key"),
+		})
+	}
+
 	#[tracing::instrument(skip(self, room_id, pdu_id), level = "debug")]
 	pub async fn send_pdu_room(&self, room_id: &RoomId, pdu_id: &RawPduId) -> Result {
 		let servers = self
@@ -304,6 +324,16 @@ pub async fn flush_servers<'a, S>(&self, servers: S) -> Result
 			.await
 	}
 
+	/// Whether the last transaction to the server failed, so anything queued
+	/// for it now waits out the retry backoff.
+	#[must_use]
+	pub fn in_backoff(&self, server: &ServerName) -> bool {
+		self.backoff
+			.lock()
+			.expect("locked")
+			.contains(server)
+	}
+
 	/// Sends a request to a federation server
 	#[inline]
 	pub async fn send_federation_request<T>(
@@ -138,10 +138,30 @@ async fn handle_response<'a>(
 		statuses: &mut CurTransactionStatus,
 	) {
 		match response {
-			| Ok(dest) =>
+			| Ok(dest) => {
+				self.set_backoff(&dest, false);
 				self.handle_response_ok(&dest, futures, statuses)
-					.await,
-			| Err((dest, e)) => Self::handle_response_err(dest, statuses, &e),
+					.await;
+			},
+			| Err((dest, e)) => {
+				self.set_backoff(&dest, true);
+				Self::handle_response_err(dest, statuses, &e);
+			},
+		}
+	}
+
+	/// Tracks whether a federation destination is backing off after a failed
+	/// transaction.
+	fn set_backoff(&self, dest: &Destination, failed: bool) {
+		let Destination::Federation(server) = dest else {
+			return;
+		};
+
+		let mut backoff = self.backoff.lock().expect("locked");
+		if failed {
+			backoff.insert(server.clone());
+		} else {
+			backoff.remove(server);
 		}
 	}
 
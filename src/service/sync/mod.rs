@@ -1,3 +1,5 @@
+#[cfg(test)]
+mod tests;
 mod watch;
 
 use std::{
@@ -5,7 +7,10 @@
 	sync::{Arc, Mutex, Mutex as StdMutex},
 };
 
-use ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId, api::client::sync::sync_events::v5};
+use ruma::{
+	OneTimeKeyAlgorithm, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId,
+	api::client::sync::sync_events::v5,
+};
 use tuwunel_core::{Result, Server};
 use tuwunel_database::Map;
 
@@ -46,10 +51,26 @@ struct SnakeSyncCache {
 	subscriptions: BTreeMap<OwnedRoomId, v5::request::RoomSubscription>,
 	known_rooms: BTreeMap<String, BTreeMap<OwnedRoomId, u64>>,
 	extensions: v5::request::Extensions,
+	/// unused fallback key types last reported on this connection
+	fallback_key_types: Option<Vec<OneTimeKeyAlgorithm>>,
+	/// joined and invited member counts last reported for each room
+	room_counts: BTreeMap<OwnedRoomId, MemberCounts>,
+	/// room order last sent for each list
+	list_orders: BTreeMap<String, Vec<OwnedRoomId>>,
+	/// unstable `is_dm` filter last set for each list
+	list_is_dm: BTreeMap<String, bool>,
+	/// count up to which to-device events were last delivered
+	to_device_delivered: Option<u64>,
 }
 
+/// A room's joined and invited member counts.
+pub type MemberCounts = (u64, u64);
+
+/// Change in a room's joined and invited member counts.
+pub type MemberCountsDelta = (i64, i64);
+
 type DbConnections<K, V> = Mutex<BTreeMap<K, V>>;
-type SnakeConnectionsKey = (OwnedUserId, OwnedDeviceId, Option<String>);
+pub type SnakeConnectionsKey = (OwnedUserId, OwnedDeviceId, Option<String>);
 type SnakeConnectionsVal = Arc<Mutex<SnakeSyncCache>>;
 
 impl crate::Service for Service {
@@ -98,19 +119,23 @@ pub fn forget_snake_sync_connection(&self, key: &SnakeConnectionsKey) {
 			.remove(key);
 	}
 
+	/// The connection's cache, which is created when not cached yet.
+	fn snake_connection(&self, key: &SnakeConnectionsKey) -> SnakeConnectionsVal {
+		let mut cache = self.snake_connections.lock().expect("locked");
+		Arc::clone(
+			cache
+				.entry(key.clone())
+				.or_insert_with(|| Arc::new(Mutex::new(SnakeSyncCache::default()))),
+		)
+	}
+
 	pub fn update_snake_sync_request_with_cache(
 		&self,
 		snake_key: &SnakeConnectionsKey,
 		request: &mut v5::Request,
 	) -> BTreeMap<String, BTreeMap<OwnedRoomId, u64>> {
-		let mut cache = self.snake_connections.lock().expect("locked");
-		let cached = Arc::clone(
-			cache
-				.entry(snake_key.clone())
-				.or_insert_with(|| Arc::new(Mutex::new(SnakeSyncCache::default()))),
-		);
+		let cached = self.snake_connection(snake_key);
 		let cached = &mut cached.lock().expect("locked");
-		drop(cache);
 
 		//v5::Request::try_from_http_request(req, path_args);
 		for (list_id, list) in &mut request.lists {
@@ -211,14 +236,8 @@ pub fn update_snake_sync_known_rooms(
 		globalsince: u64,
 	) {
 		assert!(key.2.is_some(), "Some(conn_id) required for this call");
-		let mut cache = self.snake_connections.lock().expect("locked");
-		let cached = Arc::clone(
-			cache
-				.entry(key.clone())
-				.or_insert_with(|| Arc::new(Mutex::new(SnakeSyncCache::default()))),
-		);
+		let cached = self.snake_connection(key);
 		let cached = &mut cached.lock().expect("locked");
-		drop(cache);
 
 		for (room_id, lastsince) in cached
 			.known_rooms
@@ -236,19 +255,129 @@ pub fn update_snake_sync_known_rooms(
 		}
 	}
 
+	/// Records the unused fallback key types reported on this connection.
+	/// Returns them when they differ from what was last reported, or `None`
+	/// when unchanged so the field can be omitted from the response.
+	pub fn update_snake_sync_fallback_key_types(
+		&self,
+		key: &SnakeConnectionsKey,
+		types: Vec<OneTimeKeyAlgorithm>,
+	) -> Option<Vec<OneTimeKeyAlgorithm>> {
+		let cached = self.snake_connection(key);
+		let cached = &mut cached.lock().expect("locked");
+
+		report_if_changed(&mut cached.fallback_key_types, types)
+	}
+
+	/// Records the member counts reported for rooms on this connection and
+	/// returns how they changed for rooms whose counts were reported before.
+	pub fn update_snake_sync_room_counts<'a, I>(
+		&self,
+		key: &SnakeConnectionsKey,
+		counts: I,
+	) -> Vec<(&'a RoomId, MemberCountsDelta)>
+	where
+		I: IntoIterator<Item = (&'a RoomId, MemberCounts)>,
+	{
+		let cached = self.snake_connection(key);
+		let cached = &mut cached.lock().expect("locked");
+
+		counts
+			.into_iter()
+			.filter_map(|(room_id, counts)| {
+				let last = cached
+					.room_counts
+					.insert(room_id.to_owned(), counts);
+
+				count_delta(last, counts).map(|delta| (room_id, delta))
+			})
+			.collect()
+	}
+
+	/// Records that to-device events up to `next_batch` are being delivered on
+	/// this connection and returns the count of the previous delivery.
+	pub fn update_snake_sync_to_device_delivered(
+		&self,
+		key: &SnakeConnectionsKey,
+		next_batch: u64,
+	) -> Option<u64> {
+		let cached = self.snake_connection(key);
+		let cached = &mut cached.lock().expect("locked");
+
+		cached.to_device_delivered.replace(next_batch)
+	}
+
+	/// Room order last sent for the list on this connection; empty when the
+	/// list has not been sent before.
+	pub fn snake_sync_list_order(
+		&self,
+		key: &SnakeConnectionsKey,
+		list_id: &str,
+	) -> Vec<OwnedRoomId> {
+		let cache = self.snake_connections.lock().expect("locked");
+		let Some(cached) = cache.get(key).map(Arc::clone) else {
+			return Vec::new();
+		};
+
+		drop(cache);
+		let cached = cached.lock().expect("locked");
+		cached
+			.list_orders
+			.get(list_id)
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	pub fn update_snake_sync_list_order(
+		&self,
+		key: &SnakeConnectionsKey,
+		list_id: String,
+		order: Vec<OwnedRoomId>,
+	) {
+		assert!(key.2.is_some(), "Some(conn_id) required for this call");
+		let cached = self.snake_connection(key);
+		let cached = &mut cached.lock().expect("locked");
+
+		cached.list_orders.insert(list_id, order);
+	}
+
+	/// Drops the rooms the client unsubscribed from from the connection's
+	/// sticky subscriptions and its known subscription rooms.
+	pub fn forget_snake_sync_subscriptions(
+		&self,
+		key: &SnakeConnectionsKey,
+		room_ids: &[OwnedRoomId],
+	) {
+		let cache = self.snake_connections.lock().expect("locked");
+		let Some(cached) = cache.get(key).map(Arc::clone) else {
+			return;
+		};
+
+		drop(cache);
+		forget_subscriptions(&mut cached.lock().expect("locked"), room_ids);
+	}
+
+	/// Records a list's `is_dm` filter when the request sets one and returns
+	/// the filter in effect, which is sticky like the list's other filters.
+	pub fn update_snake_sync_list_is_dm(
+		&self,
+		key: &SnakeConnectionsKey,
+		list_id: &str,
+		is_dm: Option<bool>,
+	) -> Option<bool> {
+		let cached = self.snake_connection(key);
+		let cached = &mut cached.lock().expect("locked");
+
+		sticky_list_is_dm(&mut cached.list_is_dm, list_id, is_dm)
+	}
+
 	pub fn update_snake_sync_subscriptions(
 		&self,
 		key: &SnakeConnectionsKey,
 		subscriptions: BTreeMap<OwnedRoomId, v5::request::RoomSubscription>,
 	) {
-		let mut cache = self.snake_connections.lock().expect("locked");
-		let cached = Arc::clone(
-			cache
-				.entry(key.clone())
-				.or_insert_with(|| Arc::new(Mutex::new(SnakeSyncCache::default()))),
-		);
+		let cached = self.snake_connection(key);
 		let cached = &mut cached.lock().expect("locked");
-		drop(cache);
 
 		cached.subscriptions = subscriptions;
 	}
@@ -264,6 +393,51 @@ pub fn into_snake_key<U, D, C>(user_id: U, device_id: D, conn_id: C) -> SnakeCon
 	(user_id.into(), device_id.into(), conn_id.into())
 }
 
+/// Returns `current` when it differs from the last reported value, which it
+/// then replaces; `None` means unchanged.
+fn report_if_changed<T: PartialEq + Clone>(last: &mut Option<T>, current: T) -> Option<T> {
+	if last.as_ref() == Some(&current) {
+		return None;
+	}
+
+	*last = Some(current.clone());
+	Some(current)
+}
+
+/// Change from the `last` reported counts, or `None` when nothing was reported
+/// before or nothing changed.
+fn count_delta(last: Option<MemberCounts>, current: MemberCounts) -> Option<MemberCountsDelta> {
+	let diff = |current: u64, last: u64| {
+		i64::try_from(current)
+			.unwrap_or(i64::MAX)
+			.saturating_sub(i64::try_from(last).unwrap_or(i64::MAX))
+	};
+
+	last.filter(|last| *last != current)
+		.map(|last| (diff(current.0, last.0), diff(current.1, last.1)))
+}
+
+fn sticky_list_is_dm(
+	cached: &mut BTreeMap<String, bool>,
+	list_id: &str,
+	is_dm: Option<bool>,
+) -> Option<bool> {
+	if let Some(is_dm) = is_dm {
+		cached.insert(list_id.to_owned(), is_dm);
+	}
+
+	cached.get(list_id).copied()
+}
+
+fn forget_subscriptions(cached: &mut SnakeSyncCache, room_ids: &[OwnedRoomId]) {
+	for room_id in room_ids {
+		cached.subscriptions.remove(room_id);
+		if let Some(known_rooms) = cached.known_rooms.get_mut("subscriptions") {
+			known_rooms.remove(room_id);
+		}
+	}
+}
+
 /// load params from cache if body doesn't contain it, as long as it's allowed
 /// in some cases we may need to allow an empty list as an actual value
 fn list_or_sticky<T: Clone>(target: &mut Vec<T>, cached: &Vec<T>) {
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+
+use ruma::{OneTimeKeyAlgorithm, api::client::sync::sync_events::v5, room_id};
+
+use super::{
+	SnakeSyncCache, count_delta, forget_subscriptions, report_if_changed, sticky_list_is_dm,
+};
+
+#[test]
+fn fallback_key_types_omitted_when_unchanged() {
+	let mut last = None;
+	let types = vec![OneTimeKeyAlgorithm::SignedCurve25519];
+
+	assert_eq!(report_if_changed(&mut last, types.clone()), Some(types.clone()));
+	assert_eq!(report_if_changed(&mut last, types), None);
+}
+
+#[test]
+fn fallback_key_types_reported_when_changed() {
+	let mut last = Some(vec![OneTimeKeyAlgorithm::SignedCurve25519]);
+
+	assert_eq!(report_if_changed(&mut last, Vec::new()), Some(Vec::new()));
+	assert_eq!(last, Some(Vec::new()));
+}
+
+#[test]
+fn member_count_delta_between_syncs() {
+	// first sync reports only absolute counts
+	assert_eq!(count_delta(None, (10, 2)), None);
+
+	// two joins and one invite accepted
+	assert_eq!(count_delta(Some((10, 2)), (13, 1)), Some((3, -1)));
+
+	// a member left
+	assert_eq!(count_delta(Some((13, 1)), (12, 1)), Some((-1, 0)));
+
+	// unchanged
+	assert_eq!(count_delta(Some((12, 1)), (12, 1)), None);
+}
+
+#[test]
+fn unsubscribed_room_leaves_known_rooms() {
+	let kept = room_id!("!kept:example.org").to_owned();
+	let dropped = room_id!("!dropped:example.org").to_owned();
+
+	let mut cached = SnakeSyncCache::default();
+	for room_id in [&kept, &dropped] {
+		cached
+			.subscriptions
+			.insert(room_id.clone(), v5::request::RoomSubscription::default());
+		cached
+			.known_rooms
+			.entry("subscriptions".to_owned())
+			.or_default()
+			.insert(room_id.clone(), 10);
+	}
+
+	forget_subscriptions(&mut cached, &[dropped.clone()]);
+
+	let known_rooms = &cached.known_rooms["subscriptions"];
+	assert!(known_rooms.contains_key(&kept));
+	assert!(!known_rooms.contains_key(&dropped));
+	assert!(cached.subscriptions.contains_key(&kept));
+	assert!(!cached.subscriptions.contains_key(&dropped));
+}
+
+#[test]
+fn list_is_dm_filter_is_sticky() {
+	let mut cached = BTreeMap::new();
+
+	assert_eq!(sticky_list_is_dm(&mut cached, "dms", None), None);
+	assert_eq!(sticky_list_is_dm(&mut cached, "dms", Some(true)), Some(true));
+
+	// later requests leaving the filter out keep it
+	assert_eq!(sticky_list_is_dm(&mut cached, "dms", None), Some(true));
+	assert_eq!(sticky_list_is_dm(&mut cached, "rooms", None), None);
+
+	assert_eq!(sticky_list_is_dm(&mut cached, "dms", Some(false)), Some(false));
+}
@@ -72,6 +72,15 @@ pub async fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) {
 
 	// TODO: Remove onetimekeys
 
+	// Remove fallback keys
+	let prefix = (user_id, device_id, Interfix);
+	self.db
+		.userdeviceid_fallbackkeys
+		.keys_prefix_raw(&prefix)
+		.ignore_err()
+		.ready_for_each(|key| self.db.userdeviceid_fallbackkeys.remove(key))
+		.await;
+
 	increment(&self.db.userid_devicelistversion, user_id.as_bytes());
 
 	let userdeviceid = (user_id, device_id);
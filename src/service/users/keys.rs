@@ -8,11 +8,12 @@
 	encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
 	serde::Raw,
 };
+use serde::{Deserialize, Serialize};
 use tuwunel_core::{
 	Err, Error, Result, err, implement,
 	utils::{ReadyExt, stream::TryIgnore, string::Unquoted},
 };
-use tuwunel_database::{Deserialized, Ignore, Json};
+use tuwunel_database::{Deserialized, Ignore, Interfix, Json};
 
 #[implement(super::Service)]
 pub async fn add_one_time_key(
@@ -155,6 +156,105 @@ pub async fn count_one_time_keys(
 	algorithm_counts
 }
 
+/// A device's fallback key for one algorithm. It is handed out when the
+/// device's one-time keys of the algorithm run out, and marked used rather
+/// than removed.
+#[derive(Deserialize, Serialize)]
+struct FallbackKey {
+	key_id: OwnedKeyId<OneTimeKeyAlgorithm, OneTimeKeyName>,
+	key: Raw<OneTimeKey>,
+	used: bool,
+}
+
+/// Stores the device's fallback key for the key's algorithm, replacing the
+/// previous one. Uploading the current key again keeps it marked used.
+#[implement(super::Service)]
+pub async fn add_fallback_key(
+	&self,
+	user_id: &UserId,
+	device_id: &DeviceId,
+	fallback_key_key: &KeyId<OneTimeKeyAlgorithm, OneTimeKeyName>,
+	fallback_key_value: &Raw<OneTimeKey>,
+) {
+	let algorithm = fallback_key_key.algorithm();
+	let key = (user_id, device_id, algorithm.as_ref());
+	let current: Result<FallbackKey> = self
+		.db
+		.userdeviceid_fallbackkeys
+		.qry(&key)
+		.await
+		.deserialized();
+
+	if current.is_ok_and(|current| *current.key_id == *fallback_key_key) {
+		return;
+	}
+
+	let fallback_key = FallbackKey {
+		key_id: fallback_key_key.to_owned(),
+		key: fallback_key_value.clone(),
+		used: false,
+	};
+
+	let count = self.services.globals.next_count();
+	self.db
+		.userdeviceid_fallbackkeys
+		.put(key, Json(fallback_key));
+	self.db
+		.userid_lastonetimekeyupdate
+		.raw_put(user_id, *count);
+}
+
+/// Hands out the device's fallback key for the algorithm, marking it used.
+#[implement(super::Service)]
+pub async fn take_fallback_key(
+	&self,
+	user_id: &UserId,
+	device_id: &DeviceId,
+	key_algorithm: &OneTimeKeyAlgorithm,
+) -> Result<(OwnedKeyId<OneTimeKeyAlgorithm, OneTimeKeyName>, Raw<OneTimeKey>)> {
+	let key = (user_id, device_id, key_algorithm.as_ref());
+	let mut fallback_key: FallbackKey = self
+		.db
+		.userdeviceid_fallbackkeys
+		.qry(&key)
+		.await
+		.deserialized()
+		.map_err(|_| err!(Request(NotFound("No fallback key found"))))?;
+
+	if !fallback_key.used {
+		fallback_key.used = true;
+
+		let count = self.services.globals.next_count();
+		self.db
+			.userdeviceid_fallbackkeys
+			.put(key, Json(&fallback_key));
+		self.db
+			.userid_lastonetimekeyupdate
+			.raw_put(user_id, *count);
+	}
+
+	Ok((fallback_key.key_id, fallback_key.key))
+}
+
+/// Algorithms for which the device has a fallback key not yet handed out.
+#[implement(super::Service)]
+pub async fn unused_fallback_key_types(
+	&self,
+	user_id: &UserId,
+	device_id: &DeviceId,
+) -> Vec<OneTimeKeyAlgorithm> {
+	let prefix = (user_id, device_id, Interfix);
+	self.db
+		.userdeviceid_fallbackkeys
+		.stream_prefix(&prefix)
+		.ignore_err()
+		.ready_filter_map(|(_, fallback_key): (Ignore, FallbackKey)| {
+			(!fallback_key.used).then(|| fallback_key.key_id.algorithm())
+		})
+		.collect()
+		.await
+}
+
 #[implement(super::Service)]
 pub async fn add_device_keys(
 	&self,